@@ -0,0 +1,22 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Exposes the commit the binary is built from as `GIT_HASH`, `unknown` outside a git
+/// checkout, for the `build_info` metric.
+fn main() {
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    for tracked in [".git/HEAD", ".git/refs", ".git/packed-refs"] {
+        if Path::new(tracked).exists() {
+            println!("cargo:rerun-if-changed={}", tracked);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::config::{AutoscaleConfig, TypeConfig};
+use crate::writer::WriterHandle;
+use std::time::{Duration, Instant};
+use tokio::task;
+use tokio::time::interval;
+use tracing::{info, info_span, Instrument};
+
+/// How often the queue depth is sampled.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_SUSTAIN_SECS: u64 = 10;
+
+/// Spawns the task scaling the writer tasks of `type_id` if it has `autoscale` set.
+pub fn spawn(type_id: &TypeConfig, handle: WriterHandle) {
+    let autoscale = match &type_id.autoscale {
+        None => return,
+        Some(autoscale) => autoscale.clone(),
+    };
+    let span = info_span!("autoscale", type_id = type_id.type_id);
+    task::spawn(run(autoscale, handle).instrument(span));
+}
+
+async fn run(autoscale: AutoscaleConfig, handle: WriterHandle) {
+    let min_writers = autoscale.min_writers.unwrap_or(1) as usize;
+    let max_writers = autoscale.max_writers as usize;
+    let sustain = Duration::from_secs(autoscale.sustain_secs.unwrap_or(DEFAULT_SUSTAIN_SECS));
+    let mut ticks = interval(CHECK_INTERVAL);
+    // the direction the depth has been pointing in, and since when
+    let mut pressure: Option<(bool, Instant)> = None;
+    loop {
+        ticks.tick().await;
+        if handle.stopping() {
+            return;
+        }
+        let depth = handle.backlog();
+        let writers = handle.task_count();
+        let direction = if depth > autoscale.scale_up_depth && writers < max_writers {
+            Some(true)
+        } else if depth <= autoscale.scale_down_depth && writers > min_writers {
+            Some(false)
+        } else {
+            None
+        };
+        pressure = match (direction, pressure) {
+            (Some(up), Some((was_up, since))) if up == was_up => Some((up, since)),
+            (Some(up), _) => Some((up, Instant::now())),
+            (None, _) => None,
+        };
+        let up = match pressure {
+            Some((up, since)) if since.elapsed() >= sustain => up,
+            _ => continue,
+        };
+        // another step needs another sustained period
+        pressure = None;
+        if up {
+            if !handle.add_task() {
+                return;
+            }
+            info!(depth, writers = writers + 1, "writer task added");
+        } else {
+            let file = handle.retire_task().await;
+            info!(depth, writers = writers - 1, ?file, "writer task retired");
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::blob::storage::{Container, ContainerWriter, FilePermissions};
+use crate::config::{BackendKind, S3Config, TypeConfig};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+#[cfg(test)]
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use url::Url;
+
+/// How long a signed S3 request stays valid.
+const SIGNATURE_TTL: Duration = Duration::from_secs(300);
+
+/// What `StorageBackend::store` wrote.
+pub struct Stored {
+    /// size of the stored container
+    pub bytes: u64,
+    /// index of the container's first blob in the stored one, not 0 when it was
+    /// appended to an existing container
+    pub first_index: usize,
+}
+
+/// Where the finished containers of a type are kept. Methods block, the writer calls
+/// them on the blocking thread pool.
+pub trait StorageBackend: Send + Sync {
+    /// Stores a serialized container under `name`, replacing an existing one.
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()>;
+
+    /// Reads the container stored under `name`, `NotFound` if there is none.
+    fn load(&self, name: &str) -> io::Result<Vec<u8>>;
+
+    /// Where `name` is stored, for logs and flush replies.
+    fn location(&self, name: &str) -> String;
+
+    /// The file behind `name` if containers are kept on the local disk.
+    fn local_path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Writes the blobs of `container` under `name`, appended to the container already
+    /// stored there if `append` is set. The container's spool is left alone, so a
+    /// failed store can be retried. The default goes through `save` and `load`,
+    /// holding the container in memory.
+    fn store(
+        &self,
+        name: &str,
+        container: &mut ContainerWriter,
+        append: bool,
+    ) -> io::Result<Stored> {
+        let staged = container.spool_path().with_extension("staged");
+        let finished = container.finish(&staged).and_then(|_| fs::read(&staged));
+        let _ = fs::remove_file(&staged);
+        let mut bytes = finished?;
+        let mut first_index = 0;
+        if append {
+            match self.load(name) {
+                Ok(existing) => {
+                    let existing = Container::from_bytes(&existing)?;
+                    first_index = existing.blobs().count();
+                    let containers = vec![existing, Container::from_bytes(&bytes)?];
+                    bytes = Container::merge(containers)?.to_bytes();
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.save(name, &bytes)?;
+        Ok(Stored {
+            bytes: bytes.len() as u64,
+            first_index,
+        })
+    }
+}
+
+/// Creates the backend configured for a type. Needs to run inside the runtime, the
+/// S3 backend keeps a handle to it.
+pub fn for_type(type_id: &TypeConfig) -> io::Result<Arc<dyn StorageBackend>> {
+    match (type_id.backend.unwrap_or_default(), &type_id.s3) {
+        (BackendKind::Local, _) => Ok(Arc::new(LocalBackend {
+            root: PathBuf::from(type_id.root.as_str()),
+            permissions: type_id.file_permissions(),
+        })),
+        (BackendKind::S3, Some(s3)) => Ok(Arc::new(S3Backend::new(s3)?)),
+        (BackendKind::S3, None) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("type {}: backend s3 needs an [s3] section", type_id.type_id),
+        )),
+    }
+}
+
+/// Containers as files in the type's root.
+pub struct LocalBackend {
+    root: PathBuf,
+    /// of the files `save` writes; `store` uses the writer's
+    permissions: FilePermissions,
+}
+
+impl LocalBackend {
+    /// Creates the directories of a date partitioned `name` if they are missing and
+    /// returns its path. Safe to race with other writers creating the same ones.
+    fn create_parent(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.root.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(path)
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.create_parent(name)?;
+        let tmp_path = path.with_extension("blob.tmp");
+        self.permissions.create(&tmp_path)?.write_all(bytes)?;
+        fs::rename(tmp_path, path)
+    }
+
+    fn load(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.root.join(name))
+    }
+
+    fn location(&self, name: &str) -> String {
+        self.root.join(name).display().to_string()
+    }
+
+    fn local_path(&self, name: &str) -> Option<PathBuf> {
+        Some(self.root.join(name))
+    }
+
+    /// Streams the spool into the file instead of going through memory.
+    fn store(
+        &self,
+        name: &str,
+        container: &mut ContainerWriter,
+        append: bool,
+    ) -> io::Result<Stored> {
+        let path = self.create_parent(name)?;
+        let (bytes, first_index) = if append {
+            container.append_to(&path)?
+        } else {
+            (container.finish(&path)?, 0)
+        };
+        Ok(Stored { bytes, first_index })
+    }
+}
+
+/// Containers as objects of an S3 compatible bucket, named `prefix` + file name.
+/// Credentials come from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN`; requests are unsigned without them.
+pub struct S3Backend {
+    bucket: Bucket,
+    credentials: Option<Credentials>,
+    prefix: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+    runtime: Handle,
+}
+
+impl S3Backend {
+    fn new(config: &S3Config) -> io::Result<Self> {
+        let invalid = |err: String| io::Error::new(ErrorKind::InvalidInput, err);
+        let endpoint = Url::parse(&config.endpoint)
+            .map_err(|err| invalid(format!("invalid s3 endpoint {}: {}", config.endpoint, err)))?;
+        let url_style = if config.virtual_host_style {
+            UrlStyle::VirtualHost
+        } else {
+            UrlStyle::Path
+        };
+        let region = config
+            .region
+            .clone()
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let bucket = Bucket::new(endpoint, url_style, config.bucket.clone(), region)
+            .map_err(|err| invalid(format!("invalid s3 bucket {}: {}", config.bucket, err)))?;
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            bucket,
+            credentials: Credentials::from_env(),
+            prefix: config.prefix.clone().unwrap_or_default(),
+            client: Client::builder().build(connector),
+            runtime: Handle::current(),
+        })
+    }
+
+    fn send(&self, method: Method, url: Url, body: Body) -> io::Result<Vec<u8>> {
+        let request = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .body(body)
+            .map_err(io::Error::other)?;
+        self.runtime.block_on(async {
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(io::Error::other)?;
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .map_err(io::Error::other)?;
+            match status {
+                status if status.is_success() => Ok(body.to_vec()),
+                StatusCode::NOT_FOUND => Err(io::Error::from(ErrorKind::NotFound)),
+                status => Err(io::Error::other(format!(
+                    "s3 request failed with {}: {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                ))),
+            }
+        })
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let key = format!("{}{}", self.prefix, name);
+        let url = self
+            .bucket
+            .put_object(self.credentials.as_ref(), &key)
+            .sign(SIGNATURE_TTL);
+        self.send(Method::PUT, url, Body::from(bytes.to_vec()))?;
+        Ok(())
+    }
+
+    fn load(&self, name: &str) -> io::Result<Vec<u8>> {
+        let key = format!("{}{}", self.prefix, name);
+        let url = self
+            .bucket
+            .get_object(self.credentials.as_ref(), &key)
+            .sign(SIGNATURE_TTL);
+        self.send(Method::GET, url, Body::empty())
+    }
+
+    fn location(&self, name: &str) -> String {
+        format!("s3://{}/{}{}", self.bucket.name(), self.prefix, name)
+    }
+}
+
+/// Containers kept in memory by name, for tests checking what the writer produced
+/// without looking at the disk.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryBackend {
+    containers: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl MemoryBackend {
+    /// The container stored under `name`, if any.
+    pub fn get(&self, name: &str) -> Option<Vec<u8>> {
+        self.containers.lock().unwrap().get(name).cloned()
+    }
+
+    /// The names of the stored containers, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.containers.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+impl StorageBackend for MemoryBackend {
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        self.containers
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), bytes.to_vec());
+        Ok(())
+    }
+
+    fn load(&self, name: &str) -> io::Result<Vec<u8>> {
+        self.get(name)
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+    }
+
+    fn location(&self, name: &str) -> String {
+        format!("memory:{}", name)
+    }
+}
@@ -0,0 +1,23 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io;
+use std::io::ErrorKind;
+
+/// Splits a batch body, a sequence of `[u32 length][bytes]` records with little endian
+/// lengths, into the blobs it carries. A length running past the end of the body or
+/// trailing bytes too short for a length make the whole batch invalid.
+pub fn split(mut body: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut blobs = Vec::new();
+    while !body.is_empty() {
+        let len = body.read_u32::<LittleEndian>()? as usize;
+        if len > body.len() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("record of {} bytes, only {} left", len, body.len()),
+            ));
+        }
+        let (blob, rest) = body.split_at(len);
+        blobs.push(blob.to_vec());
+        body = rest;
+    }
+    Ok(blobs)
+}
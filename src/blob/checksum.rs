@@ -0,0 +1,264 @@
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::io::ErrorKind;
+use xxhash_rust::xxh64::Xxh64;
+
+/// Number of reserved data header words holding the full digest.
+pub const DIGEST_WORDS: usize = 8;
+/// Reflected polynomial of the standard CRC-32 (IEEE 802.3, zlib, PNG), the one
+/// `ChecksumAlgo::Crc32` stores.
+pub const CRC32_IEEE: u32 = 0xEDB88320;
+/// Reflected polynomial of CRC-32C (Castagnoli), used by iSCSI, ext4 and others.
+pub const CRC32_CASTAGNOLI: u32 = 0x82F63B78;
+
+/// The CRC-32 implementation behind `ChecksumAlgo::Crc32`.
+#[cfg(feature = "crc32fast")]
+pub type DefaultCrc32 = crc32fast::Hasher;
+#[cfg(not(feature = "crc32fast"))]
+pub type DefaultCrc32 = TableCrc32<CRC32_IEEE>;
+
+/// Algorithm used for the container checksum, stored in the data header so every
+/// file is verified with the algorithm it was written with.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgo {
+    /// detects accidental corruption; the only algorithm of files written before
+    /// the algorithm was recorded
+    #[default]
+    Crc32 = 0,
+    Xxhash64 = 1,
+    /// detects tampering as well as corruption
+    Sha256 = 2,
+}
+
+/// What the container checksum covers, stored in the data header next to the
+/// algorithm. Either way the data header and the TOC are covered, and since format
+/// version 6 the TOC holds a CRC-32 of each blob's data.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumScope {
+    /// the data too, so it is hashed again whenever the container is written, e.g.
+    /// on every append; the only scope of files written before it was selectable
+    #[default]
+    Full,
+    /// the headers only, the data being covered through the per-blob CRCs: writing
+    /// hashes each blob once as it is pushed, and `from_file` checks the blobs against
+    /// their CRCs instead of the checksum. A damaged blob is reported as such rather
+    /// than as a checksum mismatch, and readers that don't check the CRCs, like a
+    /// lookup serving a blob straight from the file, don't notice it
+    Headers,
+}
+
+/// A computed checksum: `short` goes into the file header, `words` into the reserved
+/// data header words. CRC32 fits in `short` alone and leaves `words` zeroed, which
+/// keeps files written before the algorithm was selectable valid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub short: u32,
+    pub words: [u32; DIGEST_WORDS],
+}
+
+/// A CRC-32 implementation: `DefaultCrc32` for the stored checksums, others to
+/// compare a container with a tool using another variant, see
+/// `Container::crc32_with`.
+pub trait Crc32: Clone {
+    fn new() -> Self;
+    fn update(&mut self, buf: &[u8]);
+    fn finalize(self) -> u32;
+}
+
+/// Portable table-driven CRC-32 over the reflected polynomial `POLY`, with the
+/// register and the result inverted like the common variants.
+#[derive(Clone)]
+pub struct TableCrc32<const POLY: u32> {
+    crc: u32,
+}
+
+#[derive(Clone)]
+pub enum ChecksumHasher {
+    Crc32(DefaultCrc32),
+    Xxhash64(Xxh64),
+    Sha256(Sha256),
+}
+
+impl ChecksumAlgo {
+    pub fn from_u32(algo: u32) -> io::Result<Self> {
+        match algo {
+            0 => Ok(Self::Crc32),
+            1 => Ok(Self::Xxhash64),
+            2 => Ok(Self::Sha256),
+            _ => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("unknown checksum algorithm {}", algo),
+            )),
+        }
+    }
+
+    pub fn hasher(self) -> ChecksumHasher {
+        match self {
+            Self::Crc32 => ChecksumHasher::Crc32(DefaultCrc32::new()),
+            Self::Xxhash64 => ChecksumHasher::Xxhash64(Xxh64::new(0)),
+            Self::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+impl Checksum {
+    /// The full digest of `algo` in hex, as the usual command line tools print it.
+    pub fn to_hex(self, algo: ChecksumAlgo) -> String {
+        match algo {
+            ChecksumAlgo::Crc32 => format!("{:08x}", self.short),
+            ChecksumAlgo::Xxhash64 => {
+                format!(
+                    "{:016x}",
+                    (self.words[1] as u64) << 32 | self.words[0] as u64
+                )
+            }
+            ChecksumAlgo::Sha256 => self
+                .words
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "crc32fast")]
+impl Crc32 for crc32fast::Hasher {
+    fn new() -> Self {
+        crc32fast::Hasher::new()
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        crc32fast::Hasher::update(self, buf)
+    }
+
+    fn finalize(self) -> u32 {
+        crc32fast::Hasher::finalize(self)
+    }
+}
+
+impl<const POLY: u32> TableCrc32<POLY> {
+    const TABLE: [u32; 256] = crc32_table(POLY);
+}
+
+impl<const POLY: u32> Crc32 for TableCrc32<POLY> {
+    fn new() -> Self {
+        Self { crc: !0 }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        for byte in buf {
+            self.crc = Self::TABLE[((self.crc ^ *byte as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn finalize(self) -> u32 {
+        !self.crc
+    }
+}
+
+const fn crc32_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            Self::Crc32(hasher) => hasher.update(buf),
+            Self::Xxhash64(hasher) => hasher.update(buf),
+            Self::Sha256(hasher) => hasher.update(buf),
+        }
+    }
+
+    pub fn finalize(self) -> Checksum {
+        let digest = match self {
+            Self::Crc32(hasher) => {
+                return Checksum {
+                    short: hasher.finalize(),
+                    words: [0; DIGEST_WORDS],
+                }
+            }
+            Self::Xxhash64(hasher) => hasher.digest().to_le_bytes().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
+        let mut words = [0u32; DIGEST_WORDS];
+        for (word, chunk) in words.iter_mut().zip(digest.chunks(4)) {
+            *word = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Checksum {
+            short: words[0],
+            words,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::content_type::ContentType;
+    use crate::blob::meta::BlobMeta;
+    use crate::blob::storage::{BlobError, Container};
+
+    /// A serialized container of two blobs checksummed with `algo`.
+    fn written(algo: ChecksumAlgo) -> Vec<u8> {
+        let mut container = Container::with_checksum_algo(1, algo);
+        for (writer_id, data) in [(0, &b"first blob"[..]), (1, &b"second blob"[..])] {
+            container
+                .push(writer_id, 0, ContentType::None, &BlobMeta::new(), data)
+                .unwrap();
+        }
+        container.to_bytes()
+    }
+
+    fn round_trip(algo: ChecksumAlgo) {
+        let bytes = written(algo);
+        let container = Container::from_bytes(&bytes).unwrap();
+        assert_eq!(container.get_data_header().checksum_algo(), algo);
+        let blobs: Vec<_> = container.blobs().map(|blob| blob.data.to_vec()).collect();
+        assert_eq!(blobs, vec![b"first blob".to_vec(), b"second blob".to_vec()]);
+
+        let mut flipped = bytes.clone();
+        // the last data byte, the trailer being 16 bytes
+        let last_data_byte = flipped.len() - 17;
+        flipped[last_data_byte] ^= 0x01;
+        match Container::from_bytes(&flipped) {
+            Err(err @ BlobError::ChecksumMismatch { .. }) => assert!(err.is_checksum_failure()),
+            Err(err) => panic!("{:?}: unexpected {:?}", algo, err),
+            Ok(_) => panic!("{:?}: flipped byte not detected", algo),
+        }
+    }
+
+    #[test]
+    fn crc32_round_trip() {
+        round_trip(ChecksumAlgo::Crc32);
+    }
+
+    #[test]
+    fn xxhash64_round_trip() {
+        round_trip(ChecksumAlgo::Xxhash64);
+    }
+
+    #[test]
+    fn sha256_round_trip() {
+        round_trip(ChecksumAlgo::Sha256);
+    }
+}
@@ -0,0 +1,107 @@
+/// Content type of a blob, stored as a small code in its TOC entry. Only common
+/// types have their own code; everything else is kept as `Other`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    /// the producer didn't send a `Content-Type`
+    None,
+    OctetStream,
+    Json,
+    Protobuf,
+    MsgPack,
+    Xml,
+    Csv,
+    Text,
+    Gzip,
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Other,
+}
+
+const MIME_TYPES: [(ContentType, &str); 12] = [
+    (ContentType::OctetStream, "application/octet-stream"),
+    (ContentType::Json, "application/json"),
+    (ContentType::Protobuf, "application/x-protobuf"),
+    (ContentType::MsgPack, "application/msgpack"),
+    (ContentType::Xml, "application/xml"),
+    (ContentType::Csv, "text/csv"),
+    (ContentType::Text, "text/plain"),
+    (ContentType::Gzip, "application/gzip"),
+    (ContentType::Png, "image/png"),
+    (ContentType::Jpeg, "image/jpeg"),
+    (ContentType::Gif, "image/gif"),
+    (ContentType::Webp, "image/webp"),
+];
+
+impl ContentType {
+    /// Maps a `Content-Type` header value, ignoring parameters such as `charset`.
+    pub fn from_mime(mime: &str) -> Self {
+        let essence = mime.split(';').next().unwrap_or("").trim().to_lowercase();
+        let essence = match essence.as_str() {
+            "application/protobuf" | "application/vnd.google.protobuf" => "application/x-protobuf",
+            "application/x-msgpack" => "application/msgpack",
+            "text/xml" => "application/xml",
+            "application/x-gzip" => "application/gzip",
+            essence => essence,
+        };
+        MIME_TYPES
+            .iter()
+            .find(|(_, known)| *known == essence)
+            .map_or(Self::Other, |(content_type, _)| *content_type)
+    }
+
+    /// The canonical MIME type, `None` for blobs stored without one or with a type
+    /// that has no code of its own.
+    pub fn mime(self) -> Option<&'static str> {
+        MIME_TYPES
+            .iter()
+            .find(|(content_type, _)| *content_type == self)
+            .map(|(_, mime)| *mime)
+    }
+
+    /// Text based types that shrink when compressed; the others are binary or
+    /// compressed already.
+    pub fn is_compressible(self) -> bool {
+        matches!(self, Self::Json | Self::Xml | Self::Csv | Self::Text)
+    }
+
+    pub fn code(self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::OctetStream => 1,
+            Self::Json => 2,
+            Self::Protobuf => 3,
+            Self::MsgPack => 4,
+            Self::Xml => 5,
+            Self::Csv => 6,
+            Self::Text => 7,
+            Self::Gzip => 8,
+            Self::Png => 9,
+            Self::Jpeg => 10,
+            Self::Gif => 11,
+            Self::Webp => 12,
+            Self::Other => u32::MAX,
+        }
+    }
+
+    /// Unknown codes, e.g. written by a newer version, read as `Other`.
+    pub fn from_code(code: u32) -> Self {
+        match code {
+            0 => Self::None,
+            1 => Self::OctetStream,
+            2 => Self::Json,
+            3 => Self::Protobuf,
+            4 => Self::MsgPack,
+            5 => Self::Xml,
+            6 => Self::Csv,
+            7 => Self::Text,
+            8 => Self::Gzip,
+            9 => Self::Png,
+            10 => Self::Jpeg,
+            11 => Self::Gif,
+            12 => Self::Webp,
+            _ => Self::Other,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::blob::storage::{BlobError, Container, ContainerSummary};
+use std::fs;
+use std::fs::{File, ReadDir};
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// The container files of a directory, e.g. the root of one or more types. Files are
+/// listed as they are iterated, not when the directory is opened, and its
+/// subdirectories aren't looked into.
+pub struct ContainerDir {
+    root: PathBuf,
+}
+
+/// A container file found in a `ContainerDir`, with the summary of its headers and
+/// TOC; `load` reads the whole container.
+pub struct ContainerFile {
+    pub path: PathBuf,
+    pub summary: ContainerSummary,
+}
+
+/// The containers of one type in a `ContainerDir`, see `ContainerDir::iter_type`.
+pub struct TypeFiles {
+    type_id: u32,
+    entries: ReadDir,
+    skipped: Vec<(PathBuf, BlobError)>,
+}
+
+impl ContainerDir {
+    /// Opens `root`, failing if it isn't a directory.
+    pub fn open<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        if !fs::metadata(&root)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Iterates over the `.blob` files holding containers of `type_id`. The files of
+    /// other types are told apart by the first words of their headers, only the
+    /// matching ones are summarized. Files that can't be read as containers are
+    /// skipped and kept in `TypeFiles::skipped` for the caller to report; symbolic
+    /// links, like the `latest` pointers, are skipped silently as they point at
+    /// files listed anyway.
+    pub fn iter_type(&self, type_id: u32) -> io::Result<TypeFiles> {
+        Ok(TypeFiles {
+            type_id,
+            entries: fs::read_dir(&self.root)?,
+            skipped: Vec::new(),
+        })
+    }
+}
+
+impl ContainerFile {
+    /// Reads the container and verifies its checksum.
+    pub fn load(&self) -> Result<Container, BlobError> {
+        Container::from_file(BufReader::new(File::open(&self.path)?))
+    }
+}
+
+impl TypeFiles {
+    /// The files skipped so far and why they couldn't be read.
+    pub fn skipped(&self) -> &[(PathBuf, BlobError)] {
+        &self.skipped
+    }
+
+    fn read(&self, entry: io::Result<fs::DirEntry>) -> Result<Option<ContainerFile>, BlobError> {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "blob")
+            || !entry.file_type()?.is_file()
+            || Container::peek_type_id(&path)? != self.type_id
+        {
+            return Ok(None);
+        }
+        let summary = Container::summarize(&path)?;
+        Ok(Some(ContainerFile { path, summary }))
+    }
+}
+
+impl Iterator for TypeFiles {
+    type Item = ContainerFile;
+
+    fn next(&mut self) -> Option<ContainerFile> {
+        loop {
+            let entry = self.entries.next()?;
+            let path = entry.as_ref().map(fs::DirEntry::path).unwrap_or_default();
+            match self.read(entry) {
+                Ok(Some(file)) => return Some(file),
+                Ok(None) => {}
+                Err(err) => self.skipped.push((path, err)),
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{ErrorKind, Read};
+
+/// Key/value tags attached to a blob by its producer.
+pub type BlobMeta = BTreeMap<String, String>;
+
+/// Serializes the tags as a sequence of `u16` length prefixed keys and values.
+/// No tags serialize to nothing.
+pub fn encode(meta: &BlobMeta) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for (key, value) in meta {
+        for part in [key, value] {
+            let len = u16::try_from(part.len()).map_err(|_| {
+                io::Error::new(ErrorKind::InvalidInput, "metadata key or value too long")
+            })?;
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(part.as_bytes());
+        }
+    }
+    Ok(buf)
+}
+
+pub fn decode(mut buf: &[u8]) -> io::Result<BlobMeta> {
+    let mut meta = BlobMeta::new();
+    while !buf.is_empty() {
+        let key = read_part(&mut buf)?;
+        let value = read_part(&mut buf)?;
+        meta.insert(key, value);
+    }
+    Ok(meta)
+}
+
+/// Parses url-encoded pairs such as `tenant=acme&source=edge-7`.
+pub fn from_urlencoded(query: &str) -> io::Result<BlobMeta> {
+    let mut meta = BlobMeta::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let (key, value) = (percent_decode(key)?, percent_decode(value)?);
+        if key.len() > u16::MAX as usize || value.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "metadata key or value too long",
+            ));
+        }
+        meta.insert(key, value);
+    }
+    Ok(meta)
+}
+
+fn read_part(buf: &mut &[u8]) -> io::Result<String> {
+    let len = buf.read_u16::<LittleEndian>()? as usize;
+    let mut part = vec![0u8; len];
+    buf.read_exact(&mut part)?;
+    String::from_utf8(part).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+fn percent_decode(encoded: &str) -> io::Result<String> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, "invalid percent-encoding");
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = [
+                    bytes.next().ok_or_else(invalid)?,
+                    bytes.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
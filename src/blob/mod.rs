@@ -1 +1,5 @@
+pub mod checksum;
+pub mod content_type;
+pub mod dir;
+pub mod meta;
 pub mod storage;
@@ -1,19 +1,175 @@
+use crate::blob::checksum::{
+    Checksum, ChecksumAlgo, ChecksumHasher, ChecksumScope, Crc32, DefaultCrc32, DIGEST_WORDS,
+};
+use crate::blob::content_type::ContentType;
+use crate::blob::meta;
+use crate::blob::meta::BlobMeta;
 use byteorder::{LittleEndian, ReadBytesExt};
-use crc32fast::Hasher;
-use std::fs::File;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{ErrorKind, Read, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const MAGIC: u32 = 0xDADADADA;
-const VERSION: u32 = 0x00000000;
+/// Version 1 stamps TOC entries in microseconds since the Unix epoch, the same unit
+/// the writer uses in file names; version 0 used seconds.
+/// Version 2 adds the content type code to TOC entries.
+/// Version 3 adds the size of each blob's metadata to its TOC entry and a metadata
+/// section with the serialized tags of all blobs between the TOC and the data.
+/// Version 4 adds the offset of each blob's data to its TOC entry, so entries can
+/// share a data region; older files store the blobs back to back in TOC order.
+/// Version 5 adds the per-writer sequence number to TOC entries.
+/// Version 6 adds a CRC-32 of each blob's data to its TOC entry, which lets the
+/// checksum leave the data out, see `ChecksumScope`.
+/// Version 7 adds a `Trailer` after the data section declaring the length of the
+/// file, so a truncated file is told apart from a damaged one without hashing it.
+/// Version 8 adds the `Codec` of each blob's data to its TOC entry, with the offset
+/// and size of the data once decompressed.
+///
+/// Every version up to this one is read, missing fields taking their defaults;
+/// rewriting a container (`save_to_file`, `merge`, appends) migrates it to this
+/// version, while `repair` keeps the stored one. Newer versions are rejected as
+/// their layout is unknown.
+pub const VERSION: u32 = 0x00000008;
+/// Size of the file header and the data header.
+const HEADER_SIZE: u64 = 64;
+/// Ends the trailer; a file whose last bytes don't hold it was cut short.
+const TRAILER_MAGIC: u32 = 0xADADADAD;
+const TRAILER_SIZE: u64 = 16;
 const RESERVED: [u32; 11] = [0; 11];
+/// Reserved data header word holding the `ChecksumAlgo`, and `HEADERS_SCOPE`.
+const RESERVED_CHECKSUM_ALGO: usize = 0;
+/// Flag in the `RESERVED_CHECKSUM_ALGO` word set for `ChecksumScope::Headers`.
+const HEADERS_SCOPE: u32 = 1 << 16;
+/// First of the `DIGEST_WORDS` reserved data header words holding the full digest.
+/// They are hashed as zeros.
+const RESERVED_DIGEST: usize = 1;
+/// First of the two reserved data header words holding the creation time of the
+/// container in microseconds since the Unix epoch, low word first. Zero in files
+/// written before it was recorded.
+const RESERVED_CREATED: usize = RESERVED_DIGEST + DIGEST_WORDS;
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+/// Raw bytes after which a shared compressed block is closed, bounding what reading
+/// one small blob decompresses.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Current time in microseconds since the Unix epoch, `now_micros` unless a test
+/// freezes it to produce byte-exact containers.
+pub type Clock = Arc<dyn Fn() -> u64 + Send + Sync>;
 
 pub struct Container {
     file_header: FileHeader,
     data_header: DataHeader,
     toc: Vec<TocEntry>,
     data: Vec<u8>,
+    dedup: Option<DedupIndex>,
+    /// stamps the creation time and the TOC entries of pushed blobs
+    clock: Clock,
+}
+
+/// Builds a container on disk: blob data is streamed into a spool file as it is
+/// pushed, so only the TOC is kept in memory until the container is finished.
+pub struct ContainerWriter {
+    type_id: u32,
+    checksum_algo: ChecksumAlgo,
+    checksum_scope: ChecksumScope,
+    toc: Vec<TocEntry>,
+    spool: BufWriter<File>,
+    spool_path: PathBuf,
+    data_size: u64,
+    dedup: Option<DedupIndex>,
+    streamed: Option<StreamedBlob>,
+    /// microseconds since the Unix epoch
+    created: u64,
+    permissions: FilePermissions,
+    /// the TOC journal next to the spool file, see `enable_journal`
+    journal: Option<File>,
+    /// see `enable_compression`
+    block_threshold: Option<u32>,
+    /// of the last `finish` or `append_to`
+    timings: FinishTimings,
+}
+
+/// How long the last `ContainerWriter::finish` or `append_to` spent in each phase.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FinishTimings {
+    /// compressing the data and, when appending, checking the existing file's headers
+    pub serialize: Duration,
+    /// writing the container file
+    pub write: Duration,
+    /// renaming the written temporary file to the container's name
+    pub rename: Duration,
+}
+
+/// Permissions of the container files of a type, set on each file when it is opened,
+/// before any data is written to it, so it is never readable more widely. Only
+/// applied on Unix.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilePermissions {
+    /// permission bits, e.g. `0o640`; the umask decides when not set
+    pub mode: Option<u32>,
+    /// group id the files are given, the process's group when not set
+    pub gid: Option<u32>,
+}
+
+/// Reads a container blob by blob from a file or any other seekable reader, keeping
+/// only the TOC and the current blob in memory, for scanning containers too big to
+/// load with `from_file`. The checksum is computed along the way and checked by
+/// `finish`.
+pub struct ContainerReader<R> {
+    reader: BufReader<R>,
+    file_header: FileHeader,
+    data_header: DataHeader,
+    toc: std::vec::IntoIter<TocEntry>,
+    hasher: ChecksumHasher,
+    /// from the start of the file
+    data_start: u64,
+    /// without the trailer
+    data_size: u64,
+    /// bytes of the data section fed to the hasher so far; the reader is positioned
+    /// right after them between blobs
+    hashed: u64,
+}
+
+/// A container file mapped into memory: the headers and the TOC are parsed and
+/// checked against the file size when it is opened, the blobs are slices of the
+/// mapping, so only the pages of the blobs accessed are ever read. For read-heavy
+/// consumers opening many containers; `from_file` copies the whole data section.
+#[cfg(feature = "mmap")]
+pub struct MappedContainer {
+    map: memmap2::Mmap,
+    file_header: FileHeader,
+    data_header: DataHeader,
+    toc: Vec<TocEntry>,
+    /// from the start of the file
+    data_start: usize,
+    /// where the trailer starts or the file ends
+    data_end: usize,
+}
+
+/// A blob being written chunk by chunk behind the stored data of a `ContainerWriter`.
+struct StreamedBlob {
+    size: u64,
+    crc: DefaultCrc32,
+    /// only kept when deduplication is on
+    hasher: Option<Sha256>,
+}
+
+/// Finds blobs whose data is already stored in the container being built, keyed by
+/// the SHA-256 of the data.
+#[derive(Default)]
+struct DedupIndex {
+    offsets: HashMap<[u8; 32], u64>,
+    saved_bytes: u64,
 }
 
 pub struct FileHeader {
@@ -21,6 +177,11 @@ pub struct FileHeader {
     checksum: u32,
 }
 
+/// The second part of the file header. Its eleven reserved words hold the
+/// `ChecksumAlgo` and scope, the `DIGEST_WORDS` of the full digest and the creation
+/// time, so none are left. The object count isn't repeated there as `toc_size`
+/// already is one, and whether the data is compressed is recorded by the `Codec` of
+/// each TOC entry, which a container-wide flag could only contradict.
 pub struct DataHeader {
     version: u32,
     type_id: u32,
@@ -28,10 +189,347 @@ pub struct DataHeader {
     reserved: [u32; 11],
 }
 
+/// Written after the data section since version 7: the length of the whole file,
+/// including the trailer, and a repeat of the checksum in the file header, which
+/// ties the trailer to the headers it was written with.
+struct Trailer {
+    total: u64,
+    checksum: u32,
+}
+
+/// How the data of a blob is stored, recorded in its TOC entry since version 8.
+/// Readers decompress it transparently.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// as pushed
+    #[default]
+    None = 0,
+    /// a raw deflate stream of the blob's data alone
+    Deflate = 1,
+    /// a raw deflate stream of a block of small blobs stored back to back, which the
+    /// TOC entries of all of them point to; each entry tells where its blob is in
+    /// the decompressed block
+    DeflateBlock = 2,
+}
+
+#[derive(Clone)]
 pub struct TocEntry {
     writer_id: u32,
+    /// assigned per (type_id, writer_id) on submission, stored since version 5;
+    /// 0 means unknown
+    sequence: u64,
+    /// from the start of the data section, stored since version 4
+    data_offset: u64,
     data_size: u32,
+    /// CRC-32 of the data as stored, compressed or not, stored since version 6;
+    /// computed when an older file is loaded into a `Container`, 0 otherwise
+    data_crc: u32,
+    /// since version 8, `Codec::None` for older files
+    codec: Codec,
+    /// where the blob starts in its decompressed `Codec::DeflateBlock`, 0 otherwise
+    raw_offset: u32,
+    /// the size of the blob's data once decompressed, `data_size` for `Codec::None`
+    raw_size: u32,
+    /// microseconds since the Unix epoch
     timestamp: u64,
+    /// since version 2, `ContentType::None` for older files
+    content_type: ContentType,
+    /// serialized `BlobMeta`, stored in the metadata section since version 3
+    meta: Vec<u8>,
+}
+
+/// Where the data of a blob is in a container file.
+pub struct BlobLocation {
+    /// from the start of the file
+    pub offset: u64,
+    /// of the data as stored at `offset`
+    pub stored_size: u32,
+    /// of the blob's data, decompressed
+    pub size: u32,
+    pub content_type: ContentType,
+    entry: TocEntry,
+}
+
+/// The facts about a container file listed in a directory manifest.
+pub struct ContainerSummary {
+    pub type_id: u32,
+    pub objects: usize,
+    /// oldest and newest blob timestamp, `None` for an empty container
+    pub time_range: Option<(u64, u64)>,
+    /// `None` for files written before the creation time was recorded
+    pub created: Option<u64>,
+    pub checksum_algo: ChecksumAlgo,
+    pub checksum_scope: ChecksumScope,
+    /// as stored, not verified
+    pub checksum: Checksum,
+}
+
+/// A blob as stored in a container.
+pub struct Blob<'a> {
+    pub writer_id: u32,
+    /// submission order among the blobs of the same writer, 0 for files older than
+    /// version 5
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub content_type: ContentType,
+    meta: &'a [u8],
+    /// borrowed from the container unless it had to be decompressed
+    pub data: Cow<'a, [u8]>,
+}
+
+/// A blob read by `ContainerReader`, owning its data.
+pub struct OwnedBlob {
+    pub writer_id: u32,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub content_type: ContentType,
+    meta: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Why a container couldn't be read, for callers that need to tell the failures
+/// apart; converts into the `io::Error` the rest of the API returns, which keeps it
+/// as its inner error.
+#[derive(Debug)]
+pub enum BlobError {
+    /// not a container file
+    BadMagic,
+    /// written in a format version newer than the one this reads
+    VersionUnsupported {
+        got: u32,
+    },
+    /// the data header names no known `ChecksumAlgo`
+    UnknownChecksumAlgo {
+        got: u32,
+    },
+    /// the checksum computed over the container differs from the stored one
+    ChecksumMismatch {
+        expected: Checksum,
+        got: Checksum,
+    },
+    /// the TOC doesn't account for exactly the bytes of the data section
+    SizeMismatch {
+        toc_bytes: u64,
+        data_bytes: u64,
+    },
+    /// TOC entries whose blob can't be read or doesn't match its CRC
+    BadBlobs(Vec<BadBlob>),
+    /// the file ends within the headers, the TOC or the data of a blob
+    Truncated,
+    Io(io::Error),
+}
+
+/// Why a container doesn't verify, as reported by `Container::verify`.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// the checksum computed over the container differs from the stored one
+    ChecksumMismatch {
+        stored: Checksum,
+        computed: Checksum,
+    },
+    /// the TOC doesn't account for exactly the bytes of the data section
+    SizeMismatch { toc_bytes: u64, data_bytes: u64 },
+    /// TOC entries whose blob can't be read, in TOC order
+    BadBlobs(Vec<BadBlob>),
+}
+
+/// A TOC entry that fails verification.
+#[derive(Debug)]
+pub struct BadBlob {
+    /// the entry's index in the TOC
+    pub index: usize,
+    pub writer_id: u32,
+    pub sequence: u64,
+    pub problem: BlobProblem,
+}
+
+#[derive(Debug)]
+pub enum BlobProblem {
+    /// the entry points past the end of the data section
+    MissingData,
+    /// the entry's data doesn't match its CRC
+    BadData,
+    /// the entry's metadata doesn't decode
+    BadMeta(io::Error),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "checksum mismatch: stored {:08x}, computed {:08x}",
+                stored.short, computed.short
+            ),
+            Self::SizeMismatch {
+                toc_bytes,
+                data_bytes,
+            } => write!(
+                f,
+                "size mismatch: the TOC covers {} bytes, the data section holds {}",
+                toc_bytes, data_bytes
+            ),
+            Self::BadBlobs(bad) => write_bad_blobs(f, bad),
+        }
+    }
+}
+
+fn write_bad_blobs(f: &mut fmt::Formatter<'_>, bad: &[BadBlob]) -> fmt::Result {
+    write!(f, "bad blobs:")?;
+    for blob in bad {
+        match &blob.problem {
+            BlobProblem::MissingData => write!(f, " #{} missing data", blob.index)?,
+            BlobProblem::BadData => write!(f, " #{} damaged data", blob.index)?,
+            BlobProblem::BadMeta(err) => write!(f, " #{} bad metadata ({})", blob.index, err)?,
+        }
+    }
+    Ok(())
+}
+
+impl std::error::Error for VerifyError {}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a container file"),
+            Self::VersionUnsupported { got } => write!(
+                f,
+                "container format version {} is newer than the supported version {}",
+                got, VERSION
+            ),
+            Self::UnknownChecksumAlgo { got } => write!(f, "unknown checksum algorithm {}", got),
+            Self::ChecksumMismatch { expected, got } => VerifyError::ChecksumMismatch {
+                stored: *expected,
+                computed: *got,
+            }
+            .fmt(f),
+            Self::SizeMismatch {
+                toc_bytes,
+                data_bytes,
+            } => VerifyError::SizeMismatch {
+                toc_bytes: *toc_bytes,
+                data_bytes: *data_bytes,
+            }
+            .fmt(f),
+            Self::BadBlobs(bad) => write_bad_blobs(f, bad),
+            Self::Truncated => write!(f, "container truncated"),
+            Self::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl BlobError {
+    /// Whether the container read doesn't match its checksum or the CRC of a blob,
+    /// i.e. it is damaged rather than unreadable.
+    pub fn is_checksum_failure(&self) -> bool {
+        matches!(self, Self::ChecksumMismatch { .. } | Self::BadBlobs(_))
+    }
+
+    /// `is_checksum_failure` of a `BlobError` converted into `err`.
+    pub fn is_checksum_failure_in(err: &io::Error) -> bool {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Self>())
+            .is_some_and(Self::is_checksum_failure)
+    }
+}
+
+impl std::error::Error for BlobError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlobError {
+    /// Unwraps a `BlobError` converted into an `io::Error` before; a read hitting
+    /// the end of the file means it is truncated.
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<BlobError>()) {
+            return *err
+                .into_inner()
+                .and_then(|inner| inner.downcast().ok())
+                .expect("checked above");
+        }
+        match err.kind() {
+            ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<VerifyError> for BlobError {
+    fn from(err: VerifyError) -> Self {
+        match err {
+            VerifyError::ChecksumMismatch { stored, computed } => Self::ChecksumMismatch {
+                expected: stored,
+                got: computed,
+            },
+            VerifyError::SizeMismatch {
+                toc_bytes,
+                data_bytes,
+            } => Self::SizeMismatch {
+                toc_bytes,
+                data_bytes,
+            },
+            VerifyError::BadBlobs(bad) => Self::BadBlobs(bad),
+        }
+    }
+}
+
+impl From<BlobError> for io::Error {
+    fn from(err: BlobError) -> Self {
+        let kind = match err {
+            BlobError::Io(err) => return err,
+            BlobError::BadMagic
+            | BlobError::VersionUnsupported { .. }
+            | BlobError::UnknownChecksumAlgo { .. } => ErrorKind::Unsupported,
+            BlobError::ChecksumMismatch { .. }
+            | BlobError::SizeMismatch { .. }
+            | BlobError::BadBlobs(_) => ErrorKind::InvalidData,
+            BlobError::Truncated => ErrorKind::UnexpectedEof,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl Codec {
+    fn from_u32(code: u32) -> io::Result<Self> {
+        match code {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Deflate),
+            2 => Ok(Self::DeflateBlock),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown codec {}", code),
+            )),
+        }
+    }
+}
+
+impl BlobLocation {
+    pub fn is_compressed(&self) -> bool {
+        self.entry.codec != Codec::None
+    }
+
+    /// The blob's data from the `stored_size` bytes at `offset`, decompressed if
+    /// needed.
+    pub fn decode(&self, stored: &[u8]) -> io::Result<Vec<u8>> {
+        self.entry.decode(stored).map(Cow::into_owned)
+    }
+}
+
+impl Blob<'_> {
+    pub fn meta(&self) -> io::Result<BlobMeta> {
+        meta::decode(self.meta)
+    }
+}
+
+impl OwnedBlob {
+    pub fn meta(&self) -> io::Result<BlobMeta> {
+        meta::decode(&self.meta)
+    }
 }
 
 impl FileHeader {
@@ -65,116 +563,2210 @@ impl DataHeader {
         buf.extend_from_slice(as_u8_slice::<u32>(&self.reserved));
         buf
     }
+
+    pub fn checksum_algo(&self) -> ChecksumAlgo {
+        ChecksumAlgo::from_u32(self.reserved[RESERVED_CHECKSUM_ALGO] & !HEADERS_SCOPE)
+            .expect("validated by read_header")
+    }
+
+    pub fn checksum_scope(&self) -> ChecksumScope {
+        if self.reserved[RESERVED_CHECKSUM_ALGO] & HEADERS_SCOPE != 0 {
+            ChecksumScope::Headers
+        } else {
+            ChecksumScope::Full
+        }
+    }
+
+    fn set_checksum_scope(&mut self, scope: ChecksumScope) {
+        let word = &mut self.reserved[RESERVED_CHECKSUM_ALGO];
+        match scope {
+            ChecksumScope::Full => *word &= !HEADERS_SCOPE,
+            ChecksumScope::Headers => *word |= HEADERS_SCOPE,
+        }
+    }
+
+    /// Whether the TOC entries carry the CRC of their data.
+    fn has_data_crcs(&self) -> bool {
+        self.version >= 6
+    }
+
+    fn has_trailer(&self) -> bool {
+        self.version >= 7
+    }
+
+    fn trailer_size(&self) -> u64 {
+        if self.has_trailer() {
+            TRAILER_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// The bytes covered by the checksum: the header with the digest words zeroed.
+    pub fn hashed_bytes(&self) -> Vec<u8> {
+        let mut reserved = self.reserved;
+        reserved[RESERVED_DIGEST..RESERVED_DIGEST + DIGEST_WORDS].fill(0);
+        DataHeader::new(self.version, self.type_id, self.toc_size, reserved).as_bytes()
+    }
+
+    /// When the container was created, `None` for files written before it was
+    /// recorded.
+    pub fn created(&self) -> Option<u64> {
+        let created = (self.reserved[RESERVED_CREATED + 1] as u64) << 32
+            | self.reserved[RESERVED_CREATED] as u64;
+        (created != 0).then_some(created)
+    }
+
+    fn digest_words(&self) -> [u32; DIGEST_WORDS] {
+        let mut words = [0; DIGEST_WORDS];
+        words.copy_from_slice(&self.reserved[RESERVED_DIGEST..RESERVED_DIGEST + DIGEST_WORDS]);
+        words
+    }
+
+    fn set_digest_words(&mut self, words: [u32; DIGEST_WORDS]) {
+        self.reserved[RESERVED_DIGEST..RESERVED_DIGEST + DIGEST_WORDS].copy_from_slice(&words);
+    }
+}
+
+impl Trailer {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(TRAILER_SIZE as usize);
+        buf.extend_from_slice(&self.total.to_le_bytes());
+        buf.extend_from_slice(&self.checksum.to_le_bytes());
+        buf.extend_from_slice(&TRAILER_MAGIC.to_le_bytes());
+        buf
+    }
+
+    /// The checksum repeated by the trailer `reader` is positioned at, `None` if it
+    /// isn't the trailer of a file of length `len`.
+    fn read_from<R: Read>(reader: &mut R, len: u64) -> io::Result<Option<u32>> {
+        let total = reader.read_u64::<LittleEndian>()?;
+        let checksum = reader.read_u32::<LittleEndian>()?;
+        let magic = reader.read_u32::<LittleEndian>()?;
+        Ok((magic == TRAILER_MAGIC && total == len).then_some(checksum))
+    }
+}
+
+impl FilePermissions {
+    /// Creates the file at `path` with these permissions, truncating it if it exists.
+    pub fn create(&self, path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::OpenOptionsExt;
+            // narrowed by the umask, `apply` sets it exactly
+            options.mode(mode);
+        }
+        let file = options.open(path)?;
+        self.apply(&file)?;
+        Ok(file)
+    }
+
+    /// Sets these permissions on an open file.
+    pub fn apply(&self, file: &File) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = self.mode {
+                file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+            }
+            if let Some(gid) = self.gid {
+                std::os::unix::fs::fchown(file, None, Some(gid))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = file;
+        Ok(())
+    }
+}
+
+fn reserved_for(checksum_algo: ChecksumAlgo, scope: ChecksumScope, created: u64) -> [u32; 11] {
+    let mut reserved = RESERVED;
+    reserved[RESERVED_CHECKSUM_ALGO] = match scope {
+        ChecksumScope::Full => checksum_algo as u32,
+        ChecksumScope::Headers => checksum_algo as u32 | HEADERS_SCOPE,
+    };
+    reserved[RESERVED_CREATED] = created as u32;
+    reserved[RESERVED_CREATED + 1] = (created >> 32) as u32;
+    reserved
 }
 
 impl TocEntry {
-    pub fn new(writer_id: u32, data_size: u32) -> Self {
+    pub fn new(
+        writer_id: u32,
+        sequence: u64,
+        data_offset: u64,
+        data_size: u32,
+        content_type: ContentType,
+        meta: Vec<u8>,
+    ) -> Self {
         Self::new_with_timestamp(
             writer_id,
+            sequence,
+            data_offset,
             data_size,
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            now_micros(),
+            content_type,
+            meta,
         )
     }
-    pub fn new_with_timestamp(writer_id: u32, data_size: u32, timestamp: u64) -> Self {
+    pub fn new_with_timestamp(
+        writer_id: u32,
+        sequence: u64,
+        data_offset: u64,
+        data_size: u32,
+        timestamp: u64,
+        content_type: ContentType,
+        meta: Vec<u8>,
+    ) -> Self {
         Self {
             writer_id,
+            sequence,
+            data_offset,
             data_size,
+            data_crc: 0,
+            codec: Codec::None,
+            raw_offset: 0,
+            raw_size: data_size,
             timestamp,
+            content_type,
+            meta,
         }
     }
 
+    fn with_data_crc(mut self, data_crc: u32) -> Self {
+        self.data_crc = data_crc;
+        self
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
+        self.as_bytes_for(VERSION)
+    }
+
+    /// The entry in the layout of format `version`, needed to verify older files.
+    pub fn as_bytes_for(&self, version: u32) -> Vec<u8> {
         let mut buf = Vec::new();
         buf.extend_from_slice(as_u8_slice::<u32>(&[self.writer_id, self.data_size]));
         buf.extend_from_slice(as_u8_slice::<u64>(&[self.timestamp]));
+        if version >= 2 {
+            buf.extend_from_slice(as_u8_slice::<u32>(&[self.content_type.code()]));
+        }
+        if version >= 3 {
+            buf.extend_from_slice(as_u8_slice::<u32>(&[self.meta.len() as u32]));
+        }
+        if version >= 4 {
+            buf.extend_from_slice(as_u8_slice::<u64>(&[self.data_offset]));
+        }
+        if version >= 5 {
+            buf.extend_from_slice(as_u8_slice::<u64>(&[self.sequence]));
+        }
+        if version >= 6 {
+            buf.extend_from_slice(as_u8_slice::<u32>(&[self.data_crc]));
+        }
+        if version >= 8 {
+            buf.extend_from_slice(as_u8_slice::<u32>(&[
+                self.codec as u32,
+                self.raw_offset,
+                self.raw_size,
+            ]));
+        }
         buf
     }
+
+    /// Reads an entry in the layout of `version` and returns it with the size of its
+    /// metadata, which is read separately from the metadata section. `next_offset` is
+    /// where the data of a file without stored offsets continues.
+    fn read_from<R: Read>(
+        reader: &mut R,
+        version: u32,
+        next_offset: u64,
+    ) -> io::Result<(Self, u32)> {
+        let writer_id = reader.read_u32::<LittleEndian>()?;
+        let data_size = reader.read_u32::<LittleEndian>()?;
+        let timestamp = reader.read_u64::<LittleEndian>()?;
+        let content_type = if version >= 2 {
+            ContentType::from_code(reader.read_u32::<LittleEndian>()?)
+        } else {
+            ContentType::None
+        };
+        let meta_size = if version >= 3 {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+        let data_offset = if version >= 4 {
+            reader.read_u64::<LittleEndian>()?
+        } else {
+            next_offset
+        };
+        let sequence = if version >= 5 {
+            reader.read_u64::<LittleEndian>()?
+        } else {
+            0
+        };
+        let data_crc = if version >= 6 {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+        let (codec, raw_offset, raw_size) = if version >= 8 {
+            (
+                Codec::from_u32(reader.read_u32::<LittleEndian>()?)?,
+                reader.read_u32::<LittleEndian>()?,
+                reader.read_u32::<LittleEndian>()?,
+            )
+        } else {
+            (Codec::None, 0, data_size)
+        };
+        let mut toc_entry = Self::new_with_timestamp(
+            writer_id,
+            sequence,
+            data_offset,
+            data_size,
+            timestamp,
+            content_type,
+            Vec::new(),
+        )
+        .with_data_crc(data_crc);
+        toc_entry.codec = codec;
+        toc_entry.raw_offset = raw_offset;
+        toc_entry.raw_size = raw_size;
+        Ok((toc_entry, meta_size))
+    }
+
+    fn data_end(&self) -> u64 {
+        self.data_offset + self.data_size as u64
+    }
+
+    /// The blob's data from the bytes stored for the entry, decompressed if needed.
+    fn decode<'a>(&self, stored: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        if self.codec == Codec::None {
+            return Ok(Cow::Borrowed(stored));
+        }
+        let end = self.raw_offset as u64 + self.raw_size as u64;
+        let mut raw = Vec::with_capacity(end as usize);
+        DeflateDecoder::new(stored)
+            .take(end)
+            .read_to_end(&mut raw)?;
+        if raw.len() as u64 != end {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "compressed data shorter than the TOC says",
+            ));
+        }
+        raw.drain(..self.raw_offset as usize);
+        Ok(Cow::Owned(raw))
+    }
+
+    /// Whether the data section `data` holds the entry's data as its CRC says.
+    fn data_intact(&self, data: &[u8]) -> bool {
+        data.get(self.data_offset as usize..self.data_end() as usize)
+            .is_some_and(|data| data_crc(data) == self.data_crc)
+    }
+}
+
+impl Default for StreamedBlob {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            crc: DefaultCrc32::new(),
+            hasher: None,
+        }
+    }
+}
+
+impl DedupIndex {
+    /// Returns the offset of an identical blob stored earlier, or records that `data`
+    /// is about to be stored at `offset`.
+    fn find_or_insert(&mut self, data: &[u8], offset: u64) -> Option<u64> {
+        self.find_or_insert_hash(Sha256::digest(data).into(), data.len() as u64, offset)
+    }
+
+    fn find_or_insert_hash(&mut self, hash: [u8; 32], size: u64, offset: u64) -> Option<u64> {
+        match self.offsets.get(&hash) {
+            Some(existing) => {
+                self.saved_bytes += size;
+                Some(*existing)
+            }
+            None => {
+                self.offsets.insert(hash, offset);
+                None
+            }
+        }
+    }
 }
 
 impl Container {
     pub fn new(type_id: u32) -> Self {
+        Self::with_checksum_algo(type_id, ChecksumAlgo::default())
+    }
+
+    pub fn with_checksum_algo(type_id: u32, checksum_algo: ChecksumAlgo) -> Self {
+        Self::with_clock(type_id, checksum_algo, Arc::new(now_micros))
+    }
+
+    /// A container whose creation time and blob timestamps come from `clock`, e.g. a
+    /// constant for golden-file tests. Containers split off it share the clock.
+    pub fn with_clock(type_id: u32, checksum_algo: ChecksumAlgo, clock: Clock) -> Self {
         Self {
             file_header: FileHeader::new(0),
-            data_header: DataHeader::new(VERSION, type_id, 0, RESERVED),
+            data_header: DataHeader::new(
+                VERSION,
+                type_id,
+                0,
+                reserved_for(checksum_algo, ChecksumScope::Full, clock()),
+            ),
             toc: Vec::new(),
             data: Vec::new(),
+            dedup: None,
+            clock,
         }
     }
 
-    pub fn push(&mut self, writer_id: u32, data: &[u8]) {
-        self.data.write_all(data).unwrap();
-        let toc_entry = TocEntry::new(writer_id, data.len() as u32);
+    /// A container with room for `objects` blobs and `bytes_hint` bytes of data, so
+    /// filling a batch of known size doesn't grow the buffers as it goes.
+    pub fn with_capacity(type_id: u32, objects: usize, bytes_hint: usize) -> Self {
+        let mut container = Self::new(type_id);
+        container.reserve(objects, bytes_hint);
+        container
+    }
+
+    /// Makes room for `objects` more blobs and `bytes` more bytes of data.
+    pub fn reserve(&mut self, objects: usize, bytes: usize) {
+        self.toc.reserve_exact(objects);
+        self.data.reserve_exact(bytes);
+    }
+
+    /// Microseconds since the Unix epoch when the container was created, `None` for
+    /// files written before it was recorded.
+    pub fn created(&self) -> Option<u64> {
+        self.data_header.created()
+    }
+
+    /// The oldest and newest blob timestamp, `None` for an empty container. The
+    /// reserved header words are all taken, so for a file without loading its data
+    /// use `summarize`, which reads the TOC only.
+    pub fn timestamp_range(&self) -> Option<(u64, u64)> {
+        timestamp_range(&self.toc)
+    }
+
+    /// Makes `push` store the data of identical blobs only once.
+    pub fn enable_dedup(&mut self) {
+        self.dedup = Some(DedupIndex::default());
+    }
+
+    pub fn checksum_scope(&self) -> ChecksumScope {
+        self.data_header.checksum_scope()
+    }
+
+    /// Selects what the checksum covers, `ChecksumScope::Full` unless the container
+    /// was read from a file written with another scope.
+    pub fn set_checksum_scope(&mut self, scope: ChecksumScope) {
+        self.data_header.set_checksum_scope(scope);
+    }
+
+    pub fn push(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let timestamp = (self.clock)();
+        self.push_with_timestamp(writer_id, sequence, timestamp, content_type, meta, data)
+    }
+
+    /// Like `push`, stamping the blob with `timestamp` instead of the container's
+    /// clock, e.g. the time the event it holds happened.
+    pub fn push_with_timestamp(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        timestamp: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let meta = meta::encode(meta)?;
+        let next_offset = self.data.len() as u64;
+        let duplicate = self
+            .dedup
+            .as_mut()
+            .and_then(|dedup| dedup.find_or_insert(data, next_offset));
+        let data_offset = match duplicate {
+            Some(data_offset) => data_offset,
+            None => {
+                self.data.write_all(data)?;
+                next_offset
+            }
+        };
+        let toc_entry = TocEntry::new_with_timestamp(
+            writer_id,
+            sequence,
+            data_offset,
+            data.len() as u32,
+            timestamp,
+            content_type,
+            meta,
+        )
+        .with_data_crc(data_crc(data));
         self.toc.push(toc_entry);
+        Ok(())
     }
 
+    /// Iterates over the stored blobs in TOC order. Stops early if the data section
+    /// is shorter than the TOC claims.
+    pub fn blobs(&self) -> impl Iterator<Item = Blob<'_>> {
+        self.toc.iter().map_while(move |entry| self.blob(entry))
+    }
+
+    /// Iterates over the blobs of `writer_id` in TOC order, skipping the TOC entries
+    /// of other writers without touching their data. Stops early like `blobs`.
+    pub fn iter_by_writer(&self, writer_id: u32) -> impl Iterator<Item = Blob<'_>> {
+        self.toc
+            .iter()
+            .filter(move |entry| entry.writer_id == writer_id)
+            .map_while(move |entry| self.blob(entry))
+    }
+
+    /// The blob of a TOC entry, `None` if its data is missing or doesn't decompress.
+    fn blob<'a>(&'a self, entry: &'a TocEntry) -> Option<Blob<'a>> {
+        let stored = self
+            .data
+            .get(entry.data_offset as usize..entry.data_end() as usize)?;
+        Some(Blob {
+            writer_id: entry.writer_id,
+            sequence: entry.sequence,
+            timestamp: entry.timestamp,
+            content_type: entry.content_type,
+            meta: entry.meta.as_slice(),
+            data: entry.decode(stored).ok()?,
+        })
+    }
+
+    /// The data header as stored, with `toc_size` matching the current TOC. The
+    /// version of a container read from a file is kept so its checksum still verifies.
     pub fn get_data_header(&self) -> DataHeader {
         DataHeader::new(
-            VERSION,
+            self.data_header.version,
             self.data_header.type_id,
             self.toc.len() as u32,
-            RESERVED,
+            self.data_header.reserved,
         )
     }
 
-    pub fn checksum(&self) -> u32 {
-        let mut hasher = Hasher::new();
-        hasher.update(self.get_data_header().as_bytes().as_slice());
-        self.toc
-            .iter()
-            .for_each(|toc_entry| hasher.update(toc_entry.as_bytes().as_slice()));
+    /// Whether the TOC accounts for exactly the bytes in the data section.
+    pub fn sizes_match(&self) -> bool {
+        let data_end = self.toc.iter().map(TocEntry::data_end).max().unwrap_or(0);
+        data_end == self.data.len() as u64
+    }
+
+    /// Computes the checksum with the container's algorithm over the data header, the
+    /// TOC and, unless the scope is `ChecksumScope::Headers`, the data.
+    pub fn checksum(&self) -> Checksum {
+        let mut hasher = self.data_header.checksum_algo().hasher();
+        self.hash_into(|buf| hasher.update(buf));
+        hasher.finalize()
+    }
+
+    /// Computes the checksum with the container's algorithm over the data section
+    /// alone, whatever the scope: containers with the same blob data in the same
+    /// order share it even when their headers, e.g. their timestamps, differ.
+    pub fn data_checksum(&self) -> Checksum {
+        let mut hasher = self.data_header.checksum_algo().hasher();
         hasher.update(self.data.as_slice());
         hasher.finalize()
     }
 
+    /// Computes a CRC-32 with `C` over the bytes `checksum` covers, whatever the
+    /// container's algorithm, e.g. `TableCrc32<CRC32_CASTAGNOLI>` to cross-check with
+    /// a tool using that variant.
+    pub fn crc32_with<C: Crc32>(&self) -> u32 {
+        let mut crc = C::new();
+        self.hash_into(|buf| crc.update(buf));
+        crc.finalize()
+    }
+
+    fn hash_into(&self, mut update: impl FnMut(&[u8])) {
+        update(self.get_data_header().hashed_bytes().as_slice());
+        update(toc_bytes(&self.toc, self.data_header.version).as_slice());
+        if self.data_header.checksum_scope() == ChecksumScope::Full {
+            update(self.data.as_slice());
+        }
+    }
+
+    /// Checks a container loaded with `from_file_unchecked`: first that every TOC
+    /// entry's data and metadata can be read and the data matches its CRC, then that
+    /// the TOC covers the data section exactly, then the checksum. Stops at the first
+    /// of these that fails.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let data_bytes = self.data.len() as u64;
+        let bad: Vec<BadBlob> = self
+            .toc
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                let problem = if entry.data_end() > data_bytes {
+                    BlobProblem::MissingData
+                } else if self.data_header.has_data_crcs() && !entry.data_intact(&self.data) {
+                    BlobProblem::BadData
+                } else {
+                    meta::decode(&entry.meta).err().map(BlobProblem::BadMeta)?
+                };
+                Some(BadBlob {
+                    index,
+                    writer_id: entry.writer_id,
+                    sequence: entry.sequence,
+                    problem,
+                })
+            })
+            .collect();
+        if !bad.is_empty() {
+            return Err(VerifyError::BadBlobs(bad));
+        }
+        if !self.sizes_match() {
+            return Err(VerifyError::SizeMismatch {
+                toc_bytes: self.toc.iter().map(TocEntry::data_end).max().unwrap_or(0),
+                data_bytes,
+            });
+        }
+        self.verify_checksum()
+    }
+
+    /// Checks the checksum, and the blobs against their CRCs when the checksum leaves
+    /// them out.
+    fn verify_checksum(&self) -> Result<(), VerifyError> {
+        let (stored, computed) = (self.stored_checksum(), self.checksum());
+        if stored != computed {
+            return Err(VerifyError::ChecksumMismatch { stored, computed });
+        }
+        if self.data_header.checksum_scope() == ChecksumScope::Headers {
+            check_data_crcs(&self.toc, &self.data)?;
+        }
+        Ok(())
+    }
+
+    fn stored_checksum(&self) -> Checksum {
+        Checksum {
+            short: self.file_header.checksum,
+            words: self.data_header.digest_words(),
+        }
+    }
+
+    /// Brings the headers up to date with the TOC and stores a fresh checksum.
+    fn seal(&mut self) {
+        self.data_header = self.get_data_header();
+        let checksum = self.checksum();
+        self.file_header.checksum = checksum.short;
+        self.data_header.set_digest_words(checksum.words);
+    }
+
+    /// Serializes the container in the current format version, exactly as
+    /// `save_to_file` writes it. Takes `&mut self` because it seals the headers.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_bytes(&mut self) -> Vec<u8> {
+        self.data_header.version = VERSION;
+        self.seal();
+        let toc = toc_bytes(&self.toc, VERSION);
+        let mut buf =
+            Vec::with_capacity((HEADER_SIZE + TRAILER_SIZE) as usize + toc.len() + self.data.len());
+        buf.extend_from_slice(self.file_header.as_bytes().as_slice());
+        buf.extend_from_slice(self.data_header.as_bytes().as_slice());
+        buf.extend_from_slice(toc.as_slice());
+        buf.extend_from_slice(self.data.as_slice());
+        let trailer = Trailer {
+            total: buf.len() as u64 + TRAILER_SIZE,
+            checksum: self.file_header.checksum,
+        };
+        buf.extend_from_slice(trailer.as_bytes().as_slice());
+        buf
+    }
+
+    /// Writes the container in the current format version.
     pub fn save_to_file(&mut self, mut file: File) -> io::Result<usize> {
-        self.file_header.checksum = self.checksum();
-        file.write(self.file_header.as_bytes().as_slice())?;
-        file.write(self.get_data_header().as_bytes().as_slice())?;
-        for toc_entry in self.toc.as_slice() {
-            file.write(toc_entry.as_bytes().as_slice())?;
+        let bytes = self.to_bytes();
+        file.write_all(bytes.as_slice())?;
+        Ok(bytes.len())
+    }
+
+    /// Appends the blobs of this container to the container stored at `path`,
+    /// creating the file if it doesn't exist yet.
+    ///
+    /// The TOC precedes the data on disk, so this is a read-modify-write: the whole
+    /// existing file is loaded into memory, extended and rewritten (through a temporary
+    /// file that is renamed over the original). The cost of every append grows with
+    /// the size of the existing file.
+    pub fn append_to_file(&mut self, path: &Path) -> io::Result<usize> {
+        let mut container = if path.exists() {
+            let existing = Self::from_file(File::open(path)?)?;
+            if existing.data_header.type_id != self.data_header.type_id {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type_id mismatch: file has {}, container has {}",
+                        existing.data_header.type_id, self.data_header.type_id
+                    ),
+                ));
+            }
+            existing
+        } else {
+            let mut container = Self::with_clock(
+                self.data_header.type_id,
+                self.data_header.checksum_algo(),
+                self.clock.clone(),
+            );
+            container.set_checksum_scope(self.checksum_scope());
+            container
+        };
+        container.append_blobs(self);
+        let tmp_path = path.with_extension("blob.tmp");
+        let size = container.save_to_file(File::create(&tmp_path)?)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(size)
+    }
+
+    /// Combines containers of the same type into one, keeping their TOC entries
+    /// (including the original timestamps) and data in order.
+    pub fn merge(containers: Vec<Container>) -> io::Result<Container> {
+        let (objects, bytes) = containers
+            .iter()
+            .skip(1)
+            .fold((0, 0), |(objects, bytes), c| {
+                (objects + c.toc.len(), bytes + c.data.len())
+            });
+        let mut containers = containers.into_iter();
+        let mut merged = match containers.next() {
+            None => return Err(io::Error::new(ErrorKind::InvalidInput, "nothing to merge")),
+            Some(first) => first,
+        };
+        merged.reserve(objects, bytes);
+        for mut container in containers {
+            if container.data_header.type_id != merged.data_header.type_id {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type_id mismatch: expected {}, got {}",
+                        merged.data_header.type_id, container.data_header.type_id
+                    ),
+                ));
+            }
+            merged.append_blobs(&mut container);
         }
-        file.write(self.data.as_slice())
+        merged.data_header.version = VERSION;
+        merged.seal();
+        Ok(merged)
     }
 
-    pub fn from_file(mut file: File) -> io::Result<Self> {
-        let magic = file.read_u32::<LittleEndian>()?;
+    /// Splits the container into containers of at most `max_objects` blobs each, the
+    /// inverse of `merge`. The TOC entries keep their order, writer_ids, sequence
+    /// numbers and timestamps; each piece gets the data of its own entries, data
+    /// shared by deduplicated entries within a piece is stored once. An empty
+    /// container splits into nothing.
+    pub fn split(self, max_objects: usize) -> io::Result<Vec<Container>> {
+        if max_objects == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "max_objects must be positive",
+            ));
+        }
+        let reserved = reserved_for(
+            self.data_header.checksum_algo(),
+            self.data_header.checksum_scope(),
+            self.data_header.created().unwrap_or_default(),
+        );
+        let mut pieces = Vec::new();
+        let mut toc = self.toc.into_iter().peekable();
+        while toc.peek().is_some() {
+            let mut piece = Self {
+                file_header: FileHeader::new(0),
+                data_header: DataHeader::new(VERSION, self.data_header.type_id, 0, reserved),
+                toc: Vec::new(),
+                data: Vec::new(),
+                dedup: None,
+                clock: self.clock.clone(),
+            };
+            let mut copied: HashMap<(u64, u32), u64> = HashMap::new();
+            for mut toc_entry in toc.by_ref().take(max_objects) {
+                let region = (toc_entry.data_offset, toc_entry.data_size);
+                toc_entry.data_offset = match copied.get(&region) {
+                    Some(data_offset) => *data_offset,
+                    None => {
+                        let data = self
+                            .data
+                            .get(toc_entry.data_offset as usize..toc_entry.data_end() as usize)
+                            .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+                        let data_offset = piece.data.len() as u64;
+                        piece.data.extend_from_slice(data);
+                        copied.insert(region, data_offset);
+                        data_offset
+                    }
+                };
+                piece.toc.push(toc_entry);
+            }
+            piece.seal();
+            pieces.push(piece);
+        }
+        Ok(pieces)
+    }
+
+    /// Moves the blobs of `other` behind the blobs of this container.
+    fn append_blobs(&mut self, other: &mut Container) {
+        let shift = self.data.len() as u64;
+        for toc_entry in other.toc.iter_mut() {
+            toc_entry.data_offset += shift;
+        }
+        self.toc.append(&mut other.toc);
+        self.data.append(&mut other.data);
+    }
+
+    /// Reads a container from a file or any other reader, e.g. a `Cursor` over bytes
+    /// from `to_bytes`, and verifies its checksum. Use `from_file_unchecked` and
+    /// `verify` to inspect a container that fails. The whole data section is loaded,
+    /// `ContainerReader` scans large files blob by blob instead.
+    ///
+    /// Files with a trailer fail with `BlobError::Truncated` before the checksum is
+    /// computed if it is missing or doesn't match the length read.
+    pub fn from_file<R: Read>(file: R) -> Result<Self, BlobError> {
+        let (container, trailer) = Self::read_from(file)?;
+        if container.data_header.has_trailer() && trailer != Some(container.file_header.checksum) {
+            return Err(BlobError::Truncated);
+        }
+        container.verify_checksum()?;
+        Ok(container)
+    }
+
+    /// Reads and verifies a container held in memory, e.g. received over the network
+    /// or fetched from object storage.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BlobError> {
+        Self::from_file(Cursor::new(bytes))
+    }
+
+    /// Finds the blob at `index` in the TOC reading only the headers and the TOC, so
+    /// its data can be served straight from the file. The checksum isn't verified.
+    pub fn locate_blob(path: &Path, index: usize) -> Result<Option<BlobLocation>, BlobError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (_, _, toc) = read_header(&mut reader)?;
+        let data_start = reader.stream_position()?;
+        Ok(toc.into_iter().nth(index).map(|entry| BlobLocation {
+            offset: data_start + entry.data_offset,
+            stored_size: entry.data_size,
+            size: entry.raw_size,
+            content_type: entry.content_type,
+            entry,
+        }))
+    }
+
+    /// Summarizes a container file reading only the headers and the TOC. The checksum
+    /// isn't verified.
+    pub fn summarize(path: &Path) -> Result<ContainerSummary, BlobError> {
+        let (file_header, data_header, toc) = read_header(&mut BufReader::new(File::open(path)?))?;
+        Ok(ContainerSummary {
+            type_id: data_header.type_id,
+            objects: toc.len(),
+            time_range: timestamp_range(&toc),
+            created: data_header.created(),
+            checksum_algo: data_header.checksum_algo(),
+            checksum_scope: data_header.checksum_scope(),
+            checksum: Checksum {
+                short: file_header.checksum,
+                words: data_header.digest_words(),
+            },
+        })
+    }
+
+    /// Reads the type_id of a container file from the first words of its headers,
+    /// checking only the magic and the version.
+    pub fn peek_type_id(path: &Path) -> Result<u32, BlobError> {
+        let mut reader = File::open(path)?;
+        let mut words = [0u32; 4];
+        reader.read_u32_into::<LittleEndian>(&mut words)?;
+        let [magic, _checksum, version, type_id] = words;
         if magic != MAGIC {
-            return Err(io::Error::from(ErrorKind::Unsupported));
-        }
-        let checksum = file.read_u32::<LittleEndian>()?;
-        let version: u32 = file.read_u32::<LittleEndian>()?;
-        let type_id: u32 = file.read_u32::<LittleEndian>()?;
-        let toc_size: u32 = file.read_u32::<LittleEndian>()?;
-        let mut reserved = [0u32; 11];
-        file.read_u32_into::<LittleEndian>(&mut reserved)?;
+            return Err(BlobError::BadMagic);
+        }
+        if version > VERSION {
+            return Err(BlobError::VersionUnsupported { got: version });
+        }
+        Ok(type_id)
+    }
+
+    /// Reads a container without verifying its checksum, for repairing damaged files
+    /// or inspecting them with `verify`. A trailer not matching the length read is
+    /// left in the data section.
+    pub fn from_file_unchecked<R: Read>(file: R) -> Result<Self, BlobError> {
+        Ok(Self::read_from(file)?.0)
+    }
+
+    /// Reads a container and the checksum repeated by its trailer, which is cut off
+    /// the data section if it matches the length read.
+    fn read_from<R: Read>(mut file: R) -> Result<(Self, Option<u32>), BlobError> {
+        let (file_header, data_header, toc) = read_header(&mut file)?;
         let mut container = Self {
-            file_header: FileHeader::new(checksum),
-            data_header: DataHeader::new(version, type_id, toc_size, reserved),
-            toc: Vec::new(),
+            file_header,
+            data_header,
+            toc,
             data: Vec::new(),
+            dedup: None,
+            clock: Arc::new(now_micros),
         };
+        file.read_to_end(&mut container.data)?;
+        let mut trailer = None;
+        if container.data_header.has_trailer() && container.data.len() as u64 >= TRAILER_SIZE {
+            let data_size = container.data.len() - TRAILER_SIZE as usize;
+            let len = HEADER_SIZE
+                + toc_bytes(&container.toc, container.data_header.version).len() as u64
+                + container.data.len() as u64;
+            trailer = Trailer::read_from(&mut &container.data[data_size..], len)?;
+            if trailer.is_some() {
+                container.data.truncate(data_size);
+            }
+        }
+        if !container.data_header.has_data_crcs() {
+            // for when the container is written in the current version
+            for toc_entry in container.toc.iter_mut() {
+                if let Some(data) = container
+                    .data
+                    .get(toc_entry.data_offset as usize..toc_entry.data_end() as usize)
+                {
+                    toc_entry.data_crc = data_crc(data);
+                }
+            }
+        }
+        Ok((container, trailer))
+    }
 
-        for _ in 0..toc_size {
-            let toc_entry = TocEntry::new_with_timestamp(
-                file.read_u32::<LittleEndian>()?,
-                file.read_u32::<LittleEndian>()?,
-                file.read_u64::<LittleEndian>()?,
-            );
-            container.toc.push(toc_entry)
+    /// Recomputes the checksum of the container at `path` and rewrites its file header,
+    /// and its trailer if it has one, in place. Returns `false` when the TOC doesn't
+    /// match the data section, in which case the file verifies again but its blobs
+    /// are still misaligned.
+    pub fn repair(path: &Path) -> io::Result<bool> {
+        let mut container = Self::from_file_unchecked(File::open(path)?)?;
+        container.seal();
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.write_all(container.file_header.as_bytes().as_slice())?;
+        file.write_all(container.data_header.as_bytes().as_slice())?;
+        if container.data_header.has_trailer() {
+            let data_end = HEADER_SIZE
+                + toc_bytes(&container.toc, container.data_header.version).len() as u64
+                + container.data.len() as u64;
+            let trailer = Trailer {
+                total: data_end + TRAILER_SIZE,
+                checksum: container.file_header.checksum,
+            };
+            file.seek(SeekFrom::Start(data_end))?;
+            file.write_all(trailer.as_bytes().as_slice())?;
+            file.set_len(trailer.total)?;
         }
-        file.read_to_end(&mut container.data)?;
-        if container.checksum() != container.file_header.checksum {
-            return Err(io::Error::from(ErrorKind::InvalidData));
+        file.sync_all()?;
+        Ok(container.sizes_match())
+    }
+}
+
+impl ContainerWriter {
+    pub fn create(
+        type_id: u32,
+        checksum_algo: ChecksumAlgo,
+        spool_path: PathBuf,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            type_id,
+            checksum_algo,
+            checksum_scope: ChecksumScope::default(),
+            toc: Vec::new(),
+            spool: BufWriter::new(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&spool_path)?,
+            ),
+            spool_path,
+            data_size: 0,
+            dedup: None,
+            streamed: None,
+            created: now_micros(),
+            permissions: FilePermissions::default(),
+            journal: None,
+            block_threshold: None,
+            timings: FinishTimings::default(),
+        })
+    }
+
+    /// Reopens a spool file left behind by a writer with `enable_journal`, to finish
+    /// its container. The blobs whose TOC entry made it into the journal are kept,
+    /// data written after them, e.g. a streamed blob cut off by the crash, is dropped.
+    /// The journal is removed with the spool file by `discard`.
+    pub fn resume(
+        type_id: u32,
+        checksum_algo: ChecksumAlgo,
+        spool_path: PathBuf,
+    ) -> io::Result<Self> {
+        let journal_path = journal_path(&spool_path);
+        let mut reader = BufReader::new(File::open(&journal_path)?);
+        let created = reader.read_u64::<LittleEndian>()?;
+        let mut spool = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&spool_path)?;
+        let spool_size = spool.metadata()?.len();
+        let mut toc = Vec::new();
+        // an entry torn by the crash ends the journal
+        while let Ok((mut toc_entry, meta_size)) = TocEntry::read_from(&mut reader, VERSION, 0) {
+            (&mut reader)
+                .take(meta_size as u64)
+                .read_to_end(&mut toc_entry.meta)?;
+            if toc_entry.meta.len() < meta_size as usize || toc_entry.data_end() > spool_size {
+                break;
+            }
+            toc.push(toc_entry);
         }
-        Ok(container)
+        let data_size = toc.iter().map(TocEntry::data_end).max().unwrap_or(0);
+        spool.set_len(data_size)?;
+        spool.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            type_id,
+            checksum_algo,
+            checksum_scope: ChecksumScope::default(),
+            toc,
+            spool: BufWriter::new(spool),
+            spool_path,
+            data_size,
+            dedup: None,
+            streamed: None,
+            created,
+            permissions: FilePermissions::default(),
+            journal: Some(OpenOptions::new().append(true).open(&journal_path)?),
+            block_threshold: None,
+            timings: FinishTimings::default(),
+        })
+    }
+
+    /// Records the TOC entry of each blob in a journal next to the spool file as the
+    /// blob is added, so `resume` can finish the container after a crash of the
+    /// process. Call it before pushing any blob.
+    pub fn enable_journal(&mut self) -> io::Result<()> {
+        let mut journal = self.permissions.create(&journal_path(&self.spool_path))?;
+        journal.write_all(&self.created.to_le_bytes())?;
+        self.journal = Some(journal);
+        Ok(())
+    }
+
+    /// Appends the entry of the blob added last to the journal, if there is one.
+    fn journal_added(&mut self) -> io::Result<()> {
+        let (journal, toc_entry) = match (self.journal.as_mut(), self.toc.last()) {
+            (Some(journal), Some(toc_entry)) => (journal, toc_entry),
+            _ => return Ok(()),
+        };
+        // the entry mustn't point at data still buffered
+        self.spool.flush()?;
+        let mut record = toc_entry.as_bytes();
+        record.extend_from_slice(&toc_entry.meta);
+        journal.write_all(&record)
+    }
+
+    /// Microseconds since the Unix epoch when the container was created.
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Sets the permissions of the container files written from then on, and of the
+    /// spool file, which holds no data yet when this is called right after `create`.
+    pub fn set_file_permissions(&mut self, permissions: FilePermissions) -> io::Result<()> {
+        permissions.apply(self.spool.get_ref())?;
+        self.permissions = permissions;
+        Ok(())
+    }
+
+    /// Makes `push` store the data of identical blobs only once.
+    pub fn enable_dedup(&mut self) {
+        self.dedup = Some(DedupIndex::default());
+    }
+
+    /// Compresses the data when the container is written: blobs smaller than
+    /// `block_threshold` bytes are packed into shared `Codec::DeflateBlock` blocks,
+    /// larger ones are compressed on their own. With a `block_threshold` of 0 every
+    /// blob is compressed on its own. Data that doesn't shrink is stored as it is.
+    /// The spool keeps the data as pushed.
+    pub fn enable_compression(&mut self, block_threshold: u32) {
+        self.block_threshold = Some(block_threshold);
+    }
+
+    /// Selects what the checksum covers, `ChecksumScope::Full` by default. Applies to
+    /// the files written from then on, appending rewrites the file with this scope.
+    pub fn set_checksum_scope(&mut self, scope: ChecksumScope) {
+        self.checksum_scope = scope;
+    }
+
+    /// Makes room in the TOC for `objects` more blobs; their data goes to the spool
+    /// file.
+    pub fn reserve(&mut self, objects: usize) {
+        self.toc.reserve_exact(objects);
+    }
+
+    /// Bytes of data that didn't need to be stored because an identical blob was
+    /// already in the container, `None` if deduplication is off.
+    pub fn dedup_saved_bytes(&self) -> Option<u64> {
+        self.dedup.as_ref().map(|dedup| dedup.saved_bytes)
+    }
+
+    pub fn spool_path(&self) -> &Path {
+        &self.spool_path
+    }
+
+    /// Bytes of data actually stored.
+    pub fn data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.toc.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.toc.is_empty()
+    }
+
+    /// The writer_id and sequence number of each blob, in TOC order.
+    pub fn sequences(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.toc
+            .iter()
+            .map(|toc_entry| (toc_entry.writer_id, toc_entry.sequence))
+    }
+
+    pub fn push(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let timestamp = now_micros();
+        self.push_with_timestamp(writer_id, sequence, timestamp, content_type, meta, data)
+    }
+
+    /// Like `push`, for a blob received earlier, e.g. one recovered after a crash, or
+    /// one carrying the time of its event.
+    pub fn push_with_timestamp(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        timestamp: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let meta = meta::encode(meta)?;
+        let duplicate = self
+            .dedup
+            .as_mut()
+            .and_then(|dedup| dedup.find_or_insert(data, self.data_size));
+        let data_offset = match duplicate {
+            Some(data_offset) => data_offset,
+            None => {
+                self.spool.write_all(data)?;
+                self.data_size += data.len() as u64;
+                self.data_size - data.len() as u64
+            }
+        };
+        self.toc.push(
+            TocEntry::new_with_timestamp(
+                writer_id,
+                sequence,
+                data_offset,
+                data.len() as u32,
+                timestamp,
+                content_type,
+                meta,
+            )
+            .with_data_crc(data_crc(data)),
+        );
+        self.journal_added()
+    }
+
+    /// Writes the next chunk of a blob whose size isn't known up front. `end_blob`
+    /// adds the blob once all chunks are written, `abort_blob` drops them.
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let dedup = self.dedup.is_some();
+        let streamed = self.streamed.get_or_insert_with(|| StreamedBlob {
+            hasher: dedup.then(Sha256::new),
+            ..StreamedBlob::default()
+        });
+        streamed.crc.update(chunk);
+        if let Some(hasher) = streamed.hasher.as_mut() {
+            hasher.update(chunk);
+        }
+        self.spool.write_all(chunk)?;
+        streamed.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Adds the blob written by `write_chunk`, an empty one if no chunk was written.
+    pub fn end_blob(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+    ) -> io::Result<()> {
+        let timestamp = now_micros();
+        self.end_blob_with_timestamp(writer_id, sequence, timestamp, content_type, meta)
+    }
+
+    /// Like `end_blob`, stamping the blob with `timestamp`, see `push_with_timestamp`.
+    pub fn end_blob_with_timestamp(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        timestamp: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+    ) -> io::Result<()> {
+        let streamed = self.streamed.take().unwrap_or_default();
+        let (meta, data_size) = match (meta::encode(meta), u32::try_from(streamed.size)) {
+            (Ok(meta), Ok(data_size)) => (meta, data_size),
+            (Err(err), _) => {
+                self.truncate_spool()?;
+                return Err(err);
+            }
+            (_, Err(_)) => {
+                self.truncate_spool()?;
+                return Err(io::Error::new(ErrorKind::InvalidInput, "blob too large"));
+            }
+        };
+        let duplicate = match (self.dedup.as_mut(), streamed.hasher) {
+            (Some(dedup), Some(hasher)) => {
+                dedup.find_or_insert_hash(hasher.finalize().into(), streamed.size, self.data_size)
+            }
+            _ => None,
+        };
+        let data_offset = match duplicate {
+            Some(data_offset) => {
+                self.truncate_spool()?;
+                data_offset
+            }
+            None => {
+                self.data_size += streamed.size;
+                self.data_size - streamed.size
+            }
+        };
+        self.toc.push(
+            TocEntry::new_with_timestamp(
+                writer_id,
+                sequence,
+                data_offset,
+                data_size,
+                timestamp,
+                content_type,
+                meta,
+            )
+            .with_data_crc(streamed.crc.finalize()),
+        );
+        self.journal_added()
+    }
+
+    /// Drops the chunks written since the last blob was added.
+    pub fn abort_blob(&mut self) -> io::Result<()> {
+        self.streamed = None;
+        self.truncate_spool()
+    }
+
+    /// Cuts the spool back to the data of the blobs added so far.
+    fn truncate_spool(&mut self) -> io::Result<()> {
+        self.spool.flush()?;
+        self.spool.get_ref().set_len(self.data_size)?;
+        self.spool.seek(SeekFrom::Start(self.data_size))?;
+        Ok(())
+    }
+
+    /// With compression on, writes the data section compressed to a file next to the
+    /// spool and returns that file with the TOC pointing into it, otherwise `None`.
+    /// Blobs sharing data through deduplication keep sharing it.
+    fn compress(&mut self) -> io::Result<Option<(Vec<TocEntry>, File)>> {
+        let block_threshold = match self.block_threshold {
+            None => return Ok(None),
+            Some(block_threshold) => block_threshold,
+        };
+        let mut spool = rewind(&mut self.spool)?;
+        let mut out = BufWriter::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(compressed_path(&self.spool_path))?,
+        );
+        let mut toc = self.toc.clone();
+        let (mut block, mut members) = (Vec::new(), Vec::new());
+        let mut first_of: HashMap<(u64, u32), usize> = HashMap::new();
+        let mut duplicates = Vec::new();
+        let (mut written, mut position) = (0, 0);
+        for index in 0..toc.len() {
+            let region = (toc[index].data_offset, toc[index].data_size);
+            if let Some(first) = first_of.get(&region) {
+                duplicates.push((index, *first));
+                continue;
+            }
+            first_of.insert(region, index);
+            let mut data = vec![0; region.1 as usize];
+            if region.0 != position {
+                spool.seek(SeekFrom::Start(region.0))?;
+            }
+            spool.read_exact(&mut data)?;
+            position = region.0 + region.1 as u64;
+            if region.1 >= block_threshold {
+                written += pack(&mut out, written, &data, Codec::Deflate, &[index], &mut toc)?;
+                continue;
+            }
+            toc[index].raw_offset = block.len() as u32;
+            block.extend_from_slice(&data);
+            members.push(index);
+            if block.len() >= BLOCK_SIZE {
+                let codec = Codec::DeflateBlock;
+                written += pack(&mut out, written, &block, codec, &members, &mut toc)?;
+                (block, members) = (Vec::new(), Vec::new());
+            }
+        }
+        if !members.is_empty() {
+            pack(
+                &mut out,
+                written,
+                &block,
+                Codec::DeflateBlock,
+                &members,
+                &mut toc,
+            )?;
+        }
+        for (index, first) in duplicates {
+            let first = toc[first].clone();
+            let toc_entry = &mut toc[index];
+            toc_entry.data_offset = first.data_offset;
+            toc_entry.data_size = first.data_size;
+            toc_entry.data_crc = first.data_crc;
+            toc_entry.codec = first.codec;
+            toc_entry.raw_offset = first.raw_offset;
+        }
+        let mut file = out.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Some((toc, file)))
+    }
+
+    /// Writes the container to `path` through a temporary file renamed over it, so
+    /// `path` only ever holds a complete container and a file already there is
+    /// replaced rather than truncated under its readers. The spool file is kept so a
+    /// failed write can be retried, `discard` removes it once the container is stored.
+    pub fn finish(&mut self, path: &Path) -> io::Result<u64> {
+        let started = Instant::now();
+        let compressed = self.compress()?;
+        let (toc, mut data): (&[TocEntry], Box<dyn Read + '_>) = match compressed {
+            Some((ref toc, file)) => (toc, Box::new(BufReader::new(file))),
+            None => (&self.toc, Box::new(rewind(&mut self.spool)?)),
+        };
+        let tmp_path = path.with_extension("blob.tmp");
+        let serialized = Instant::now();
+        let size = write_container(
+            self.permissions.create(&tmp_path)?,
+            self.type_id,
+            self.checksum_algo,
+            self.checksum_scope,
+            self.created,
+            toc,
+            |out, mut hasher| {
+                copy_with(&mut data, out, |buf| {
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(buf)
+                    }
+                })
+            },
+        );
+        drop(data);
+        self.spool.get_mut().seek(SeekFrom::End(0))?;
+        let size = match size {
+            Ok(size) => size,
+            Err(err) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(err);
+            }
+        };
+        let written = Instant::now();
+        if let Err(err) = std::fs::rename(&tmp_path, path) {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        self.timings = FinishTimings {
+            serialize: serialized - started,
+            write: written - serialized,
+            rename: written.elapsed(),
+        };
+        Ok(size)
+    }
+
+    /// How long the last `finish` or `append_to` took, by phase.
+    pub fn timings(&self) -> FinishTimings {
+        self.timings
+    }
+
+    /// Appends the blobs to the container stored at `path`, creating it if it doesn't
+    /// exist yet. The existing data is streamed into a temporary file together with
+    /// the new data and the result is renamed over the original, so every append
+    /// costs a full copy of the existing file. Returns the size of the result and the
+    /// index its first appended blob got.
+    pub fn append_to(&mut self, path: &Path) -> io::Result<(u64, usize)> {
+        if !path.exists() {
+            return Ok((self.finish(path)?, 0));
+        }
+        let started = Instant::now();
+        let compressed = self.compress()?;
+        let (toc, mut data): (&[TocEntry], Box<dyn Read + '_>) = match compressed {
+            Some((ref toc, file)) => (toc, Box::new(BufReader::new(file))),
+            None => (&self.toc, Box::new(rewind(&mut self.spool)?)),
+        };
+        let mut existing = BufReader::new(File::open(path)?);
+        let (file_header, data_header, mut entries) = read_header(&mut existing)?;
+        if data_header.type_id != self.type_id {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type_id mismatch: file has {}, container has {}",
+                    data_header.type_id, self.type_id
+                ),
+            ));
+        }
+        let stored_checksum = Checksum {
+            short: file_header.checksum,
+            words: data_header.digest_words(),
+        };
+        let mut existing_hasher = data_header.checksum_algo().hasher();
+        existing_hasher.update(data_header.hashed_bytes().as_slice());
+        let existing_toc = toc_bytes(&entries, data_header.version);
+        existing_hasher.update(existing_toc.as_slice());
+        // without the data in the checksum the existing blobs are copied unchecked,
+        // their CRCs going along
+        let existing_full = data_header.checksum_scope() == ChecksumScope::Full;
+        if !existing_full && existing_hasher.clone().finalize() != stored_checksum {
+            return Err(BlobError::ChecksumMismatch {
+                expected: stored_checksum,
+                got: existing_hasher.finalize(),
+            }
+            .into());
+        }
+        let first_index = entries.len();
+        let existing_data_size = data_size(&mut existing, &file_header, &data_header)?;
+        if !data_header.has_data_crcs() {
+            fill_data_crcs(&mut existing, &mut entries)?;
+        }
+        entries.extend(toc.iter().map(|toc_entry| {
+            let mut toc_entry = toc_entry.clone();
+            toc_entry.data_offset += existing_data_size;
+            toc_entry
+        }));
+
+        let tmp_path = path.with_extension("blob.tmp");
+        let serialized = Instant::now();
+        let size = write_container(
+            self.permissions.create(&tmp_path)?,
+            self.type_id,
+            self.checksum_algo,
+            self.checksum_scope,
+            data_header.created().unwrap_or_default(),
+            &entries,
+            |out, mut hasher| {
+                let mut existing = (&mut existing).take(existing_data_size);
+                let existing_size = copy_with(&mut existing, out, |buf| {
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(buf);
+                    }
+                    if existing_full {
+                        existing_hasher.update(buf);
+                    }
+                })?;
+                let existing_checksum = existing_hasher.clone().finalize();
+                if existing_full && existing_checksum != stored_checksum {
+                    return Err(BlobError::ChecksumMismatch {
+                        expected: stored_checksum,
+                        got: existing_checksum,
+                    }
+                    .into());
+                }
+                let new_size = copy_with(&mut data, out, |buf| {
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(buf);
+                    }
+                })?;
+                Ok(existing_size + new_size)
+            },
+        );
+        drop(data);
+        self.spool.get_mut().seek(SeekFrom::End(0))?;
+        let size = match size {
+            Ok(size) => size,
+            Err(err) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(err);
+            }
+        };
+        let written = Instant::now();
+        std::fs::rename(tmp_path, path)?;
+        self.timings = FinishTimings {
+            serialize: serialized - started,
+            write: written - serialized,
+            rename: written.elapsed(),
+        };
+        Ok((size, first_index))
+    }
+
+    /// Removes the spool file and its journal, dropping the pushed blobs unless they
+    /// were stored.
+    pub fn discard(self) -> io::Result<()> {
+        drop(self.spool);
+        if self.journal.is_some() {
+            std::fs::remove_file(journal_path(&self.spool_path))?;
+        }
+        if self.block_threshold.is_some() {
+            match std::fs::remove_file(compressed_path(&self.spool_path)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        std::fs::remove_file(self.spool_path)
+    }
+}
+
+impl<R: Read + Seek> ContainerReader<R> {
+    /// Reads the headers and the TOC; the blobs are read as the reader is iterated.
+    /// Fails with `BlobError::Truncated` if the trailer is missing or doesn't match
+    /// the length of the file, found by seeking to the end.
+    pub fn new(reader: R) -> Result<Self, BlobError> {
+        let mut reader = BufReader::new(reader);
+        let (file_header, data_header, toc) = read_header(&mut reader)?;
+        let data_start = reader.stream_position()?;
+        let data_size = data_size(&mut reader, &file_header, &data_header)?;
+        let mut hasher = data_header.checksum_algo().hasher();
+        hasher.update(data_header.hashed_bytes().as_slice());
+        hasher.update(toc_bytes(&toc, data_header.version).as_slice());
+        Ok(Self {
+            reader,
+            file_header,
+            data_header,
+            toc: toc.into_iter(),
+            hasher,
+            data_start,
+            data_size,
+            hashed: 0,
+        })
+    }
+
+    pub fn type_id(&self) -> u32 {
+        self.data_header.type_id
+    }
+
+    /// When the container was created, `None` for files written before it was
+    /// recorded.
+    pub fn created(&self) -> Option<u64> {
+        self.data_header.created()
+    }
+
+    /// Blobs not read yet.
+    pub fn remaining(&self) -> usize {
+        self.toc.len()
+    }
+
+    /// Hashes the rest of the data section, including the blobs not read, and checks
+    /// the checksum like `from_file`. When the checksum leaves the data out, the blobs
+    /// not read are checked against their CRCs instead.
+    pub fn finish(mut self) -> Result<(), BlobError> {
+        if self.data_header.checksum_scope() == ChecksumScope::Full {
+            let rest = self.data_size.saturating_sub(self.hashed);
+            copy_with(&mut (&mut self.reader).take(rest), &mut io::sink(), |buf| {
+                self.hasher.update(buf)
+            })?;
+        } else {
+            for blob in self.by_ref() {
+                blob?;
+            }
+        }
+        let stored = Checksum {
+            short: self.file_header.checksum,
+            words: self.data_header.digest_words(),
+        };
+        let computed = self.hasher.finalize();
+        if stored != computed {
+            return Err(BlobError::ChecksumMismatch {
+                expected: stored,
+                got: computed,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads the blob at `index`, failing with `BlobError::BadBlobs` if it doesn't
+    /// match its CRC.
+    fn read_blob(&mut self, index: usize, entry: TocEntry) -> io::Result<OwnedBlob> {
+        let mut data = vec![0; entry.data_size as usize];
+        let full = self.data_header.checksum_scope() == ChecksumScope::Full;
+        if entry.data_offset >= self.hashed {
+            // data is read in order; bytes skipped by the TOC are still hashed
+            let gap = entry.data_offset - self.hashed;
+            let skipped = copy_with(&mut (&mut self.reader).take(gap), &mut io::sink(), |buf| {
+                if full {
+                    self.hasher.update(buf)
+                }
+            })?;
+            if skipped < gap {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof));
+            }
+            self.reader.read_exact(&mut data)?;
+            if full {
+                self.hasher.update(&data);
+            }
+            self.hashed = entry.data_end();
+        } else {
+            // a deduplicated blob sharing data read before
+            self.reader
+                .seek(SeekFrom::Start(self.data_start + entry.data_offset))?;
+            self.reader.read_exact(&mut data)?;
+            self.reader
+                .seek(SeekFrom::Start(self.data_start + self.hashed))?;
+        }
+        if self.data_header.has_data_crcs() && data_crc(&data) != entry.data_crc {
+            return Err(BlobError::BadBlobs(vec![BadBlob {
+                index,
+                writer_id: entry.writer_id,
+                sequence: entry.sequence,
+                problem: BlobProblem::BadData,
+            }])
+            .into());
+        }
+        if entry.codec != Codec::None {
+            data = entry.decode(&data)?.into_owned();
+        }
+        Ok(OwnedBlob {
+            writer_id: entry.writer_id,
+            sequence: entry.sequence,
+            timestamp: entry.timestamp,
+            content_type: entry.content_type,
+            meta: entry.meta,
+            data,
+        })
+    }
+}
+
+impl<R: Read + Seek> Iterator for ContainerReader<R> {
+    type Item = io::Result<OwnedBlob>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.toc.next()?;
+        let index = self.data_header.toc_size as usize - self.toc.len() - 1;
+        Some(self.read_blob(index, entry))
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl MappedContainer {
+    /// Maps the container at `path` and reads its headers and TOC. Fails with
+    /// `BlobError::Truncated` if the trailer is missing or a TOC entry points past
+    /// the end of the data section. The checksum isn't verified, see
+    /// `verify_checksum`.
+    ///
+    /// The file mustn't be modified while it is mapped. The writer, appends and
+    /// compaction only ever replace containers by renaming a new file over them,
+    /// which leaves the mapping intact; `Container::repair` rewrites a file in place
+    /// and mustn't be run on files that are being read.
+    pub fn open(path: &Path) -> Result<Self, BlobError> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only, and the server replaces container files by
+        // renaming over them instead of writing them in place, see above
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let mut header = &map[..];
+        let (file_header, data_header, toc) = read_header(&mut header)?;
+        let data_start = map.len() - header.len();
+        let mut reader = Cursor::new(&map[..]);
+        reader.set_position(data_start as u64);
+        let data_bytes = data_size(&mut reader, &file_header, &data_header)?;
+        if toc.iter().any(|entry| entry.data_end() > data_bytes) {
+            return Err(BlobError::Truncated);
+        }
+        Ok(Self {
+            file_header,
+            data_header,
+            toc,
+            data_start,
+            data_end: data_start + data_bytes as usize,
+            map,
+        })
+    }
+
+    pub fn type_id(&self) -> u32 {
+        self.data_header.type_id
+    }
+
+    /// When the container was created, `None` for files written before it was
+    /// recorded.
+    pub fn created(&self) -> Option<u64> {
+        self.data_header.created()
+    }
+
+    pub fn len(&self) -> usize {
+        self.toc.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.toc.is_empty()
+    }
+
+    /// The blob at `index` in the TOC, its data borrowed from the mapping unless it
+    /// is compressed; `None` if it doesn't decompress.
+    pub fn get(&self, index: usize) -> Option<Blob<'_>> {
+        self.toc.get(index).and_then(|entry| self.blob(entry))
+    }
+
+    /// Iterates over the blobs in TOC order, stopping at one that doesn't decompress.
+    pub fn blobs(&self) -> impl Iterator<Item = Blob<'_>> {
+        self.toc.iter().map_while(move |entry| self.blob(entry))
+    }
+
+    /// Hashes the whole file and checks the checksum like `from_file`, or the blobs
+    /// against their CRCs when the checksum leaves the data out.
+    pub fn verify_checksum(&self) -> Result<(), VerifyError> {
+        let full = self.data_header.checksum_scope() == ChecksumScope::Full;
+        let mut hasher = self.data_header.checksum_algo().hasher();
+        hasher.update(self.data_header.hashed_bytes().as_slice());
+        hasher.update(toc_bytes(&self.toc, self.data_header.version).as_slice());
+        if full {
+            hasher.update(self.data());
+        }
+        let stored = Checksum {
+            short: self.file_header.checksum,
+            words: self.data_header.digest_words(),
+        };
+        let computed = hasher.finalize();
+        if stored != computed {
+            return Err(VerifyError::ChecksumMismatch { stored, computed });
+        }
+        if !full {
+            check_data_crcs(&self.toc, self.data())?;
+        }
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.map[self.data_start..self.data_end]
+    }
+
+    /// The blob of a TOC entry; `open` checked that its data is mapped.
+    fn blob<'a>(&'a self, entry: &'a TocEntry) -> Option<Blob<'a>> {
+        let stored = &self.data()[entry.data_offset as usize..entry.data_end() as usize];
+        Some(Blob {
+            writer_id: entry.writer_id,
+            sequence: entry.sequence,
+            timestamp: entry.timestamp,
+            content_type: entry.content_type,
+            meta: entry.meta.as_slice(),
+            data: entry.decode(stored).ok()?,
+        })
+    }
+}
+
+/// The smallest and largest timestamp of the entries, in one pass.
+fn timestamp_range(toc: &[TocEntry]) -> Option<(u64, u64)> {
+    toc.iter().fold(None, |range, entry| {
+        let timestamp = entry.timestamp;
+        Some(match range {
+            None => (timestamp, timestamp),
+            Some((min, max)) => (timestamp.min(min), timestamp.max(max)),
+        })
+    })
+}
+
+/// The TOC journal of the spool file at `spool_path`: the creation time of the
+/// container, then the entry of each blob added, followed by its metadata.
+pub fn journal_path(spool_path: &Path) -> PathBuf {
+    spool_path.with_extension("spool.toc")
+}
+
+/// The compressed data section `ContainerWriter::compress` writes next to the spool
+/// file at `spool_path`.
+fn compressed_path(spool_path: &Path) -> PathBuf {
+    spool_path.with_extension("spool.z")
+}
+
+/// Writes the data of the TOC entries at `members`, `raw` holding it back to back
+/// as their `raw_offset`s say, deflated to `out` at `offset` of the data section,
+/// or as it is if that doesn't make it smaller. Points the entries at what was
+/// written and returns its size.
+fn pack<W: Write>(
+    out: &mut W,
+    offset: u64,
+    raw: &[u8],
+    codec: Codec,
+    members: &[usize],
+    toc: &mut [TocEntry],
+) -> io::Result<u64> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(raw)?;
+    let deflated = encoder.finish()?;
+    if deflated.len() >= raw.len() {
+        out.write_all(raw)?;
+        for &index in members {
+            let toc_entry = &mut toc[index];
+            toc_entry.data_offset = offset + toc_entry.raw_offset as u64;
+            toc_entry.raw_offset = 0;
+        }
+        return Ok(raw.len() as u64);
+    }
+    out.write_all(&deflated)?;
+    let crc = data_crc(&deflated);
+    for &index in members {
+        let toc_entry = &mut toc[index];
+        toc_entry.data_offset = offset;
+        toc_entry.data_size = deflated.len() as u32;
+        toc_entry.data_crc = crc;
+        toc_entry.codec = codec;
+    }
+    Ok(deflated.len() as u64)
+}
+
+/// Reads the spooled data from the start; the caller seeks back to the end.
+fn rewind(spool: &mut BufWriter<File>) -> io::Result<BufReader<&mut File>> {
+    spool.flush()?;
+    let spool = spool.get_mut();
+    spool.seek(SeekFrom::Start(0))?;
+    Ok(BufReader::new(spool))
+}
+
+fn read_header<R: Read>(
+    reader: &mut R,
+) -> Result<(FileHeader, DataHeader, Vec<TocEntry>), BlobError> {
+    let magic = reader.read_u32::<LittleEndian>()?;
+    if magic != MAGIC {
+        return Err(BlobError::BadMagic);
+    }
+    let checksum = reader.read_u32::<LittleEndian>()?;
+    let version: u32 = reader.read_u32::<LittleEndian>()?;
+    if version > VERSION {
+        return Err(BlobError::VersionUnsupported { got: version });
+    }
+    let type_id: u32 = reader.read_u32::<LittleEndian>()?;
+    let toc_size: u32 = reader.read_u32::<LittleEndian>()?;
+    let mut reserved = [0u32; 11];
+    reader.read_u32_into::<LittleEndian>(&mut reserved)?;
+    let algo = reserved[RESERVED_CHECKSUM_ALGO] & !HEADERS_SCOPE;
+    if ChecksumAlgo::from_u32(algo).is_err() {
+        return Err(BlobError::UnknownChecksumAlgo { got: algo });
+    }
+    let mut toc = Vec::new();
+    let mut meta_sizes = Vec::new();
+    let mut next_offset = 0;
+    for _ in 0..toc_size {
+        let (toc_entry, meta_size) = TocEntry::read_from(reader, version, next_offset)?;
+        next_offset = toc_entry.data_end();
+        toc.push(toc_entry);
+        meta_sizes.push(meta_size);
+    }
+    for (toc_entry, meta_size) in toc.iter_mut().zip(meta_sizes) {
+        reader
+            .take(meta_size as u64)
+            .read_to_end(&mut toc_entry.meta)?;
+        if toc_entry.meta.len() != meta_size as usize {
+            return Err(BlobError::Truncated);
+        }
+    }
+    Ok((
+        FileHeader::new(checksum),
+        DataHeader::new(version, type_id, toc_size, reserved),
+        toc,
+    ))
+}
+
+/// Writes the headers and the TOC, lets `write_data` stream the data section while
+/// feeding it to the checksum, which it only gets when the checksum covers the data,
+/// appends the trailer, then seeks back to store the final checksum.
+fn write_container<F>(
+    file: File,
+    type_id: u32,
+    checksum_algo: ChecksumAlgo,
+    checksum_scope: ChecksumScope,
+    created: u64,
+    toc: &[TocEntry],
+    write_data: F,
+) -> io::Result<u64>
+where
+    F: FnOnce(&mut BufWriter<File>, Option<&mut ChecksumHasher>) -> io::Result<u64>,
+{
+    let mut out = BufWriter::new(file);
+    let mut hasher = checksum_algo.hasher();
+    let file_header = FileHeader::new(0).as_bytes();
+    out.write_all(file_header.as_slice())?;
+    let mut data_header = DataHeader::new(
+        VERSION,
+        type_id,
+        toc.len() as u32,
+        reserved_for(checksum_algo, checksum_scope, created),
+    );
+    let data_header_bytes = data_header.as_bytes();
+    hasher.update(data_header_bytes.as_slice());
+    out.write_all(data_header_bytes.as_slice())?;
+    let toc = toc_bytes(toc, VERSION);
+    hasher.update(toc.as_slice());
+    out.write_all(toc.as_slice())?;
+    let mut size = (file_header.len() + data_header_bytes.len() + toc.len()) as u64;
+    let data_hasher = (checksum_scope == ChecksumScope::Full).then_some(&mut hasher);
+    size += write_data(&mut out, data_hasher)?;
+    let checksum = hasher.finalize();
+    let trailer = Trailer {
+        total: size + TRAILER_SIZE,
+        checksum: checksum.short,
+    };
+    out.write_all(trailer.as_bytes().as_slice())?;
+    size = trailer.total;
+    data_header.set_digest_words(checksum.words);
+    let mut file = out.into_inner().map_err(|err| err.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(FileHeader::new(checksum.short).as_bytes().as_slice())?;
+    file.write_all(data_header.as_bytes().as_slice())?;
+    Ok(size)
+}
+
+/// The TOC entries followed by the metadata section, in the layout of `version`.
+fn toc_bytes(toc: &[TocEntry], version: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for toc_entry in toc {
+        buf.extend_from_slice(toc_entry.as_bytes_for(version).as_slice());
+    }
+    if version >= 3 {
+        for toc_entry in toc {
+            buf.extend_from_slice(toc_entry.meta.as_slice());
+        }
+    }
+    buf
+}
+
+/// CRC-32 of a blob's data as stored in its TOC entry.
+fn data_crc(data: &[u8]) -> u32 {
+    let mut crc = DefaultCrc32::new();
+    crc.update(data);
+    crc.finalize()
+}
+
+/// The size of the data section, which `reader` is positioned at the start of and
+/// returned to. Fails with `BlobError::Truncated` if the file should have a trailer
+/// but it is missing or doesn't match the file, which only reads the trailer.
+fn data_size<R: Read + Seek>(
+    reader: &mut R,
+    file_header: &FileHeader,
+    data_header: &DataHeader,
+) -> Result<u64, BlobError> {
+    let data_start = reader.stream_position()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    let data_size = (len - data_start)
+        .checked_sub(data_header.trailer_size())
+        .ok_or(BlobError::Truncated)?;
+    if data_header.has_trailer() {
+        reader.seek(SeekFrom::Start(data_start + data_size))?;
+        if Trailer::read_from(reader, len)? != Some(file_header.checksum) {
+            return Err(BlobError::Truncated);
+        }
+    }
+    reader.seek(SeekFrom::Start(data_start))?;
+    Ok(data_size)
+}
+
+/// Checks the data of each entry against its CRC, for checksums leaving the data out.
+fn check_data_crcs(toc: &[TocEntry], data: &[u8]) -> Result<(), VerifyError> {
+    let bad: Vec<BadBlob> = toc
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.data_intact(data))
+        .map(|(index, entry)| BadBlob {
+            index,
+            writer_id: entry.writer_id,
+            sequence: entry.sequence,
+            problem: if entry.data_end() > data.len() as u64 {
+                BlobProblem::MissingData
+            } else {
+                BlobProblem::BadData
+            },
+        })
+        .collect();
+    if !bad.is_empty() {
+        return Err(VerifyError::BadBlobs(bad));
+    }
+    Ok(())
+}
+
+/// Computes the CRCs of the entries of a file older than version 6 from the data
+/// section, which `reader` is positioned at the start of and returned to.
+fn fill_data_crcs<R: Read + Seek>(reader: &mut R, toc: &mut [TocEntry]) -> io::Result<()> {
+    let data_start = reader.stream_position()?;
+    for toc_entry in toc.iter_mut() {
+        reader.seek(SeekFrom::Start(data_start + toc_entry.data_offset))?;
+        let mut crc = DefaultCrc32::new();
+        let size = toc_entry.data_size as u64;
+        let read = copy_with(&mut reader.take(size), &mut io::sink(), |buf| {
+            crc.update(buf)
+        })?;
+        if read < size {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        toc_entry.data_crc = crc.finalize();
+    }
+    reader.seek(SeekFrom::Start(data_start))?;
+    Ok(())
+}
+
+fn copy_with<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    mut inspect: impl FnMut(&[u8]),
+) -> io::Result<u64> {
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        inspect(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
     }
 }
 
+/// Current time in microseconds since the Unix epoch, the unit used for TOC entry
+/// timestamps and container file names.
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
 fn as_u8_slice<T>(v: &[T]) -> &[u8] {
-    unsafe {
-        std::slice::from_raw_parts(v.as_ptr() as *const u8, v.len() * std::mem::size_of::<T>())
+    unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, std::mem::size_of_val(v)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    /// A container holding the blobs `(writer_id, data)` in order, numbered by
+    /// writer from 1 and stamped 1000, 1001 and so on.
+    fn container_of(blobs: &[(u32, &[u8])]) -> Container {
+        let mut container = Container::new(1);
+        let mut sequences: HashMap<u32, u64> = HashMap::new();
+        for (index, (writer_id, data)) in blobs.iter().enumerate() {
+            let sequence = sequences.entry(*writer_id).or_default();
+            *sequence += 1;
+            container
+                .push_with_timestamp(
+                    *writer_id,
+                    *sequence,
+                    1000 + index as u64,
+                    ContentType::None,
+                    &BlobMeta::new(),
+                    data,
+                )
+                .unwrap();
+        }
+        container
+    }
+
+    fn contents(container: &Container) -> Vec<(u32, u64, u64, Vec<u8>)> {
+        container
+            .blobs()
+            .map(|blob| {
+                (
+                    blob.writer_id,
+                    blob.sequence,
+                    blob.timestamp,
+                    blob.data.to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn to_bytes_round_trips_through_a_cursor() {
+        let mut container = container_of(&[(0, b"first"), (3, b""), (0, b"third")]);
+        let bytes = container.to_bytes();
+        let read = Container::from_file(Cursor::new(&bytes)).unwrap();
+        assert_eq!(contents(&read), contents(&container));
+        assert_eq!(read.checksum(), container.checksum());
+        assert_eq!(read.created(), container.created());
+
+        let path = std::env::temp_dir().join(format!("blob_queue_to_bytes_{}", process::id()));
+        let saved = container.save_to_file(File::create(&path).unwrap());
+        let on_disk = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.unwrap(), bytes.len());
+        assert_eq!(on_disk, bytes);
+    }
+
+    /// Splits `blobs` by `max_objects`, checking the pieces hold the blobs in order
+    /// and each reads back on its own; returns the sizes of the pieces.
+    fn split_sizes(blobs: &[(u32, &[u8])], max_objects: usize) -> Vec<usize> {
+        let container = container_of(blobs);
+        let expected = contents(&container);
+        let mut read = Vec::new();
+        let mut sizes = Vec::new();
+        for mut piece in container.split(max_objects).unwrap() {
+            let copy = Container::from_bytes(&piece.to_bytes()).unwrap();
+            assert_eq!(contents(&copy), contents(&piece));
+            assert_eq!(copy.checksum(), piece.checksum());
+            sizes.push(copy.blobs().count());
+            read.extend(contents(&copy));
+        }
+        assert_eq!(read, expected);
+        sizes
+    }
+
+    #[test]
+    fn split_on_exact_multiples() {
+        let blobs: &[(u32, &[u8])] = &[
+            (1, b"a"),
+            (2, b"b"),
+            (1, b"c"),
+            (3, b"d"),
+            (2, b"e"),
+            (1, b"f"),
+        ];
+        assert_eq!(split_sizes(blobs, 3), [3, 3]);
+        assert_eq!(split_sizes(blobs, 1), [1; 6]);
+        assert_eq!(split_sizes(blobs, 6), [6]);
+    }
+
+    #[test]
+    fn split_with_remainders() {
+        let blobs: &[(u32, &[u8])] = &[
+            (1, b"a"),
+            (2, b"b"),
+            (1, b"c"),
+            (3, b"d"),
+            (2, b"e"),
+            (1, b"f"),
+            (3, b"g"),
+        ];
+        assert_eq!(split_sizes(blobs, 3), [3, 3, 1]);
+        assert_eq!(split_sizes(blobs, 4), [4, 3]);
+        assert_eq!(split_sizes(blobs, 10), [7]);
+        assert_eq!(split_sizes(&[], 3), Vec::<usize>::new());
+        assert!(container_of(blobs).split(0).is_err());
+    }
+
+    #[test]
+    fn iter_by_writer_keeps_the_order_of_each_writer() {
+        let mut container = container_of(&[
+            (7, b"first"),
+            (2, b"other"),
+            (7, b"second"),
+            (7, b""),
+            (3, b"more"),
+            (2, b"another"),
+            (7, b"last"),
+        ]);
+        let read = Container::from_bytes(&container.to_bytes()).unwrap();
+        for writer_id in [2, 3, 7, 9] {
+            let expected: Vec<_> = contents(&read)
+                .into_iter()
+                .filter(|blob| blob.0 == writer_id)
+                .collect();
+            let blobs: Vec<_> = read.iter_by_writer(writer_id).collect();
+            assert!(blobs
+                .iter()
+                .all(|blob| matches!(blob.data, Cow::Borrowed(_))));
+            let found: Vec<_> = blobs
+                .into_iter()
+                .map(|blob| {
+                    (
+                        blob.writer_id,
+                        blob.sequence,
+                        blob.timestamp,
+                        blob.data.to_vec(),
+                    )
+                })
+                .collect();
+            assert_eq!(found, expected);
+        }
+        let data: Vec<_> = read.iter_by_writer(7).map(|blob| blob.data).collect();
+        assert_eq!(data, [&b"first"[..], b"second", b"", b"last"]);
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let mut bytes = container_of(&[(0, b"first")]).to_bytes();
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+
+        let err = Container::from_bytes(&bytes).err().expect("newer version");
+        assert!(
+            matches!(err, BlobError::VersionUnsupported { got } if got == VERSION + 1),
+            "{:?}",
+            err
+        );
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(
+            err.to_string().contains(&(VERSION + 1).to_string()),
+            "{}",
+            err
+        );
+        assert!(matches!(
+            Container::from_file_unchecked(Cursor::new(&bytes)),
+            Err(BlobError::VersionUnsupported { .. })
+        ));
+
+        let path = std::env::temp_dir().join(format!("blob_queue_version_{}", process::id()));
+        fs::write(&path, &bytes).unwrap();
+        let peeked = Container::peek_type_id(&path);
+        let summarized = Container::summarize(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(peeked, Err(BlobError::VersionUnsupported { .. })));
+        assert!(matches!(
+            summarized,
+            Err(BlobError::VersionUnsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn failed_finish_leaves_nothing_at_the_final_path() {
+        let dir = std::env::temp_dir().join(format!("blob_queue_finish_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (path, tmp_path) = (dir.join("type1_1.blob"), dir.join("type1_1.blob.tmp"));
+        let mut writer =
+            ContainerWriter::create(1, ChecksumAlgo::default(), dir.join("type1_1.blob.spool"))
+                .unwrap();
+        writer
+            .push(0, 1, ContentType::None, &BlobMeta::new(), b"first")
+            .unwrap();
+
+        // the temporary file can't be created
+        fs::create_dir(&tmp_path).unwrap();
+        assert!(writer.finish(&path).is_err());
+        assert!(!path.exists());
+        fs::remove_dir(&tmp_path).unwrap();
+
+        // the container is written but can't be renamed into place
+        fs::create_dir_all(path.join("in the way")).unwrap();
+        assert!(writer.finish(&path).is_err());
+        assert!(path.is_dir() && !tmp_path.exists());
+        fs::remove_dir_all(&path).unwrap();
+
+        // a retry succeeds, replacing an existing file instead of truncating it
+        fs::write(&path, b"older container").unwrap();
+        let mut older = File::open(&path).unwrap();
+        writer.finish(&path).unwrap();
+        let mut read = Vec::new();
+        older.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"older container");
+        let container = Container::from_file(File::open(&path).unwrap()).unwrap();
+        assert_eq!(contents(&container)[0].3, b"first");
+        assert!(!tmp_path.exists());
+        writer.discard().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
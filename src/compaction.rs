@@ -0,0 +1,204 @@
+use crate::blob::storage::{now_micros, BlobError, Container, FilePermissions};
+use crate::config::{CompactionConfig, Rotation, TypeConfig};
+use crate::manifest;
+use crate::metrics::{CompactionMetrics, TypeLabels};
+use crate::paths;
+use crate::paths::{BlobFile, FilenameTemplate};
+use crate::sidecar;
+use prometheus_client::metrics::counter::Counter;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::task;
+use tokio::time::interval;
+use tracing::{info, info_span, warn, Instrument};
+
+const DEFAULT_INTERVAL_SECS: u64 = 300;
+/// Files modified more recently may still be written, or rewritten by a retried
+/// store, so they aren't merged yet.
+const SETTLE_TIME: Duration = Duration::from_secs(60);
+
+/// What a compaction run needs to know about the type, fixed when it is spawned.
+#[derive(Clone)]
+struct Target {
+    type_id: u32,
+    root: PathBuf,
+    template: FilenameTemplate,
+    /// microseconds after its timestamp during which a file may still be appended to
+    open_for: u64,
+    write_sidecar: bool,
+    permissions: FilePermissions,
+    /// counts the files to merge that fail their checksum
+    checksum_failures: Counter,
+}
+
+/// Spawns the compaction task for `type_id` if it has a compaction policy.
+pub fn spawn(type_id: &TypeConfig, metrics: CompactionMetrics) {
+    let compaction = match &type_id.compaction {
+        None => return,
+        Some(compaction) => compaction.clone(),
+    };
+    let interval_secs = match type_id.rotation() {
+        Rotation::Interval { interval_secs } => interval_secs,
+        Rotation::Count { .. } => 0,
+    };
+    let target = Target {
+        type_id: type_id.type_id,
+        root: PathBuf::from(type_id.root.as_str()),
+        template: type_id.filename_template(),
+        open_for: interval_secs.max(type_id.append_window_secs.unwrap_or(0)) * 1_000_000,
+        write_sidecar: type_id.write_sidecar,
+        permissions: type_id.file_permissions(),
+        checksum_failures: metrics
+            .checksum_failures
+            .get_or_create(&TypeLabels {
+                type_id: type_id.type_id,
+            })
+            .clone(),
+    };
+    let span = info_span!("compaction", type_id = target.type_id);
+    task::spawn(run(target, compaction, metrics).instrument(span));
+}
+
+async fn run(target: Target, compaction: CompactionConfig, metrics: CompactionMetrics) {
+    let mut ticks = interval(Duration::from_secs(
+        compaction.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS),
+    ));
+    loop {
+        ticks.tick().await;
+        let (target, compaction) = (target.clone(), compaction.clone());
+        let type_id = target.type_id;
+        let compacted = task::spawn_blocking(move || compact(&target, &compaction)).await;
+        let (merged, written) = match compacted {
+            Ok(Ok(compacted)) => compacted,
+            Ok(Err(err)) => {
+                warn!(%err, "compaction scan failed");
+                continue;
+            }
+            Err(err) => {
+                warn!(%err, "compaction panicked");
+                continue;
+            }
+        };
+        if written > 0 {
+            info!(merged, written, "small containers compacted");
+            let labels = TypeLabels { type_id };
+            metrics.merged_files.get_or_create(&labels).inc_by(merged);
+            metrics.written_files.get_or_create(&labels).inc_by(written);
+        }
+    }
+}
+
+/// Merges runs of consecutive small files of each directory, oldest first, and
+/// returns how many files were merged and how many were written. A group that fails
+/// to merge is logged and left as it was.
+fn compact(target: &Target, compaction: &CompactionConfig) -> io::Result<(u64, u64)> {
+    let mut dirs: BTreeMap<PathBuf, Vec<BlobFile>> = BTreeMap::new();
+    for file in paths::list_blob_files(target.type_id, &target.root, &target.template)? {
+        let dir = file
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        dirs.entry(dir).or_default().push(file);
+    }
+    let settled_before = SystemTime::now() - SETTLE_TIME;
+    let (mut merged, mut written) = (0, 0);
+    for (_, mut files) in dirs {
+        files.sort_by_key(|file| file.timestamp);
+        let mut groups = vec![Vec::new()];
+        let mut group_bytes = 0;
+        for file in files {
+            let open = file.timestamp + target.open_for > now_micros()
+                || fs::metadata(&file.path)?.modified()? > settled_before;
+            if open || file.size >= compaction.target_bytes {
+                groups.push(Vec::new());
+                group_bytes = 0;
+                continue;
+            }
+            group_bytes += file.size;
+            groups.last_mut().unwrap().push(file);
+            if group_bytes >= compaction.target_bytes {
+                groups.push(Vec::new());
+                group_bytes = 0;
+            }
+        }
+        for group in groups {
+            if group.len() < compaction.min_files {
+                continue;
+            }
+            match merge_group(&group, target) {
+                Ok(()) => {
+                    merged += group.len() as u64 - 1;
+                    written += 1;
+                }
+                Err(err) => warn!(%err, files = group.len(), "merging containers failed"),
+            }
+        }
+    }
+    Ok((merged, written))
+}
+
+/// Merges the files of `group` into the newest of them and removes the others. The
+/// merged file is synced before it replaces the newest file and only then are the
+/// others removed, so a crash leaves blobs duplicated at worst, never lost. A file
+/// deleted meanwhile, e.g. on request, makes the merge back off instead of bringing
+/// its blobs back.
+fn merge_group(group: &[BlobFile], target: &Target) -> io::Result<()> {
+    let containers = group
+        .iter()
+        .map(|file| {
+            let read = File::open(&file.path)
+                .map_err(BlobError::from)
+                .and_then(|opened| Container::from_file(BufReader::new(opened)));
+            if read.as_ref().is_err_and(BlobError::is_checksum_failure) {
+                warn!(path = %file.path.display(), "container fails its checksum, not merged");
+                target.checksum_failures.inc();
+            }
+            read
+        })
+        .collect::<Result<Vec<_>, BlobError>>()?;
+    let mut merged = Container::merge(containers)?;
+    let (newest, others) = group.split_last().unwrap();
+    let tmp_path = newest.path.with_extension("blob.compact");
+    let saved = merged
+        .save_to_file(target.permissions.create(&tmp_path)?)
+        .and_then(|_| File::open(&tmp_path)?.sync_all());
+    if let Err(err) = saved {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    if let Some(gone) = group.iter().find(|file| !file.path.exists()) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("{} was removed while merging", gone.path.display()),
+        ));
+    }
+    fs::rename(&tmp_path, &newest.path)?;
+    if let Some(dir) = newest.path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    for file in others {
+        fs::remove_file(&file.path)?;
+        if let Err(err) = manifest::remove(&file.path) {
+            warn!(%err, path = %file.path.display(), "manifest update failed");
+        }
+        if let Err(err) = sidecar::remove(&file.path) {
+            warn!(%err, path = %file.path.display(), "removing the sidecar failed");
+        }
+    }
+    if target.write_sidecar {
+        if let Err(err) = sidecar::write(&newest.path) {
+            warn!(%err, path = %newest.path.display(), "writing the sidecar failed");
+        }
+    }
+    if let Err(err) = manifest::record(&newest.path) {
+        warn!(%err, path = %newest.path.display(), "manifest update failed");
+    }
+    Ok(())
+}
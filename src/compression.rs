@@ -0,0 +1,59 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::stream::{unfold, Stream};
+use futures_util::StreamExt;
+use hyper::header::ACCEPT_ENCODING;
+use hyper::HeaderMap;
+use std::io;
+use std::io::Write;
+
+/// Whether `Accept-Encoding` lists `gzip` or `*` without ruling it out with `q=0`.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or("").trim();
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+pub fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Compresses a stream of chunks as they come, so large bodies aren't buffered.
+pub fn gzip_stream<S>(chunks: S) -> impl Stream<Item = io::Result<Vec<u8>>> + Send
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Send + 'static,
+{
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    unfold(
+        (chunks.boxed(), Some(encoder)),
+        |(mut chunks, encoder)| async move {
+            let mut encoder = encoder?;
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    let compressed = encoder
+                        .write_all(&chunk)
+                        .map(|()| std::mem::take(encoder.get_mut()));
+                    Some((compressed, (chunks, Some(encoder))))
+                }
+                Some(Err(err)) => Some((Err(err), (chunks, None))),
+                None => Some((encoder.finish(), (chunks, None))),
+            }
+        },
+    )
+}
@@ -1,34 +1,787 @@
-use clap::Parser;
-use serde_derive::Deserialize;
+use crate::blob::checksum::{ChecksumAlgo, ChecksumScope};
+use crate::blob::storage::FilePermissions;
+use crate::paths::FilenameTemplate;
+use clap::{Parser, Subcommand};
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs::File;
 use std::io;
 use std::io::{ErrorKind, Read};
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+use url::Url;
 
-#[derive(Deserialize)]
+/// writer_ids a type accepts unless it sets `writer_count`
+const DEFAULT_WRITER_COUNT: u32 = 10;
+/// blobs smaller than this share compressed blocks with `compression = "adaptive"`
+const DEFAULT_COMPRESSION_THRESHOLD: u32 = 4096;
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
+    /// one of `error`, `warn`, `info`, `debug`, `trace` or `off`; defaults to `info`
+    pub log_level: Option<String>,
     pub server: HttpConfig,
     pub types: Vec<TypeConfig>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct HttpConfig {
     pub port: u16,
+    /// IP address of the interface to listen on, e.g. `127.0.0.1` or `::`; defaults
+    /// to `0.0.0.0`, all IPv4 interfaces
+    pub bind_address: Option<String>,
+    /// PEM certificate chain; the server speaks TLS when both this and `key_path` are set
+    pub cert_path: Option<String>,
+    /// PEM private key matching `cert_path`
+    pub key_path: Option<String>,
+    /// when set, POST requests must carry `Authorization: Bearer <auth_token>`;
+    /// metrics scraping via GET stays open
+    #[serde(serialize_with = "redacted")]
+    pub auth_token: Option<String>,
+    /// `/ready` and `/readyz` report 503 once this many posted blobs are waiting for
+    /// the writers
+    pub max_queued: Option<usize>,
+    /// requests still being handled after this many seconds, body included, are
+    /// answered with 408; no limit when not set
+    pub request_timeout_secs: Option<u64>,
+    /// throttles blob uploads, off when not set
+    pub rate_limit: Option<RateLimitConfig>,
+    /// benchmark mode: logs the ingest throughput of every type this often and serves
+    /// it on `/stats`
+    pub stats_interval_secs: Option<u64>,
+    /// log a line per request with the client IP, status, request body size and
+    /// latency, under the `access` target
+    #[serde(default)]
+    pub access_log: bool,
+    /// connections the kernel queues before they are accepted, 1 to 65535; defaults
+    /// to 1024 and is capped by `net.core.somaxconn`
+    pub tcp_backlog: Option<u32>,
+    /// disable Nagle's algorithm on accepted connections
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+    /// idle seconds before keepalive probes are sent on accepted connections, 1 to
+    /// 32767; no keepalive when not set
+    pub tcp_keepalive_secs: Option<u64>,
+    /// base URL of an OTLP/HTTP collector, e.g. `http://collector:4318`; when set, the
+    /// metrics are also pushed to its `/v1/metrics` as JSON, once more on SIGTERM or
+    /// SIGINT before exiting
+    pub otlp_endpoint: Option<String>,
+    /// how often the metrics are pushed, defaults to 60 seconds
+    pub otlp_interval_secs: Option<u64>,
+    /// serve the `/admin` endpoints: `/admin/flush/N`, `/admin/types`, which
+    /// registers types with any root at runtime, and `/admin/metrics/reset`, which
+    /// breaks `rate()` in production. Off by default, they answer 403 then
+    #[serde(default)]
+    pub enable_admin: bool,
+    /// `verbose` (default) or `minimal`
+    pub success_body: Option<SuccessBody>,
+    /// `json` (default) or `text`, for clients whose `Accept` has no preference
+    /// between `application/json` and `text/plain`
+    pub error_body: Option<ErrorBody>,
+    /// worker threads of the async runtime; defaults to one per CPU core
+    pub worker_threads: Option<usize>,
+    /// on SIGTERM or SIGINT new connections are refused and requests in flight get
+    /// this many seconds to complete before their connections are closed; defaults
+    /// to 30
+    pub shutdown_grace_secs: Option<u64>,
+    /// requests handled at once across all connections; further requests wait for
+    /// one to complete. No limit when not set
+    pub max_concurrent_requests: Option<usize>,
+    /// requests handled at once before further ones are shed with 503 right away,
+    /// unlike `max_concurrent_requests`, which makes them wait. No limit when not set
+    pub max_inflight_requests: Option<usize>,
+    /// container files open at once to serve blob reads; further reads wait for one
+    /// to be closed, up to `open_file_timeout_ms`, then fail with 503. No limit when
+    /// not set
+    pub max_open_files: Option<usize>,
+    /// 5000 by default
+    pub open_file_timeout_ms: Option<u64>,
+    /// seconds a connection may stay open without a request in flight before it is
+    /// closed; kept open until the client closes it when not set
+    pub idle_connection_timeout_secs: Option<u64>,
+    /// requests served on a connection before it is closed, the last one answered
+    /// with `Connection: close`; no limit when not set
+    pub max_requests_per_connection: Option<u64>,
+}
+
+/// What an accepted upload is answered with.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SuccessBody {
+    /// `{"state":0}` only, as before ids were returned
+    Minimal,
+    /// the echoed `type_id` and `writer_id` and the assigned `sequence`; batches
+    /// report their `count` and `first_sequence`
+    #[default]
+    Verbose,
 }
 
-#[derive(Deserialize, Clone)]
+/// What a failed request is answered with.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorBody {
+    /// `application/json` with the state, reason and description
+    #[default]
+    Json,
+    /// `text/plain` with the description only
+    Text,
+}
+
+/// Token bucket limit on blob uploads; requests over it get a 429 with `Retry-After`.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub requests_per_sec: u32,
+    /// requests accepted at once after a quiet period, defaults to `requests_per_sec`
+    pub burst: Option<u32>,
+    /// `writer` (default) or `client`
+    pub by: Option<RateLimitBy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBy {
+    /// one bucket per (type_id, writer_id)
+    #[default]
+    Writer,
+    /// one bucket per client IP address
+    Client,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct TypeConfig {
     pub type_id: u32,
     pub root: String,
-    pub objects_in_container: u32,
+    /// producers post with writer_ids below this, 10 by default
+    pub writer_count: Option<u32>,
+    /// shorthand for `rotation = { count = N }`, kept for existing configs
+    pub objects_in_container: Option<u32>,
+    pub rotation: Option<Rotation>,
+    /// flushes a container once it holds this many blobs whatever the rotation says,
+    /// guarding against a mistyped rotation; defaults to 1000000
+    pub max_objects_hard_limit: Option<u32>,
+    /// append flushed containers to one file per window of this many seconds
+    /// instead of creating a new file per flush (read-modify-write on every flush)
+    pub append_window_secs: Option<u64>,
+    /// flush a container holding blobs once no blob arrived for this many
+    /// milliseconds, so the last blobs of a quiet period aren't held back
+    pub idle_flush_ms: Option<u64>,
+    /// with interval rotation, flush each window's container up to this many
+    /// milliseconds after the window ends, by an offset drawn from the type_id and
+    /// the window, so types on the same interval don't all write at once. The
+    /// container also takes the blobs of the first moments of the next window; 0 by
+    /// default
+    pub flush_jitter_ms: Option<u64>,
+    /// flush a container once its data reaches this many bytes and no blob is
+    /// waiting, so a burst isn't cut into a full container and a tiny trailing one;
+    /// while blobs keep arriving it grows up to `hard_max_bytes`
+    pub soft_max_bytes: Option<u64>,
+    /// a blob that would take the data of a container past this many bytes starts
+    /// the next one. Only a container of a single bigger blob gets larger. When
+    /// flushes are appended to a file, both limits apply to each flush, not to the
+    /// file
+    pub hard_max_bytes: Option<u64>,
+    /// queue size the type is sized for: `/ready` and `/readyz` report 503 once 90%
+    /// of it is waiting for the writer, until the queue drains below 70%
+    pub max_queued: Option<usize>,
+    /// writer tasks building containers of the type in parallel, 1 by default; blobs
+    /// go to the least loaded one, so their order across the tasks' files is
+    /// arbitrary. Can't be combined with appending, and only changes on restart
+    pub writers_per_type: Option<u32>,
+    /// adds and retires writer tasks by queue depth, starting from
+    /// `writers_per_type`; only changes on restart
+    pub autoscale: Option<AutoscaleConfig>,
+    /// limits what a single writer_id puts into one container, off when not set
+    pub writer_cap: Option<WriterCapConfig>,
+    pub retention: Option<RetentionConfig>,
+    pub compaction: Option<CompactionConfig>,
+    /// `crc32` (default), `xxhash64` or `sha256`
+    pub checksum: Option<ChecksumAlgo>,
+    /// permission bits of the container files, as a TOML octal integer, e.g. `0o640`;
+    /// set when a file is created, before anything is written to it. The umask
+    /// decides when not set
+    pub file_mode: Option<u32>,
+    /// numeric group id the container files are given, which the server's user must
+    /// be a member of
+    pub file_gid: Option<u32>,
+    /// `full` (default) or `headers`, which leaves the data out of the checksum and
+    /// relies on the per-blob CRCs in the TOC for it, saving a pass over the data on
+    /// every flush and append; see `ChecksumScope` for what is checked when
+    pub checksum_scope: Option<ChecksumScope>,
+    /// store the data of byte-identical blobs in a container only once
+    #[serde(default)]
+    pub dedup: bool,
+    /// `none` (default), `blob`, which deflates each blob on its own, or `adaptive`,
+    /// which packs blobs under `compression_threshold` into shared compressed blocks
+    /// and deflates larger ones on their own, so they can still be read without the
+    /// others. Data that doesn't shrink is stored as it is
+    pub compression: Option<CompressionMode>,
+    /// bytes, 4096 by default
+    pub compression_threshold: Option<u32>,
+    /// put container files in `root/YYYY/MM/DD/` directories by the UTC day they were
+    /// started; each directory gets its own manifest
+    #[serde(default)]
+    pub date_partitioned: bool,
+    /// names of the container files, `type{type_id}_{timestamp}.blob` by default; see
+    /// `FilenameTemplate` for the placeholders
+    pub filename_template: Option<String>,
+    /// write a `<name>.meta.json` with the checksum and object count next to each
+    /// local container
+    #[serde(default)]
+    pub write_sidecar: bool,
+    /// keep `type{type_id}_latest.blob` in the root pointing at the newest local
+    /// container, a symlink replaced after each flush; a `type{type_id}_latest` file
+    /// holding its name where there are no symlinks. Dangles once retention or
+    /// compaction removed that container, until the next flush
+    #[serde(default)]
+    pub maintain_latest_pointer: bool,
+    /// when local container files are synced to disk: `none` (default) leaves it to
+    /// the OS, `always` syncs each file and its directory as it is written, and
+    /// `interval` syncs the files written meanwhile every `sync_interval_ms`, so a
+    /// crash loses at most that window of containers. Only changes on restart
+    pub sync_policy: Option<SyncPolicy>,
+    /// 1000 by default
+    pub sync_interval_ms: Option<u64>,
+    /// log every accepted blob to a write-ahead log in the root before answering the
+    /// producer, and replay the log into the next container after a crash, so
+    /// acknowledged blobs are stored at least once. With `sync_policy = "always"` the
+    /// log is synced before each answer, otherwise it only survives a crash of the
+    /// process. Only changes on restart
+    #[serde(default)]
+    pub wal: bool,
+    /// journal the TOC of the container being built next to its spool file, and
+    /// store the containers of the spool files left behind when the server starts, so
+    /// a crash of the process only loses the blobs still queued and the uploads
+    /// being received. Cheaper than `wal`, which it can't be combined with
+    #[serde(default)]
+    pub resume_spool: bool,
+    /// where finished containers go, `local` (default) or `s3`; with `s3` the root
+    /// only holds spool and upload files, and retention, manifests, reads and
+    /// deletes only cover local containers
+    pub backend: Option<BackendKind>,
+    /// required with `backend = "s3"`
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Local,
+    S3,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionMode {
+    #[default]
+    None,
+    Blob,
+    Adaptive,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    #[default]
+    None,
+    Always,
+    Interval,
+}
+
+/// An S3 compatible bucket, e.g. AWS S3 or MinIO.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://minio:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    /// prepended to the file names to form the object keys
+    pub prefix: Option<String>,
+    /// defaults to `us-east-1`
+    pub region: Option<String>,
+    /// address the bucket as `bucket.endpoint` instead of `endpoint/bucket`
+    #[serde(default)]
+    pub virtual_host_style: bool,
+}
+
+/// Deletes the oldest `.blob` files of a type once they exceed an age or the type's
+/// files exceed a total size. The newest file is never deleted.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RetentionConfig {
+    pub max_age_secs: Option<u64>,
+    pub max_total_bytes: Option<u64>,
+    /// how often the root is scanned, defaults to 60 seconds
+    pub interval_secs: Option<u64>,
+}
+
+/// Adds a writer task when the blobs waiting for the type's writers stay above
+/// `scale_up_depth` for `sustain_secs`, and retires one when they stay at or below
+/// `scale_down_depth` as long.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AutoscaleConfig {
+    /// writer tasks kept at least, defaults to 1
+    pub min_writers: Option<u32>,
+    pub max_writers: u32,
+    pub scale_up_depth: usize,
+    pub scale_down_depth: usize,
+    /// defaults to 10 seconds
+    pub sustain_secs: Option<u64>,
+}
+
+/// Caps the blobs a single writer_id contributes to one container, so a noisy producer
+/// doesn't crowd the others out of a shared type. A writer's first blob in a container
+/// is always taken, whatever its size.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct WriterCapConfig {
+    pub max_objects: Option<u32>,
+    pub max_bytes: Option<u64>,
+    /// `spill` (default) or `reject`
+    pub over_cap: Option<OverCap>,
+}
+
+/// What happens to a blob that would take its writer over the cap.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverCap {
+    /// the current container is flushed and the blob starts the next one
+    #[default]
+    Spill,
+    /// the blob is dropped; single uploads and, with `wal`, batches are answered
+    /// with 429, otherwise its sequence turns unknown to the lookup
+    Reject,
+}
+
+/// Merges runs of small `.blob` files of a type into larger ones in the background.
+/// Files still being written or appended to are left alone.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct CompactionConfig {
+    /// smallest number of files worth merging, at least 2
+    pub min_files: usize,
+    /// files are merged until the result reaches this size; bigger files are kept
+    pub target_bytes: u64,
+    /// how often the root is scanned, defaults to 300 seconds
+    pub interval_secs: Option<u64>,
+}
+
+/// When the writer closes the current container and starts a new one.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Rotation {
+    /// after this many objects
+    Count { count: u32 },
+    /// when the wall clock crosses a multiple of this many seconds; the file is named
+    /// by the start of its window
+    Interval { interval_secs: u64 },
+}
+
+impl HttpConfig {
+    pub fn socket_addr(&self) -> io::Result<SocketAddr> {
+        let ip = match &self.bind_address {
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            Some(bind_address) => bind_address.parse().map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("server: invalid bind_address {}", bind_address),
+                )
+            })?,
+        };
+        Ok(SocketAddr::new(ip, self.port))
+    }
+}
+
+/// Serializes a secret as a placeholder, so printed configs can be shared.
+fn redacted<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_some("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl TypeConfig {
+    /// Validates the settings of a single type, whether it comes from the config file
+    /// or is registered at runtime.
+    pub fn check(&self) -> io::Result<()> {
+        if self.rotation.is_some() == self.objects_in_container.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type {}: exactly one of objects_in_container and rotation must be set",
+                    self.type_id
+                ),
+            ));
+        }
+        if let Rotation::Count { count: 0 } | Rotation::Interval { interval_secs: 0 } =
+            self.rotation()
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("type {}: rotation must be positive", self.type_id),
+            ));
+        }
+        if self.writer_count == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("type {}: writer_count must be positive", self.type_id),
+            ));
+        }
+        if self.max_objects_hard_limit == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type {}: max_objects_hard_limit must be positive",
+                    self.type_id
+                ),
+            ));
+        }
+        if self.max_queued == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("type {}: max_queued must be positive", self.type_id),
+            ));
+        }
+        if self.writers_per_type == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("type {}: writers_per_type must be positive", self.type_id),
+            ));
+        }
+        if self.writers_per_type.unwrap_or(1) > 1 && self.appends() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type {}: several writers_per_type can't append to the same file",
+                    self.type_id
+                ),
+            ));
+        }
+        if let Some(autoscale) = &self.autoscale {
+            let min_writers = autoscale.min_writers.unwrap_or(1);
+            let writers = self.writers_per_type.unwrap_or(1);
+            if min_writers == 0 || !(min_writers..=autoscale.max_writers).contains(&writers) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: autoscale needs 1 <= min_writers <= writers_per_type <= \
+                         max_writers",
+                        self.type_id
+                    ),
+                ));
+            }
+            if autoscale.scale_down_depth >= autoscale.scale_up_depth
+                || autoscale.sustain_secs == Some(0)
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: autoscale needs scale_down_depth below scale_up_depth and \
+                         a positive sustain_secs",
+                        self.type_id
+                    ),
+                ));
+            }
+            if autoscale.max_writers > 1 && self.appends() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: autoscaled writers can't append to the same file",
+                        self.type_id
+                    ),
+                ));
+            }
+        }
+        if let Some(writer_cap) = &self.writer_cap {
+            if writer_cap.max_objects.is_none() && writer_cap.max_bytes.is_none() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: writer_cap needs max_objects or max_bytes",
+                        self.type_id
+                    ),
+                ));
+            }
+            if writer_cap.max_objects == Some(0) || writer_cap.max_bytes == Some(0) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: writer_cap max_objects and max_bytes must be positive",
+                        self.type_id
+                    ),
+                ));
+            }
+        }
+        if self.wal && self.resume_spool {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type {}: wal and resume_spool can't both be set",
+                    self.type_id
+                ),
+            ));
+        }
+        if self.idle_flush_ms == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("type {}: idle_flush_ms must be positive", self.type_id),
+            ));
+        }
+        if let Some(flush_jitter_ms) = self.flush_jitter_ms {
+            let interval_ms = match self.rotation() {
+                Rotation::Interval { interval_secs } => interval_secs * 1000,
+                Rotation::Count { .. } => 0,
+            };
+            if flush_jitter_ms >= interval_ms {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: flush_jitter_ms needs an interval rotation longer than it",
+                        self.type_id
+                    ),
+                ));
+            }
+        }
+        if self.file_mode.is_some_and(|mode| mode > 0o7777) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type {}: file_mode must be permission bits like 0o640",
+                    self.type_id
+                ),
+            ));
+        }
+        if self.soft_max_bytes == Some(0) || self.hard_max_bytes == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type {}: soft_max_bytes and hard_max_bytes must be positive",
+                    self.type_id
+                ),
+            ));
+        }
+        if let (Some(soft), Some(hard)) = (self.soft_max_bytes, self.hard_max_bytes) {
+            if soft > hard {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: soft_max_bytes must not exceed hard_max_bytes",
+                        self.type_id
+                    ),
+                ));
+            }
+        }
+        if self.sync_interval_ms == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("type {}: sync_interval_ms must be positive", self.type_id),
+            ));
+        }
+        if self.append_window_secs == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("type {}: append_window_secs must be positive", self.type_id),
+            ));
+        }
+        if let Some(retention) = &self.retention {
+            if retention.max_age_secs.is_none() && retention.max_total_bytes.is_none() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: retention needs max_age_secs or max_total_bytes",
+                        self.type_id
+                    ),
+                ));
+            }
+            if retention.interval_secs == Some(0) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: retention interval_secs must be positive",
+                        self.type_id
+                    ),
+                ));
+            }
+        }
+        if let Some(template) = &self.filename_template {
+            let template = FilenameTemplate::parse(template).map_err(|err| {
+                io::Error::new(err.kind(), format!("type {}: {}", self.type_id, err))
+            })?;
+            if self.appends() && template.varies_per_container() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: {{count}} and {{seq}} can't be used when containers are appended",
+                        self.type_id
+                    ),
+                ));
+            }
+        }
+        if let Some(compaction) = &self.compaction {
+            if compaction.min_files < 2
+                || compaction.target_bytes == 0
+                || compaction.interval_secs == Some(0)
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: compaction needs min_files of at least 2 and positive \
+                         target_bytes and interval_secs",
+                        self.type_id
+                    ),
+                ));
+            }
+            if self.filename_template().varies_per_container() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "type {}: {{count}} and {{seq}} can't be used with compaction",
+                        self.type_id
+                    ),
+                ));
+            }
+        }
+        match (self.backend.unwrap_or_default(), &self.s3) {
+            (BackendKind::S3, None) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("type {}: backend s3 needs an [s3] section", self.type_id),
+                ))
+            }
+            (BackendKind::S3, Some(s3)) => {
+                if let Err(err) = Url::parse(&s3.endpoint) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "type {}: invalid s3 endpoint {}: {}",
+                            self.type_id, s3.endpoint, err
+                        ),
+                    ));
+                }
+                if s3.bucket.is_empty() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("type {}: s3 bucket must not be empty", self.type_id),
+                    ));
+                }
+            }
+            (BackendKind::Local, _) => {}
+        }
+        let path = Path::new(self.root.as_str());
+        if !path.exists() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("path {} not found", self.root),
+            ));
+        }
+        if !path.is_dir() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("path {} is not a directory", self.root),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn filename_template(&self) -> FilenameTemplate {
+        match &self.filename_template {
+            None => FilenameTemplate::default(),
+            Some(template) => {
+                FilenameTemplate::parse(template).expect("checked by TypeConfig::check")
+            }
+        }
+    }
+
+    /// The `block_threshold` of `ContainerWriter::enable_compression`, `None` without
+    /// compression.
+    pub fn block_threshold(&self) -> Option<u32> {
+        match self.compression.unwrap_or_default() {
+            CompressionMode::None => None,
+            CompressionMode::Blob => Some(0),
+            CompressionMode::Adaptive => Some(
+                self.compression_threshold
+                    .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
+            ),
+        }
+    }
+
+    pub fn file_permissions(&self) -> FilePermissions {
+        FilePermissions {
+            mode: self.file_mode,
+            gid: self.file_gid,
+        }
+    }
+
+    pub fn rotation(&self) -> Rotation {
+        match (self.rotation, self.objects_in_container) {
+            (Some(rotation), _) => rotation,
+            (None, Some(count)) => Rotation::Count { count },
+            (None, None) => unreachable!("checked by TypeConfig::check"),
+        }
+    }
+
+    pub fn writer_count(&self) -> u32 {
+        self.writer_count.unwrap_or(DEFAULT_WRITER_COUNT)
+    }
+
+    /// Whether flushed containers are appended to an existing file: with
+    /// `append_window_secs` and with interval rotation.
+    pub fn appends(&self) -> bool {
+        self.append_window_secs.is_some() || matches!(self.rotation(), Rotation::Interval { .. })
+    }
 }
 
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
     /// path to config
+    #[arg(short, long, required = true)]
+    pub config: Option<String>,
+    /// worker threads of the async runtime, overrides `worker_threads` in the config
+    #[arg(long)]
+    pub worker_threads: Option<NonZeroUsize>,
+    /// print the config as it takes effect, with the environment and command line
+    /// overrides applied, and exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// log the effective config at startup
     #[arg(short, long)]
-    pub config: String,
+    pub verbose: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Rewrite a container's checksum so it can be read again
+    Repair {
+        /// container file to repair in place
+        file: PathBuf,
+    },
+    /// Write the data of one blob of a container to stdout, after verifying the
+    /// container's checksum
+    Cat {
+        /// container file to read
+        file: PathBuf,
+        /// position of the blob in the container, from 0; with `--writer`, among the
+        /// blobs of that writer
+        index: usize,
+        /// count only the blobs of this writer_id
+        #[arg(long)]
+        writer: Option<u32>,
+    },
+    /// Rebuild a directory's manifest from the containers in it
+    Reindex {
+        /// root directory of one or more types
+        dir: PathBuf,
+    },
+    /// Validate a config file without starting the server, exiting non-zero if it's
+    /// invalid
+    CheckConfig {
+        /// path to config
+        #[arg(short, long)]
+        config: String,
+    },
 }
 
 impl Config {
@@ -37,26 +790,269 @@ impl Config {
         let mut toml_str = "".to_string();
         file.read_to_string(&mut toml_str)?;
         match toml::from_str::<Self>(toml_str.as_str()) {
-            Ok(config) => config.check_config(),
+            Ok(config) => config
+                .with_overrides(|name| std::env::var(name).ok())?
+                .check_config(),
             Err(err) => Err(io::Error::new(ErrorKind::Unsupported, err)),
         }
     }
+    /// The config as TOML, with `auth_token` redacted. Settings left out of the file
+    /// are left out here too, they take their documented defaults.
+    pub fn to_toml(&self) -> io::Result<String> {
+        // through a `Value`, which puts the tables after the plain values as TOML
+        // requires, whatever the order of the fields
+        toml::Value::try_from(self)
+            .and_then(|config| toml::to_string(&config))
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+
+    /// Applies overrides on top of the values read from the file; an override always
+    /// wins. `lookup` resolves a variable name, `std::env::var` in production.
+    ///
+    /// Recognized names:
+    /// - `BLOBQUEUE_PORT`, `BLOBQUEUE_AUTH_TOKEN`, `BLOBQUEUE_LOG_LEVEL`
+    /// - `BLOBQUEUE_TYPE_<type_id>_ROOT`, `BLOBQUEUE_TYPE_<type_id>_OBJECTS_IN_CONTAINER`
+    ///   for types present in the file
+    pub fn with_overrides<F>(mut self, lookup: F) -> io::Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(port) = lookup("BLOBQUEUE_PORT") {
+            self.server.port = parse_override("BLOBQUEUE_PORT", &port)?;
+        }
+        if let Some(auth_token) = lookup("BLOBQUEUE_AUTH_TOKEN") {
+            self.server.auth_token = Some(auth_token);
+        }
+        if let Some(log_level) = lookup("BLOBQUEUE_LOG_LEVEL") {
+            self.log_level = Some(log_level);
+        }
+        for type_id in self.types.iter_mut() {
+            let prefix = format!("BLOBQUEUE_TYPE_{}_", type_id.type_id);
+            if let Some(root) = lookup(&format!("{}ROOT", prefix)) {
+                type_id.root = root;
+            }
+            let name = format!("{}OBJECTS_IN_CONTAINER", prefix);
+            if let Some(count) = lookup(&name) {
+                type_id.objects_in_container = Some(parse_override(&name, &count)?);
+                type_id.rotation = None;
+            }
+        }
+        Ok(self)
+    }
+    pub fn log_level(&self) -> io::Result<LevelFilter> {
+        match &self.log_level {
+            None => Ok(LevelFilter::INFO),
+            Some(level) => LevelFilter::from_str(level).map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown log_level {}", level),
+                )
+            }),
+        }
+    }
     pub fn check_config(self) -> io::Result<Self> {
-        for type_id in self.types.iter() {
-            let path = Path::new(type_id.root.as_str());
-            if !path.exists() {
+        self.log_level()?;
+        self.server.socket_addr()?;
+        if self.server.cert_path.is_some() != self.server.key_path.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: cert_path and key_path must be set together",
+            ));
+        }
+        if self.server.auth_token.as_deref() == Some("") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: auth_token must not be empty",
+            ));
+        }
+        if self.server.stats_interval_secs == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: stats_interval_secs must be positive",
+            ));
+        }
+        if self.server.request_timeout_secs == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: request_timeout_secs must be positive",
+            ));
+        }
+        if !matches!(self.server.tcp_backlog, None | Some(1..=65535)) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: tcp_backlog must be between 1 and 65535",
+            ));
+        }
+        if !matches!(self.server.tcp_keepalive_secs, None | Some(1..=32767)) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: tcp_keepalive_secs must be between 1 and 32767",
+            ));
+        }
+        if let Some(endpoint) = &self.server.otlp_endpoint {
+            if let Err(err) = Url::parse(endpoint) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("server: invalid otlp_endpoint {}: {}", endpoint, err),
+                ));
+            }
+        }
+        if self.server.worker_threads == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: worker_threads must be positive",
+            ));
+        }
+        if self.server.max_concurrent_requests == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: max_concurrent_requests must be positive",
+            ));
+        }
+        if self.server.max_open_files == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: max_open_files must be positive",
+            ));
+        }
+        if self.server.idle_connection_timeout_secs == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: idle_connection_timeout_secs must be positive",
+            ));
+        }
+        if self.server.max_requests_per_connection == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: max_requests_per_connection must be positive",
+            ));
+        }
+        if self.server.max_inflight_requests == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: max_inflight_requests must be positive",
+            ));
+        }
+        if self.server.otlp_interval_secs == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "server: otlp_interval_secs must be positive",
+            ));
+        }
+        if let Some(rate_limit) = &self.server.rate_limit {
+            if rate_limit.requests_per_sec == 0 || rate_limit.burst == Some(0) {
                 return Err(io::Error::new(
-                    ErrorKind::NotFound,
-                    format!("path {} not found", type_id.root),
+                    ErrorKind::InvalidInput,
+                    "server: rate_limit requests_per_sec and burst must be positive",
                 ));
             }
-            if !path.is_dir() {
+        }
+        let mut type_ids = HashSet::new();
+        for type_id in self.types.iter() {
+            type_id.check()?;
+            if !type_ids.insert(type_id.type_id) {
                 return Err(io::Error::new(
-                    ErrorKind::NotFound,
-                    format!("path {} is not a directory", type_id.root),
+                    ErrorKind::InvalidInput,
+                    format!("type {} is configured twice", type_id.type_id),
                 ));
             }
         }
         Ok(self)
     }
 }
+
+fn parse_override<T: FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value.parse::<T>().map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: invalid value {}", name, value),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            log_level = "info"
+            [server]
+            port = 8080
+            auth_token = "from file"
+            [[types]]
+            type_id = 1
+            root = "/var/lib/type1"
+            rotation = { interval_secs = 60 }
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn with_overrides(config: Config, vars: &[(&str, &str)]) -> io::Result<Config> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        config.with_overrides(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn overrides_win_over_the_file() {
+        let config = with_overrides(
+            config(),
+            &[
+                ("BLOBQUEUE_PORT", "9090"),
+                ("BLOBQUEUE_AUTH_TOKEN", "from env"),
+                ("BLOBQUEUE_LOG_LEVEL", "debug"),
+                ("BLOBQUEUE_TYPE_1_ROOT", "/srv/type1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.server.auth_token.as_deref(), Some("from env"));
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+        assert_eq!(config.types[0].root, "/srv/type1");
+        assert_eq!(
+            config.types[0].rotation,
+            Some(Rotation::Interval { interval_secs: 60 })
+        );
+    }
+
+    #[test]
+    fn objects_in_container_override_replaces_rotation() {
+        let config = with_overrides(
+            config(),
+            &[("BLOBQUEUE_TYPE_1_OBJECTS_IN_CONTAINER", "500")],
+        )
+        .unwrap();
+        assert_eq!(config.types[0].objects_in_container, Some(500));
+        assert_eq!(config.types[0].rotation, None);
+        assert_eq!(config.types[0].rotation(), Rotation::Count { count: 500 });
+    }
+
+    #[test]
+    fn bad_port_override_is_invalid_input() {
+        for port in ["http", "-1", "65536"] {
+            let overridden = with_overrides(config(), &[("BLOBQUEUE_PORT", port)]);
+            let err = overridden.err().expect(port);
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", port);
+        }
+    }
+
+    #[test]
+    fn overrides_of_unknown_types_are_ignored() {
+        let config = with_overrides(
+            config(),
+            &[
+                ("BLOBQUEUE_TYPE_2_ROOT", "/srv/type2"),
+                ("BLOBQUEUE_TYPE_2_OBJECTS_IN_CONTAINER", "not even a number"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.types.len(), 1);
+        assert_eq!(config.types[0].root, "/var/lib/type1");
+    }
+}
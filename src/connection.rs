@@ -0,0 +1,169 @@
+use crate::rate_limit::RemoteIp;
+use hyper::server::accept::Accept;
+use std::future::Future;
+use std::io;
+use std::io::IoSlice;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Accepts the connections of `incoming`, each closed once it had no request in
+/// flight and saw no traffic for `idle_timeout`.
+pub struct IdleIncoming<I> {
+    incoming: I,
+    idle_timeout: Option<Duration>,
+}
+
+impl<I> IdleIncoming<I> {
+    pub fn new(incoming: I, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            incoming,
+            idle_timeout,
+        }
+    }
+}
+
+impl<I: Accept + Unpin> Accept for IdleIncoming<I> {
+    type Conn = IdleConn<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        let idle_timeout = this.idle_timeout;
+        Pin::new(&mut this.incoming)
+            .poll_accept(cx)
+            .map(|conn| conn.map(|conn| conn.map(|conn| IdleConn::new(conn, idle_timeout))))
+    }
+}
+
+/// A connection that reads as closed by the client once it was idle too long, which
+/// makes hyper close it. The requests in flight are counted by `InFlight` guards, a
+/// connection waiting for a response is not idle.
+pub struct IdleConn<C> {
+    conn: C,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<C> IdleConn<C> {
+    fn new(conn: C, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            conn,
+            idle: idle_timeout.map(|timeout| (timeout, Box::pin(sleep(timeout)))),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The count of requests in flight on this connection, kept by `InFlight`.
+    pub fn requests(&self) -> Arc<AtomicUsize> {
+        self.in_flight.clone()
+    }
+
+    fn active(&mut self) {
+        if let Some((timeout, idle)) = self.idle.as_mut() {
+            idle.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+
+    /// Whether the idle timeout elapsed, registering for its wake-up otherwise. With
+    /// requests in flight the timeout starts over, but stays registered: hyper needn't
+    /// read again once they are answered, the wake-up makes it.
+    fn timed_out(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.in_flight.load(Ordering::Relaxed) > 0 {
+            self.active();
+        }
+        match self.idle.as_mut() {
+            Some((_, idle)) => idle.as_mut().poll(cx).is_ready(),
+            None => false,
+        }
+    }
+}
+
+/// A request in flight on an `IdleConn`, counted until dropped.
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn new(requests: Arc<AtomicUsize>) -> Self {
+        requests.fetch_add(1, Ordering::Relaxed);
+        Self(requests)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<C: RemoteIp> RemoteIp for IdleConn<C> {
+    fn remote_ip(&self) -> IpAddr {
+        self.conn.remote_ip()
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for IdleConn<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.conn).poll_read(cx, buf) {
+            Poll::Ready(read) => {
+                this.active();
+                Poll::Ready(read)
+            }
+            // nothing read is end of stream
+            Poll::Pending if this.timed_out(cx) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for IdleConn<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.conn).poll_write(cx, buf);
+        if written.is_ready() {
+            this.active();
+        }
+        written
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.conn).poll_write_vectored(cx, bufs);
+        if written.is_ready() {
+            this.active();
+        }
+        written
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.conn.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().conn).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().conn).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,95 @@
+use crate::config::ErrorBody;
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE, VARY};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use serde_derive::Serialize;
+
+/// The body of a failed request, `{"state":-1,"reason":N,"desc":"..."}`. The
+/// response carries it in its extensions too, for `render` to answer with `desc`
+/// alone.
+#[derive(Serialize, Clone, Debug)]
+pub struct ApiError {
+    pub state: i32,
+    /// tells the failures apart, also the `reason` label of the request metrics
+    pub reason: u32,
+    pub desc: String,
+}
+
+impl ApiError {
+    pub fn new(reason: u32, desc: impl Into<String>) -> Self {
+        Self {
+            state: -1,
+            reason,
+            desc: desc.into(),
+        }
+    }
+
+    pub fn into_response(self, status: StatusCode) -> Response<Body> {
+        let mut response = Response::new(Body::from(serde_json::to_string(&self).unwrap()));
+        *response.status_mut() = status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// The format `Accept` prefers for an error body: `text/plain` or `application/json`
+/// by their quality, `default` when it has no preference between them.
+pub fn negotiate(headers: &HeaderMap, default: ErrorBody) -> ErrorBody {
+    let json = quality(headers, "application", "json");
+    let text = quality(headers, "text", "plain");
+    match (json, text) {
+        (Some(json), Some(text)) if text > json => ErrorBody::Text,
+        (Some(json), Some(text)) if json > text => ErrorBody::Json,
+        (None, Some(text)) if text > 0.0 => ErrorBody::Text,
+        (Some(json), None) if json > 0.0 => ErrorBody::Json,
+        _ => default,
+    }
+}
+
+/// The quality `Accept` gives `kind/subtype`, from its most specific matching range.
+fn quality(headers: &HeaderMap, kind: &str, subtype: &str) -> Option<f32> {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let (range_kind, range_subtype) = params.next()?.trim().split_once('/')?;
+            let specificity = match (range_kind, range_subtype) {
+                ("*", "*") => 0,
+                (k, "*") if k.eq_ignore_ascii_case(kind) => 1,
+                (k, s) if k.eq_ignore_ascii_case(kind) && s.eq_ignore_ascii_case(subtype) => 2,
+                _ => return None,
+            };
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((specificity, q))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+        .map(|(_, q)| q)
+}
+
+/// Answers an `ApiError` response with the error's `desc` as `text/plain` for
+/// `ErrorBody::Text`; other responses are returned as they are.
+pub fn render(mut response: Response<Body>, format: ErrorBody) -> Response<Body> {
+    let error = match response.extensions_mut().remove::<ApiError>() {
+        None => return response,
+        Some(error) => error,
+    };
+    if format == ErrorBody::Text {
+        *response.body_mut() = Body::from(format!("{}\n", error.desc));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+    }
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept"));
+    response
+}
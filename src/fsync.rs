@@ -0,0 +1,128 @@
+use crate::config::{SyncPolicy, TypeConfig};
+use crate::metrics::{FlushPhase, PhaseDurations, TypeLabels, WriterMetrics};
+use prometheus_client::metrics::gauge::Gauge;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task;
+use tokio::time::interval;
+use tracing::{info_span, warn, Instrument};
+
+const DEFAULT_SYNC_INTERVAL_MS: u64 = 1000;
+
+/// Syncs the container files of a type to disk according to its `sync_policy`.
+pub struct Syncer {
+    type_id: u32,
+    policy: SyncPolicy,
+    /// files written since the last batch with `SyncPolicy::Interval`
+    pending: Mutex<BTreeSet<PathBuf>>,
+    unsynced: Gauge,
+    /// of each sync with `SyncPolicy::Always`, each batch with `SyncPolicy::Interval`
+    durations: PhaseDurations,
+}
+
+impl Syncer {
+    /// Creates the syncer of `type_id` and, with `SyncPolicy::Interval`, spawns the
+    /// task syncing its batches.
+    pub fn spawn(type_id: &TypeConfig, metrics: &WriterMetrics) -> Arc<Self> {
+        let syncer = Arc::new(Self {
+            type_id: type_id.type_id,
+            policy: type_id.sync_policy.unwrap_or_default(),
+            pending: Mutex::new(BTreeSet::new()),
+            unsynced: metrics
+                .unsynced_files
+                .get_or_create(&TypeLabels {
+                    type_id: type_id.type_id,
+                })
+                .clone(),
+            durations: metrics.flush_phase_seconds.clone(),
+        });
+        if syncer.policy == SyncPolicy::Interval {
+            let period =
+                Duration::from_millis(type_id.sync_interval_ms.unwrap_or(DEFAULT_SYNC_INTERVAL_MS));
+            let span = info_span!("fsync", type_id = type_id.type_id);
+            task::spawn(syncer.clone().run(period).instrument(span));
+        }
+        syncer
+    }
+
+    /// Called with each container file written: syncs it right away, queues it for the
+    /// next batch or does nothing, depending on the policy.
+    pub async fn written(&self, path: PathBuf) -> io::Result<()> {
+        match self.policy {
+            SyncPolicy::None => Ok(()),
+            SyncPolicy::Always => {
+                let started = Instant::now();
+                let synced = match task::spawn_blocking(move || sync(&[path])).await {
+                    Ok(result) => result,
+                    Err(err) => Err(io::Error::other(err)),
+                };
+                self.observe(started);
+                synced
+            }
+            SyncPolicy::Interval => {
+                let mut pending = self.pending.lock().unwrap();
+                pending.insert(path);
+                self.unsynced.set(pending.len() as u64);
+                Ok(())
+            }
+        }
+    }
+
+    fn observe(&self, started: Instant) {
+        self.durations
+            .observe(self.type_id, FlushPhase::Sync, started.elapsed());
+    }
+
+    async fn run(self: Arc<Self>, period: Duration) {
+        let mut ticks = interval(period);
+        loop {
+            ticks.tick().await;
+            let batch: Vec<PathBuf> = mem::take(&mut *self.pending.lock().unwrap())
+                .into_iter()
+                .collect();
+            if batch.is_empty() {
+                continue;
+            }
+            let (syncer, started) = (self.clone(), Instant::now());
+            let synced = task::spawn_blocking(move || {
+                let synced = sync(&batch);
+                if synced.is_err() {
+                    syncer.pending.lock().unwrap().extend(batch);
+                }
+                synced
+            })
+            .await;
+            self.observe(started);
+            match synced {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!(%err, "syncing containers failed, retrying"),
+                Err(err) => warn!(%err, "syncing containers panicked"),
+            }
+            self.unsynced.set(self.pending.lock().unwrap().len() as u64);
+        }
+    }
+}
+
+/// Syncs `files`, then each of their directories once so new names and renames are
+/// durable too. Files removed meanwhile, e.g. by retention, are skipped.
+fn sync(files: &[PathBuf]) -> io::Result<()> {
+    let mut dirs = BTreeSet::new();
+    for file in files {
+        match File::open(file) {
+            Ok(file) => file.sync_all()?,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+        dirs.extend(file.parent().map(Path::to_path_buf));
+    }
+    for dir in dirs {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use serde_derive::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+
+/// A type's queue counts as saturated once it holds this share of its `max_queued`...
+const HIGH_WATER_PERCENT: usize = 90;
+/// ...and until it drains below this one, so readiness doesn't flap around one mark.
+const LOW_WATER_PERCENT: usize = 70;
+
+/// Cheap state behind the health endpoints: how many writer tasks have exited, how
+/// many posted blobs are still waiting in the writer queues and which types can't
+/// store their containers.
+pub struct Health {
+    dead_writers: AtomicUsize,
+    queued: AtomicUsize,
+    max_queued: Option<usize>,
+    /// types whose last container couldn't be stored after retrying
+    failing_types: Mutex<HashSet<u32>>,
+    queues: RwLock<HashMap<u32, TypeQueue>>,
+}
+
+/// The queue of one type's writer.
+#[derive(Default)]
+struct TypeQueue {
+    depth: AtomicUsize,
+    capacity: Option<usize>,
+    saturated: AtomicBool,
+}
+
+/// How full a type's queue is, as reported by `/ready`.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct QueueDepth {
+    pub type_id: u32,
+    pub depth: usize,
+    pub capacity: Option<usize>,
+    pub saturated: bool,
+}
+
+impl Health {
+    pub fn new(max_queued: Option<usize>) -> Self {
+        Self {
+            dead_writers: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            max_queued,
+            failing_types: Mutex::new(HashSet::new()),
+            queues: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Starts tracking the queue of `type_id`, or updates its capacity.
+    pub fn track_queue(&self, type_id: u32, capacity: Option<usize>) {
+        let mut queues = self.queues.write().unwrap();
+        let queue = queues.entry(type_id).or_default();
+        queue.capacity = capacity;
+        if capacity.is_none() {
+            queue.saturated.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn writer_exited(&self) {
+        self.dead_writers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn flush_failed(&self, type_id: u32) {
+        self.failing_types.lock().unwrap().insert(type_id);
+    }
+
+    pub fn flush_succeeded(&self, type_id: u32) {
+        self.failing_types.lock().unwrap().remove(&type_id);
+    }
+
+    pub fn enqueued(&self, type_id: u32) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        if let Some(queue) = self.queues.read().unwrap().get(&type_id) {
+            let depth = queue.depth.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(capacity) = queue.capacity {
+                if depth * 100 >= capacity * HIGH_WATER_PERCENT {
+                    queue.saturated.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    pub fn dequeued(&self, type_id: u32) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        if let Some(queue) = self.queues.read().unwrap().get(&type_id) {
+            let depth = queue.depth.fetch_sub(1, Ordering::Relaxed) - 1;
+            if let Some(capacity) = queue.capacity {
+                if depth * 100 < capacity * LOW_WATER_PERCENT {
+                    queue.saturated.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// The queues of all types, ordered by type_id.
+    pub fn queue_depths(&self) -> Vec<QueueDepth> {
+        let mut depths: Vec<QueueDepth> = self
+            .queues
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(type_id, queue)| QueueDepth {
+                type_id: *type_id,
+                depth: queue.depth.load(Ordering::Relaxed),
+                capacity: queue.capacity,
+                saturated: queue.saturated.load(Ordering::Relaxed),
+            })
+            .collect();
+        depths.sort_by_key(|depth| depth.type_id);
+        depths
+    }
+
+    /// Ready means every writer task is still running, the last container of every
+    /// type was stored, no type's queue is saturated and, when `max_queued` is set,
+    /// the queues hold fewer blobs than that.
+    pub fn is_ready(&self) -> bool {
+        if self.dead_writers.load(Ordering::Relaxed) > 0 {
+            return false;
+        }
+        if !self.failing_types.lock().unwrap().is_empty() {
+            return false;
+        }
+        let saturated = self
+            .queues
+            .read()
+            .unwrap()
+            .values()
+            .any(|queue| queue.saturated.load(Ordering::Relaxed));
+        if saturated {
+            return false;
+        }
+        match self.max_queued {
+            None => true,
+            Some(max_queued) => self.queued.load(Ordering::Relaxed) < max_queued,
+        }
+    }
+}
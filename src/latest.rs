@@ -0,0 +1,27 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the pointer to the newest container of `type_id` is kept in its root: a
+/// symlink named like a container, or where there are no symlinks a text file
+/// holding the container's name.
+pub fn path(root: &Path, type_id: u32) -> PathBuf {
+    if cfg!(unix) {
+        root.join(format!("type{}_latest.blob", type_id))
+    } else {
+        root.join(format!("type{}_latest", type_id))
+    }
+}
+
+/// Points the pointer of `type_id` at `name`, a container under `root`. The new
+/// pointer is made next to the old one and renamed over it, so readers find either.
+pub fn point_at(root: &Path, type_id: u32, name: &str) -> io::Result<()> {
+    let pointer = path(root, type_id);
+    let tmp_path = pointer.with_extension("tmp");
+    let _ = fs::remove_file(&tmp_path);
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(name, &tmp_path)?;
+    #[cfg(not(unix))]
+    fs::write(&tmp_path, name)?;
+    fs::rename(tmp_path, pointer)
+}
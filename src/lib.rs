@@ -0,0 +1,6 @@
+//! The blob container format, usable without the server and without an async
+//! runtime: build a `blob::storage::Container`, push blobs, and save or load it
+//! synchronously. The HTTP server is the `blob_queue` binary behind the `server`
+//! feature, which is on by default.
+
+pub mod blob;
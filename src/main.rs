@@ -1,199 +1,1189 @@
 extern crate core;
 
-use crate::blob::storage::Container;
-use crate::config::{Args, Config};
+use crate::blob::content_type::ContentType;
+use crate::blob::meta;
+use crate::blob::meta::BlobMeta;
+use crate::blob::storage;
+use crate::blob::storage::{now_micros, BlobLocation, Container, ContainerReader};
+use crate::config::{Args, Command, Config, ErrorBody, HttpConfig, SuccessBody, TypeConfig};
+use crate::connection::{IdleConn, IdleIncoming, InFlight};
+use crate::error_body::ApiError;
+use crate::health::Health;
 use crate::metrics::Success::{No, Yes};
-use crate::metrics::{HttpLabels, HttpMethod, HttpStatus};
+use crate::metrics::{
+    BuildInfoLabels, CompactionMetrics, HttpLabels, HttpMethod, HttpStatus, RateLimitLabels,
+    Resettable, RetentionMetrics, Success, TypeLabels, WriterMetrics,
+};
+use crate::range::ByteRange;
+use crate::rate_limit::{LimitKey, RateLimiter, RemoteIp};
+use crate::receipts::Lookup;
+use crate::stats::Throughput;
+use crate::upload::UploadFile;
+use crate::writer::{PostData, WriterHandle};
+use blob_queue::blob;
 use clap::Parser;
-use hyper::service::{make_service_fn, service_fn};
+use futures_util::future::{pending, ready};
+use futures_util::stream::{unfold, Stream};
+use futures_util::StreamExt;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, AUTHORIZATION, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, RANGE, RETRY_AFTER, VARY,
+};
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::make_service_fn;
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use prometheus_client::encoding::text::encode;
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Registry;
+use serde::Serialize;
+use serde_derive::Serialize;
 use std::collections::HashMap;
 use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
 use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+use tls_listener::TlsListener;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use tokio::net::TcpSocket;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{oneshot, watch, OwnedSemaphorePermit, Semaphore};
 use tokio::task;
+use tokio::time::{sleep, timeout};
+use tower::limit::ConcurrencyLimitLayer;
+use tower::{BoxError, Service, ServiceBuilder};
+use tracing::{debug, error, info, instrument, warn};
 
-mod blob;
+mod autoscale;
+mod backend;
+mod batch;
+mod compaction;
+mod compression;
 mod config;
+mod connection;
+mod error_body;
+mod fsync;
+mod health;
+mod latest;
+mod manifest;
 mod metrics;
+mod otlp;
+mod paths;
+mod range;
+mod rate_limit;
+mod receipts;
+mod retention;
+mod sidecar;
+mod stats;
+#[cfg(test)]
+mod tests;
+mod tls;
+mod upload;
+mod wal;
+mod writer;
 
-const WRITER_COUNT: u32 = 10;
-
-#[derive(Debug)]
-struct PostData {
-    data: Vec<u8>,
-    writer_id: u32,
-}
-
-impl PostData {
-    fn new(writer_id: u32, data: Vec<u8>) -> Self {
-        Self { data, writer_id }
-    }
-}
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+const DEFAULT_TCP_BACKLOG: u32 = 1024;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 30;
+const DEFAULT_OPEN_FILE_TIMEOUT_MS: u64 = 5000;
 
 #[derive(Clone)]
 struct Context {
-    senders: Arc<RwLock<HashMap<u32, UnboundedSender<PostData>>>>,
-    http_requests_metrics: Family<HttpLabels, Counter>,
+    senders: Arc<RwLock<HashMap<u32, WriterHandle>>>,
+    http_requests_metrics: Resettable<Family<HttpLabels, Counter>>,
     http_requests_registry: Arc<Registry>,
+    auth_token: Option<Arc<str>>,
+    health: Arc<Health>,
+    retention_metrics: RetentionMetrics,
+    compaction_metrics: CompactionMetrics,
+    deleted_containers: Family<TypeLabels, Counter>,
+    writer_metrics: WriterMetrics,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    rate_limited: Family<RateLimitLabels, Counter>,
+    request_timeout: Option<Duration>,
+    timed_out_requests: Counter,
+    /// permits for `max_inflight_requests`
+    inflight: Option<Arc<Semaphore>>,
+    inflight_requests: Gauge,
+    shed_requests: Counter,
+    /// permits for `max_open_files`
+    open_files: Option<Arc<Semaphore>>,
+    open_file_timeout: Duration,
+    open_read_files: Gauge,
+    access_log: bool,
+    /// serve the `POST /admin/...` endpoints
+    enable_admin: bool,
+    success_body: SuccessBody,
+    /// for clients without a preference in `Accept`
+    error_body: ErrorBody,
+    /// set in benchmark mode
+    throughput: Option<Arc<Throughput>>,
+    /// microseconds since the Unix epoch when the server started, for `/version`
+    started: u64,
 }
 
 impl Context {
-    pub fn new(senders: HashMap<u32, UnboundedSender<PostData>>) -> Self {
+    pub fn new(
+        senders: HashMap<u32, WriterHandle>,
+        auth_token: Option<String>,
+        health: Arc<Health>,
+        writer_metrics: WriterMetrics,
+        rate_limiter: Option<RateLimiter>,
+        request_timeout: Option<Duration>,
+        access_log: bool,
+    ) -> Self {
         let mut http_requests_registry = <Registry>::default();
-        let http_requests_metrics = Family::<HttpLabels, Counter>::default();
+        let http_requests_metrics = Resettable::<Family<HttpLabels, Counter>>::default();
         http_requests_registry.register(
             "http_requests",
             "Number of HTTP requests received",
             Box::new(http_requests_metrics.clone()),
         );
+        let build_info = Family::<BuildInfoLabels, Gauge>::default();
+        build_info
+            .get_or_create(&BuildInfoLabels {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                git_hash: env!("GIT_HASH").to_string(),
+            })
+            .set(1);
+        http_requests_registry.register(
+            "build_info",
+            "Always 1, labeled with the version and commit of the running build",
+            Box::new(build_info),
+        );
+        let retention_metrics = RetentionMetrics::default();
+        http_requests_registry.register(
+            "retention_deleted_files",
+            "Number of container files deleted by the retention policy",
+            Box::new(retention_metrics.deleted_files.clone()),
+        );
+        http_requests_registry.register(
+            "retention_freed_bytes",
+            "Number of bytes freed by the retention policy",
+            Box::new(retention_metrics.freed_bytes.clone()),
+        );
+        let compaction_metrics = CompactionMetrics {
+            checksum_failures: writer_metrics.checksum_failures.clone(),
+            ..CompactionMetrics::default()
+        };
+        http_requests_registry.register(
+            "compaction_merged_files",
+            "Number of small container files merged into others by compaction",
+            Box::new(compaction_metrics.merged_files.clone()),
+        );
+        http_requests_registry.register(
+            "compaction_written_files",
+            "Number of container files written by compaction",
+            Box::new(compaction_metrics.written_files.clone()),
+        );
+        let deleted_containers = Family::<TypeLabels, Counter>::default();
+        http_requests_registry.register(
+            "deleted_containers",
+            "Number of container files deleted on request",
+            Box::new(deleted_containers.clone()),
+        );
+        http_requests_registry.register(
+            "writers_live",
+            "Number of writer tasks currently running",
+            Box::new(writer_metrics.live.clone()),
+        );
+        http_requests_registry.register(
+            "writer_tasks",
+            "Number of writer tasks of each type",
+            Box::new(writer_metrics.tasks.clone()),
+        );
+        http_requests_registry.register(
+            "writer_restarts",
+            "Number of times a failed writer task was restarted",
+            Box::new(writer_metrics.restarts.clone()),
+        );
+        http_requests_registry.register(
+            "writer_write_failures",
+            "Number of failed attempts to store a finished container",
+            Box::new(writer_metrics.write_failures.clone()),
+        );
+        http_requests_registry.register(
+            "ingested_bytes",
+            "Number of blob bytes added to containers",
+            Box::new(writer_metrics.bytes_in.clone()),
+        );
+        http_requests_registry.register(
+            "ingested_objects",
+            "Number of blobs added to containers",
+            Box::new(writer_metrics.objects_in.clone()),
+        );
+        http_requests_registry.register(
+            "unsynced_files",
+            "Number of container files written and not yet synced to disk",
+            Box::new(writer_metrics.unsynced_files.clone()),
+        );
+        http_requests_registry.register(
+            "container_flushes",
+            "Number of containers stored, by what triggered the flush",
+            Box::new(writer_metrics.flushes.clone()),
+        );
+        http_requests_registry.register(
+            "writer_cap_hits",
+            "Number of blobs that would have taken their writer over the type's writer_cap",
+            Box::new(writer_metrics.cap_hits.clone()),
+        );
+        http_requests_registry.register(
+            "blob_checksum_failures",
+            "Number of container files read that failed their checksum or a blob CRC",
+            Box::new(writer_metrics.checksum_failures.clone()),
+        );
+        http_requests_registry.register(
+            "container_flush_phase_seconds",
+            "Time spent in each phase of writing a container file",
+            Box::new(writer_metrics.flush_phase_seconds.0.clone()),
+        );
+        let rate_limited = Family::<RateLimitLabels, Counter>::default();
+        http_requests_registry.register(
+            "rate_limited",
+            "Number of uploads rejected by the rate limit",
+            Box::new(rate_limited.clone()),
+        );
+        let timed_out_requests = Counter::default();
+        http_requests_registry.register(
+            "timed_out_requests",
+            "Number of requests answered with 408 after request_timeout_secs",
+            Box::new(timed_out_requests.clone()),
+        );
+        let inflight_requests = Gauge::default();
+        http_requests_registry.register(
+            "inflight_requests",
+            "Number of requests being handled",
+            Box::new(inflight_requests.clone()),
+        );
+        let shed_requests = Counter::default();
+        http_requests_registry.register(
+            "shed_requests",
+            "Number of requests answered with 503 at max_inflight_requests",
+            Box::new(shed_requests.clone()),
+        );
+        let open_read_files = Gauge::default();
+        http_requests_registry.register(
+            "open_read_files",
+            "Number of container files open to serve blob reads",
+            Box::new(open_read_files.clone()),
+        );
         Self {
             senders: Arc::new(RwLock::new(senders)),
             http_requests_metrics,
             http_requests_registry: Arc::new(http_requests_registry),
+            auth_token: auth_token.map(Arc::from),
+            health,
+            retention_metrics,
+            compaction_metrics,
+            deleted_containers,
+            writer_metrics,
+            rate_limiter: rate_limiter.map(Arc::new),
+            rate_limited,
+            request_timeout,
+            timed_out_requests,
+            inflight: None,
+            inflight_requests,
+            shed_requests,
+            open_files: None,
+            open_file_timeout: Duration::from_millis(DEFAULT_OPEN_FILE_TIMEOUT_MS),
+            open_read_files,
+            access_log,
+            enable_admin: false,
+            success_body: SuccessBody::default(),
+            error_body: ErrorBody::default(),
+            throughput: None,
+            started: now_micros(),
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> std::io::Result<()> {
     let args: Args = Args::parse();
-    let config = Config::from_file(args.config)?;
+    if let Some(command) = args.command {
+        return run_command(command);
+    }
+    let config_path = args.config.expect("required by clap");
+    let mut config = Config::from_file(config_path.clone())?;
+    if let Some(worker_threads) = args.worker_threads {
+        config.server.worker_threads = Some(worker_threads.get());
+    }
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = config.server.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    runtime
+        .build()?
+        .block_on(run(config_path, config, args.verbose))
+}
+
+async fn run(config_path: String, config: Config, verbose: bool) -> io::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level()?)
+        .init();
+    if verbose {
+        info!(config = %config.to_toml()?, "effective config");
+    }
     let mut senders = HashMap::new();
+    let health = Arc::new(Health::new(config.server.max_queued));
+    let writer_metrics = WriterMetrics::default();
 
-    for type_id in config.types {
-        let (sender, mut receiver) = unbounded_channel();
-        let type_id = type_id.clone();
-        senders.insert(type_id.type_id, sender);
-        task::spawn(async move {
-            loop {
-                let creation_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_micros();
-                let mut container = Container::new(type_id.type_id);
-                for _ in 0..type_id.objects_in_container {
-                    let obj: PostData = receiver.recv().await.unwrap();
-                    container.push(obj.writer_id, obj.data.as_slice());
+    for type_id in config.types.iter() {
+        senders.insert(
+            type_id.type_id,
+            writer::spawn(type_id.clone(), health.clone(), writer_metrics.clone()),
+        );
+    }
+    let mut ctx = Context::new(
+        senders,
+        config.server.auth_token.clone(),
+        health,
+        writer_metrics,
+        config.server.rate_limit.as_ref().map(RateLimiter::new),
+        config.server.request_timeout_secs.map(Duration::from_secs),
+        config.server.access_log,
+    );
+    ctx.enable_admin = config.server.enable_admin;
+    ctx.success_body = config.server.success_body.unwrap_or_default();
+    ctx.error_body = config.server.error_body.unwrap_or_default();
+    ctx.inflight = config
+        .server
+        .max_inflight_requests
+        .map(|max| Arc::new(Semaphore::new(max)));
+    ctx.open_files = config
+        .server
+        .max_open_files
+        .map(|max| Arc::new(Semaphore::new(max)));
+    if let Some(open_file_timeout_ms) = config.server.open_file_timeout_ms {
+        ctx.open_file_timeout = Duration::from_millis(open_file_timeout_ms);
+    }
+    if let Some(stats_interval_secs) = config.server.stats_interval_secs {
+        ctx.throughput = Some(stats::spawn(
+            Duration::from_secs(stats_interval_secs),
+            ctx.senders.clone(),
+            ctx.writer_metrics.clone(),
+        ));
+    }
+    for type_id in config.types.iter() {
+        retention::spawn(type_id, ctx.retention_metrics.clone());
+        compaction::spawn(type_id, ctx.compaction_metrics.clone());
+    }
+    let hangup = signal(SignalKind::hangup())?;
+    task::spawn(reload_on_hangup(
+        hangup,
+        config_path,
+        config.clone(),
+        ctx.clone(),
+    ));
+    let exporter = config.server.otlp_endpoint.as_deref().map(|endpoint| {
+        otlp::Exporter::spawn(
+            endpoint,
+            config.server.otlp_interval_secs,
+            ctx.http_requests_registry.clone(),
+        )
+    });
+    let addr = config.server.socket_addr()?;
+    let incoming = listen(&config.server)?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let (draining, drain_started) = oneshot::channel();
+    let (close, closing) = watch::channel(());
+    let stop = async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        info!("shutting down, draining connections");
+        let _ = draining.send(());
+    };
+    let grace = Duration::from_secs(
+        config
+            .server
+            .shutdown_grace_secs
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+    );
+    let grace_over = async move {
+        if drain_started.await.is_err() {
+            // the server stopped on its own
+            pending::<()>().await;
+        }
+        sleep(grace).await;
+    };
+
+    let server_ctx = ctx.clone();
+    let server = async {
+        match (&config.server.cert_path, &config.server.key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let acceptor = tls::acceptor(cert_path, key_path)?;
+                let incoming = TlsListener::new(acceptor, incoming).filter(|conn| {
+                    if let Err(err) = conn {
+                        warn!(%err, "tls handshake failed");
+                    }
+                    ready(conn.is_ok())
+                });
+                info!(%addr, "listening with tls");
+                let incoming = accept::from_stream(incoming);
+                Ok(serve(incoming, server_ctx, &config.server, stop, closing).await)
+            }
+            _ => {
+                info!(%addr, "listening");
+                Ok::<_, io::Error>(serve(incoming, server_ctx, &config.server, stop, closing).await)
+            }
+        }
+    };
+    let result = tokio::select! {
+        result = server => result?,
+        _ = grace_over => {
+            warn!(?grace, "shutdown grace period over, closing the remaining connections");
+            let _ = close.send(());
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        error!(err = %e, "server error");
+    }
+
+    // no request is enqueuing anymore: store what the writers hold
+    let handles: Vec<(u32, WriterHandle)> = ctx
+        .senders
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(type_id, handle)| (*type_id, handle.clone()))
+        .collect();
+    for (type_id, handle) in handles {
+        match handle.shutdown().await {
+            Some(files) => info!(type_id, ?files, "writers stopped"),
+            None => warn!(type_id, "a writer was not running"),
+        }
+    }
+    if let Some(exporter) = exporter {
+        exporter.export().await;
+    }
+
+    Ok(())
+}
+
+fn run_command(command: Command) -> io::Result<()> {
+    match command {
+        Command::Repair { file } => {
+            if Container::repair(&file)? {
+                println!("{}: checksum rewritten", file.display());
+            } else {
+                println!(
+                    "{}: checksum rewritten, but the TOC sizes don't match the data section; \
+                     blobs may be misaligned",
+                    file.display()
+                );
+            }
+            Ok(())
+        }
+        Command::Cat {
+            file,
+            index,
+            writer,
+        } => {
+            if let Err(err) = cat(&file, index, writer) {
+                eprintln!("{}: {}", file.display(), err);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Reindex { dir } => {
+            let (manifest, skipped) = manifest::rebuild(&dir)?;
+            for (file, err) in skipped {
+                eprintln!("{}: skipped, {}", file, err);
+            }
+            println!(
+                "{}: {} containers indexed",
+                dir.join(manifest::MANIFEST_FILE).display(),
+                manifest.containers.len()
+            );
+            Ok(())
+        }
+        Command::CheckConfig { config } => {
+            let checked = Config::from_file(config.clone()).and_then(|checked| checked.log_level());
+            match checked {
+                Ok(_) => {
+                    println!("{}: OK", config);
+                    Ok(())
+                }
+                Err(err) => {
+                    eprintln!("{}: {}", config, err);
+                    std::process::exit(1);
                 }
-                let path = Path::new(type_id.root.as_str())
-                    .join(format!("type{}_{}.blob", type_id.type_id, creation_time));
-                println!("{}", path.to_str().unwrap());
-                let file = File::create(path).unwrap();
-                container.save_to_file(file).unwrap();
             }
-        });
+        }
     }
-    let ctx = Context::new(senders);
-    let addr = ([0, 0, 0, 0], config.server.port).into();
-    let service = make_service_fn(move |_| {
-        let ctx = ctx.clone();
-        async move {
-            Ok::<_, hyper::Error>(service_fn(move |_req| {
-                let ctx = ctx.clone();
-                handler(_req, ctx)
-            }))
+}
+
+/// Writes the data of the `index`th blob of `file`, counting only the blobs of
+/// `writer` if given, to stdout. Nothing is written unless the checksum verifies,
+/// so the rest of the container is read too.
+fn cat(file: &Path, index: usize, writer: Option<u32>) -> io::Result<()> {
+    let mut reader = ContainerReader::new(File::open(file)?)?;
+    let found = reader
+        .by_ref()
+        .filter(|blob| match (blob, writer) {
+            (Ok(blob), Some(writer)) => blob.writer_id == writer,
+            _ => true,
+        })
+        .nth(index)
+        .transpose()?;
+    reader.finish()?;
+    let blob = found.ok_or_else(|| {
+        let desc = match writer {
+            None => format!("no blob at index {}", index),
+            Some(writer) => format!("no blob at index {} of writer {}", index, writer),
+        };
+        io::Error::new(io::ErrorKind::InvalidInput, desc)
+    })?;
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&blob.data)?;
+    stdout.flush()
+}
+
+/// Re-reads the config file on every SIGHUP and applies the type changes: new types
+/// get a writer, changed ones are reconfigured in place so their buffered blobs are
+/// kept. Removed types, server settings, and the retention and compaction policies
+/// of existing types only take effect after a restart.
+async fn reload_on_hangup(mut hangup: Signal, path: String, mut current: Config, ctx: Context) {
+    while hangup.recv().await.is_some() {
+        let config = match Config::from_file(path.clone()) {
+            Ok(config) => config,
+            Err(err) => {
+                error!(%err, path, "config reload rejected, keeping the running config");
+                continue;
+            }
+        };
+        if config.server != current.server {
+            warn!("server settings changed, they take effect after a restart");
+        }
+        let mut senders = ctx.senders.write().unwrap();
+        for type_id in config.types.iter() {
+            let previous = current.types.iter().find(|t| t.type_id == type_id.type_id);
+            if previous == Some(type_id) {
+                continue;
+            }
+            match senders.get(&type_id.type_id) {
+                None => {
+                    info!(type_id = type_id.type_id, "type added");
+                    retention::spawn(type_id, ctx.retention_metrics.clone());
+                    compaction::spawn(type_id, ctx.compaction_metrics.clone());
+                    senders.insert(
+                        type_id.type_id,
+                        writer::spawn(
+                            type_id.clone(),
+                            ctx.health.clone(),
+                            ctx.writer_metrics.clone(),
+                        ),
+                    );
+                }
+                Some(handle) => {
+                    info!(type_id = type_id.type_id, "type changed");
+                    if previous.map(|previous| previous.writers_per_type)
+                        != Some(type_id.writers_per_type)
+                    {
+                        warn!(
+                            type_id = type_id.type_id,
+                            "writers_per_type changed, it takes effect after a restart"
+                        );
+                    }
+                    let sync =
+                        |type_id: &TypeConfig| (type_id.sync_policy, type_id.sync_interval_ms);
+                    if previous.map(sync) != Some(sync(type_id)) {
+                        warn!(
+                            type_id = type_id.type_id,
+                            "sync_policy changed, it takes effect after a restart"
+                        );
+                    }
+                    if previous.map(|previous| &previous.autoscale) != Some(&type_id.autoscale) {
+                        warn!(
+                            type_id = type_id.type_id,
+                            "autoscale changed, it takes effect after a restart"
+                        );
+                    }
+                    if previous.map(|previous| previous.wal) != Some(type_id.wal) {
+                        warn!(
+                            type_id = type_id.type_id,
+                            "wal changed, it takes effect after a restart"
+                        );
+                    }
+                    if let Err(err) = handle.reconfigure(type_id.clone()) {
+                        error!(%err, "type change rejected, keeping its running config");
+                    }
+                }
+            }
         }
+        for type_id in current.types.iter() {
+            if !config.types.iter().any(|t| t.type_id == type_id.type_id) {
+                warn!(
+                    type_id = type_id.type_id,
+                    "type removed from config, its writer keeps running until restart"
+                );
+            }
+        }
+        drop(senders);
+        current = config;
+    }
+}
+
+/// Binds the listening socket with the configured backlog and the TCP options for
+/// accepted connections.
+fn listen(config: &HttpConfig) -> io::Result<AddrIncoming> {
+    let addr = config.socket_addr()?;
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    let listener = socket.listen(config.tcp_backlog.unwrap_or(DEFAULT_TCP_BACKLOG))?;
+    let mut incoming = AddrIncoming::from_listener(listener).map_err(io::Error::other)?;
+    incoming.set_nodelay(config.tcp_nodelay);
+    incoming.set_keepalive(config.tcp_keepalive_secs.map(Duration::from_secs));
+    Ok(incoming)
+}
+
+/// Serves until `stop` resolves, then refuses new connections and waits for the
+/// requests in flight. Connections still open when `closing` changes are cut off.
+///
+/// The tower middleware stacked around `Handler` is shared by all connections, each
+/// of which adds its `ClientIp` to the requests and counts them: a connection is
+/// closed after `idle_connection_timeout_secs` without a request in flight, and after
+/// its `max_requests_per_connection`th response.
+async fn serve<I>(
+    incoming: I,
+    ctx: Context,
+    config: &HttpConfig,
+    stop: impl Future<Output = ()>,
+    closing: watch::Receiver<()>,
+) -> hyper::Result<()>
+where
+    I: Accept + Unpin,
+    I::Conn: AsyncRead + AsyncWrite + RemoteIp + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let incoming = IdleIncoming::new(
+        incoming,
+        config.idle_connection_timeout_secs.map(Duration::from_secs),
+    );
+    let max_requests = config.max_requests_per_connection;
+    let handler = ServiceBuilder::new()
+        .option_layer(
+            config
+                .max_concurrent_requests
+                .map(ConcurrencyLimitLayer::new),
+        )
+        .service(Handler { ctx });
+    let service = make_service_fn(move |conn: &IdleConn<I::Conn>| {
+        let client = ClientIp(conn.remote_ip());
+        let requests = conn.requests();
+        let mut served = 0;
+        let handler = ServiceBuilder::new()
+            .map_future(move |response| {
+                let in_flight = InFlight::new(requests.clone());
+                served += 1;
+                let last = max_requests.is_some_and(|max| served >= max);
+                async move {
+                    let mut response: Response<Body> = response.await?;
+                    drop(in_flight);
+                    if last {
+                        response
+                            .headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                    }
+                    Ok::<_, BoxError>(response)
+                }
+            })
+            .map_request(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(client);
+                req
+            })
+            .service(handler.clone());
+        ready(Ok::<_, hyper::Error>(handler))
     });
+    Server::builder(incoming)
+        .executor(ClosingExecutor { closing })
+        .serve(service)
+        .with_graceful_shutdown(stop)
+        .await
+}
+
+/// The address of the client of the connection a request came in on, added to the
+/// request extensions by `serve`.
+#[derive(Clone, Copy)]
+struct ClientIp(IpAddr);
 
-    let server = Server::bind(&addr).serve(service);
+/// The request handler as a `tower::Service`, so middleware can be stacked around it.
+#[derive(Clone)]
+struct Handler {
+    ctx: Context,
+}
 
-    println!("Listening {}", addr);
+impl Service<Request<Body>> for Handler {
+    type Response = Response<Body>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, hyper::Error>> + Send>>;
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
     }
 
-    Ok(())
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let client = req
+            .extensions()
+            .get::<ClientIp>()
+            .map_or(IpAddr::from([0, 0, 0, 0]), |client| client.0);
+        let error_body = error_body::negotiate(req.headers(), self.ctx.error_body);
+        let response = handle_logged(req, client, self.ctx.clone());
+        Box::pin(async move { Ok(error_body::render(response.await?, error_body)) })
+    }
+}
+
+/// Spawns the connection tasks of the server so they can be ended together; hyper
+/// only stops accepting on shutdown and leaves open connections running.
+#[derive(Clone)]
+struct ClosingExecutor {
+    closing: watch::Receiver<()>,
+}
+
+impl<F> hyper::rt::Executor<F> for ClosingExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    fn execute(&self, connection: F) {
+        let mut closing = self.closing.clone();
+        task::spawn(async move {
+            tokio::select! {
+                _ = connection => {}
+                _ = closing.changed() => {}
+            }
+        });
+    }
+}
+
+/// Runs the request, logging an access line for it when `access_log` is set. The
+/// body size counts the bytes of the request body that were read.
+async fn handle_logged(
+    req: Request<Body>,
+    client: IpAddr,
+    ctx: Context,
+) -> Result<Response<Body>, hyper::Error> {
+    if !ctx.access_log {
+        return handle_with_timeout(req, client, ctx).await;
+    }
+    let started = Instant::now();
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    let body_bytes = Arc::new(AtomicU64::new(0));
+    let counted = body_bytes.clone();
+    let req = req.map(|body| {
+        Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counted.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }))
+    });
+    let response = handle_with_timeout(req, client, ctx).await;
+    info!(
+        target: "access",
+        method = %method,
+        path,
+        client = %client,
+        status = response.as_ref().map_or(0, |response| response.status().as_u16()),
+        body_bytes = body_bytes.load(Ordering::Relaxed),
+        latency_ms = started.elapsed().as_millis() as u64,
+        "request"
+    );
+    response
+}
+
+/// Runs `handler` within `request_timeout`, so a client trickling its request can't
+/// hold on to a handler, and to the upload file it receives into, indefinitely.
+async fn handle_with_timeout(
+    req: Request<Body>,
+    client: IpAddr,
+    ctx: Context,
+) -> Result<Response<Body>, hyper::Error> {
+    let request_timeout = match ctx.request_timeout {
+        None => return handler(req, client, ctx).await,
+        Some(request_timeout) => request_timeout,
+    };
+    let method = match *req.method() {
+        Method::POST => HttpMethod::POST,
+        Method::DELETE => HttpMethod::DELETE,
+        _ => HttpMethod::GET,
+    };
+    let path = req.uri().path().to_string();
+    match timeout(request_timeout, handler(req, client, ctx.clone())).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(path, "request timed out");
+            ctx.timed_out_requests.inc();
+            ctx.http_requests_metrics
+                .current()
+                .get_or_create(&HttpLabels {
+                    method,
+                    status: HttpStatus::Status4xx,
+                    success: No,
+                    type_id: 0,
+                    writer_id: 0,
+                    reason: 56,
+                })
+                .inc();
+            Ok(ApiError::new(56, "request timed out").into_response(StatusCode::REQUEST_TIMEOUT))
+        }
+    }
 }
 
-async fn handler(req: Request<Body>, ctx: Context) -> Result<Response<Body>, hyper::Error> {
-    match req.method() {
-        &Method::POST => {
+#[instrument(skip_all, fields(method = %req.method(), path = %req.uri().path()))]
+async fn handler(
+    req: Request<Body>,
+    client: IpAddr,
+    ctx: Context,
+) -> Result<Response<Body>, hyper::Error> {
+    let _inflight = match Inflight::enter(&ctx) {
+        Some(inflight) => inflight,
+        None => return Ok(shed(req.method(), &ctx)),
+    };
+    match *req.method() {
+        Method::POST => {
+            if !is_authorized(&req, ctx.auth_token.as_deref()) {
+                return Ok(unauthorized(HttpMethod::POST, &ctx));
+            }
+            if req.uri().path().starts_with("/admin/") && !ctx.enable_admin {
+                return Ok(ApiError::new(60, "admin endpoints are disabled")
+                    .into_response(StatusCode::FORBIDDEN));
+            }
+            if let Some(type_id) = parse_admin_flush_path(req.uri().path()) {
+                return Ok(admin_flush(type_id, ctx).await);
+            }
+            if req.uri().path() == "/admin/metrics/reset" {
+                return Ok(admin_reset_metrics(&ctx));
+            }
+            if req.uri().path() == "/admin/types" {
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                return Ok(admin_register_type(&body, ctx));
+            }
             let (type_id, writer_id) = match parse_path(req.uri().path()) {
-                None => {
+                Err(err) => {
                     ctx.http_requests_metrics
+                        .current()
                         .get_or_create(&HttpLabels {
                             method: HttpMethod::POST,
                             status: HttpStatus::Status2xx,
                             success: No,
                             type_id: 0,
                             writer_id: 0,
+                            reason: err.reason(),
                         })
                         .inc();
-                    return Ok(Response::new(Body::from(
-                        r#"{ "state": -1,"reason"=41,desc="invalid path, need /type_id/N/writer_id/K" }"#
-                            .to_string(),
-                    )));
+                    return Ok(
+                        ApiError::new(err.reason(), err.desc()).into_response(StatusCode::OK)
+                    );
                 }
-                Some(type_id) => type_id,
+                Ok(type_id) => type_id,
             };
 
-            if writer_id >= WRITER_COUNT {
+            let sender = ctx.senders.read().unwrap().get(&type_id).cloned();
+            let sender = match sender {
+                None => {
+                    ctx.http_requests_metrics
+                        .current()
+                        .get_or_create(&HttpLabels {
+                            method: HttpMethod::POST,
+                            status: HttpStatus::Status2xx,
+                            success: No,
+                            type_id,
+                            writer_id,
+                            reason: 43,
+                        })
+                        .inc();
+                    return Ok(
+                        ApiError::new(43, "invalid type_id value").into_response(StatusCode::OK)
+                    );
+                }
+                Some(s) => s,
+            };
+            if writer_id >= sender.writer_count() {
                 ctx.http_requests_metrics
+                    .current()
                     .get_or_create(&HttpLabels {
                         method: HttpMethod::POST,
                         status: HttpStatus::Status2xx,
                         success: No,
-                        type_id: 0,
-                        writer_id: 0,
+                        type_id,
+                        writer_id,
+                        reason: 42,
                     })
                     .inc();
-                return Ok(Response::new(Body::from(
-                    r#"{ "state": -1,"reason"=42,desc="invalid  writer_id value" }"#.to_string(),
-                )));
+                return Ok(
+                    ApiError::new(42, "invalid writer_id value").into_response(StatusCode::OK)
+                );
             }
-            let whole_body = hyper::body::to_bytes(req.into_body()).await?.to_vec();
-            let senders = ctx.senders.read().unwrap();
-            let sender = match senders.get(&type_id) {
-                None => {
+            let content_type = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map_or(ContentType::None, ContentType::from_mime);
+            let meta = match req.headers().get("x-blob-meta").map(|value| {
+                value
+                    .to_str()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+                    .and_then(meta::from_urlencoded)
+            }) {
+                None => BlobMeta::new(),
+                Some(Ok(meta)) => meta,
+                Some(Err(_)) => {
                     ctx.http_requests_metrics
+                        .current()
                         .get_or_create(&HttpLabels {
                             method: HttpMethod::POST,
                             status: HttpStatus::Status2xx,
                             success: No,
-                            type_id: 0,
+                            type_id,
                             writer_id,
+                            reason: 47,
                         })
                         .inc();
-                    return Ok(Response::new(Body::from(
-                        r#"{ "state": -1,"reason"=43,desc="invalid type_id value" }"#.to_string(),
-                    )));
+                    return Ok(ApiError::new(47, "invalid X-Blob-Meta header")
+                        .into_response(StatusCode::OK));
                 }
-                Some(s) => s.clone(),
             };
+            // microseconds since the epoch; the write time is used instead if it is
+            // missing or unparsable
+            let event_time = req
+                .headers()
+                .get("x-event-time")
+                .and_then(|value| value.to_str().ok()?.trim().parse().ok());
+            if let Some(rate_limiter) = &ctx.rate_limiter {
+                let key = rate_limiter.key(type_id, writer_id, client);
+                if let Err(retry_after) = rate_limiter.acquire(key) {
+                    return Ok(rate_limited(key, retry_after, type_id, writer_id, &ctx));
+                }
+            }
 
-            sender.send(PostData::new(writer_id, whole_body)).unwrap();
+            let is_batch = req
+                .headers()
+                .get("x-blob-batch")
+                .is_some_and(|value| value == "1");
+            if !is_batch {
+                let upload =
+                    match UploadFile::receive(&sender.root(), type_id, req.into_body()).await {
+                        Ok(upload) => upload,
+                        Err(err) => return Ok(upload_failed(err, type_id, writer_id, &ctx)),
+                    };
+                let (stored, result) = oneshot::channel();
+                debug!(
+                    type_id,
+                    writer_id,
+                    size = upload.size(),
+                    "uploaded blob enqueued"
+                );
+                ctx.health.enqueued(type_id);
+                let sent = sender.send(
+                    PostData::uploaded(writer_id, content_type, meta, upload, stored)
+                        .with_event_time(event_time),
+                );
+                let sequence = match sent {
+                    Ok(sequence) => sequence,
+                    Err(_) => return Ok(writer_gone(type_id, writer_id, &ctx)),
+                };
+                return Ok(match result.await {
+                    Ok(Ok(())) => {
+                        ctx.http_requests_metrics
+                            .current()
+                            .get_or_create(&HttpLabels {
+                                method: HttpMethod::POST,
+                                status: HttpStatus::Status2xx,
+                                success: Yes,
+                                type_id,
+                                writer_id,
+                                reason: 0,
+                            })
+                            .inc();
+                        accepted(
+                            Uploaded {
+                                type_id,
+                                writer_id,
+                                sequence,
+                            },
+                            &ctx,
+                        )
+                    }
+                    Ok(Err(err)) if err.kind() == io::ErrorKind::QuotaExceeded => {
+                        writer_capped(type_id, writer_id, &ctx)
+                    }
+                    Ok(Err(err)) => upload_failed(err, type_id, writer_id, &ctx),
+                    Err(_) => writer_gone(type_id, writer_id, &ctx),
+                });
+            }
+
+            // batches are buffered as a whole since they need to be split
+            let whole_body = hyper::body::to_bytes(req.into_body()).await?.to_vec();
+            let blobs = match batch::split(&whole_body) {
+                Ok(blobs) => blobs,
+                Err(err) => {
+                    ctx.http_requests_metrics
+                        .current()
+                        .get_or_create(&HttpLabels {
+                            method: HttpMethod::POST,
+                            status: HttpStatus::Status4xx,
+                            success: No,
+                            type_id,
+                            writer_id,
+                            reason: 48,
+                        })
+                        .inc();
+                    return Ok(ApiError::new(48, format!("malformed batch: {}", err))
+                        .into_response(StatusCode::BAD_REQUEST));
+                }
+            };
+            let count = blobs.len();
+            let mut logged = Vec::new();
+            let posts = blobs
+                .into_iter()
+                .map(|blob| {
+                    debug!(type_id, writer_id, size = blob.len(), "blob enqueued");
+                    ctx.health.enqueued(type_id);
+                    let post = PostData::new(writer_id, content_type, meta.clone(), blob)
+                        .with_event_time(event_time);
+                    if !sender.logs_ahead() {
+                        return post;
+                    }
+                    let (ack, result) = oneshot::channel();
+                    logged.push(result);
+                    post.acknowledged(ack)
+                })
+                .collect();
+            let sequences = match sender.send_all(posts) {
+                Ok(sequences) => sequences,
+                Err(_) => return Ok(writer_gone(type_id, writer_id, &ctx)),
+            };
+            for result in logged {
+                let err = match result.await {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(err)) if err.kind() == io::ErrorKind::QuotaExceeded => {
+                        return Ok(writer_capped(type_id, writer_id, &ctx));
+                    }
+                    Ok(Err(err)) => err,
+                    Err(_) => return Ok(writer_gone(type_id, writer_id, &ctx)),
+                };
+                ctx.http_requests_metrics
+                    .current()
+                    .get_or_create(&HttpLabels {
+                        method: HttpMethod::POST,
+                        status: HttpStatus::Status5xx,
+                        success: No,
+                        type_id,
+                        writer_id,
+                        reason: 59,
+                    })
+                    .inc();
+                return Ok(
+                    ApiError::new(59, format!("write-ahead log failed: {}", err))
+                        .into_response(StatusCode::INTERNAL_SERVER_ERROR),
+                );
+            }
             ctx.http_requests_metrics
+                .current()
                 .get_or_create(&HttpLabels {
                     method: HttpMethod::POST,
                     status: HttpStatus::Status2xx,
                     success: Yes,
                     type_id,
                     writer_id,
+                    reason: 0,
                 })
                 .inc();
-            Ok(Response::new(Body::from(r#"{ "state": 0 }"#.to_string())))
+            Ok(accepted(
+                BatchUploaded {
+                    count,
+                    type_id,
+                    writer_id,
+                    first_sequence: sequences.first().copied(),
+                },
+                &ctx,
+            ))
         }
-        &Method::GET => {
-            let mut buffer = vec![];
-            encode(&mut buffer, &ctx.http_requests_registry).unwrap();
-            Ok(Response::new(Body::from(buffer)))
+        Method::DELETE => {
+            if !is_authorized(&req, ctx.auth_token.as_deref()) {
+                return Ok(unauthorized(HttpMethod::DELETE, &ctx));
+            }
+            Ok(delete_container(req.uri().path(), ctx).await)
+        }
+        Method::GET => {
+            if let Some((type_id, name, index)) = parse_blob_path(req.uri().path()) {
+                if !is_authorized(&req, ctx.auth_token.as_deref()) {
+                    return Ok(unauthorized(HttpMethod::GET, &ctx));
+                }
+                let range = req
+                    .headers()
+                    .get(RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                let gzip = compression::accepts_gzip(req.headers());
+                return Ok(read_blob(type_id, name.to_string(), index, range, gzip, ctx).await);
+            }
+            if let Some((type_id, writer_id, sequence)) = parse_sequence_path(req.uri().path()) {
+                if !is_authorized(&req, ctx.auth_token.as_deref()) {
+                    return Ok(unauthorized(HttpMethod::GET, &ctx));
+                }
+                return Ok(blob_receipt(type_id, writer_id, sequence, &ctx));
+            }
+            match req.uri().path() {
+                "/healthz" => Ok(Response::new(Body::from("ok"))),
+                "/stats" => Ok(throughput_stats(&ctx)),
+                "/ready" => Ok(readiness(&ctx)),
+                "/version" => Ok(version(&ctx)),
+                "/readyz" => {
+                    if ctx.health.is_ready() {
+                        return Ok(Response::new(Body::from("ok")));
+                    }
+                    let mut not_ready = Response::new(Body::from("not ready"));
+                    *not_ready.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    Ok(not_ready)
+                }
+                _ => {
+                    let mut buffer = vec![];
+                    encode(&mut buffer, &ctx.http_requests_registry).unwrap();
+                    if !compression::accepts_gzip(req.headers()) {
+                        return Ok(Response::new(Body::from(buffer)));
+                    }
+                    Ok(Response::builder()
+                        .header(CONTENT_ENCODING, "gzip")
+                        .header(VARY, "accept-encoding")
+                        .body(Body::from(compression::gzip(&buffer).unwrap()))
+                        .unwrap())
+                }
+            }
         }
         _ => {
             ctx.http_requests_metrics
+                .current()
                 .get_or_create(&HttpLabels {
                     method: HttpMethod::GET,
                     status: HttpStatus::Status4xx,
                     success: No,
                     type_id: 0,
                     writer_id: 0,
+                    reason: 0,
                 })
                 .inc();
             let mut not_found = Response::default();
@@ -203,21 +1193,846 @@ async fn handler(req: Request<Body>, ctx: Context) -> Result<Response<Body>, hyp
     }
 }
 
-fn parse_path(path: &str) -> Option<(u32, u32)> {
-    let parts: Vec<&str> = path.split('/').collect();
-    if parts.len() < 5
-        || parts[1].to_lowercase() != "type_id"
-        || parts[3].to_lowercase() != "writer_id"
-    {
-        return None;
+/// Asks the writers for `type_id` to write out their current containers and reports
+/// the files they went to; `file` is the first of them, for types with one writer.
+async fn admin_flush(type_id: u32, ctx: Context) -> Response<Body> {
+    let handle = ctx.senders.read().unwrap().get(&type_id).cloned();
+    let handle = match handle {
+        None => return ApiError::new(43, "invalid type_id value").into_response(StatusCode::OK),
+        Some(handle) => handle,
+    };
+    match handle.flush().await {
+        Some(files) => Response::new(Body::from(format!(
+            r#"{{ "state": 0, "file": {}, "files": {} }}"#,
+            serde_json::to_string(&files.first()).unwrap(),
+            serde_json::to_string(&files).unwrap()
+        ))),
+        None => writer_unavailable(),
+    }
+}
+
+/// Zeroes the request counters, for benchmark runs; only with `enable_admin`, as a
+/// counter going back breaks `rate()` over it.
+fn admin_reset_metrics(ctx: &Context) -> Response<Body> {
+    ctx.http_requests_metrics.reset();
+    info!("request metrics reset");
+    Response::new(Body::from(r#"{ "state": 0 }"#.to_string()))
+}
+
+/// Registers a new type from a JSON encoded `TypeConfig` and starts its writer, so
+/// producers can be onboarded without a restart.
+fn admin_register_type(body: &[u8], ctx: Context) -> Response<Body> {
+    let type_config = match serde_json::from_slice::<TypeConfig>(body) {
+        Ok(type_config) => type_config,
+        Err(err) => {
+            return ApiError::new(45, format!("invalid type config: {}", err))
+                .into_response(StatusCode::OK)
+        }
+    };
+    if let Err(err) = type_config.check() {
+        return ApiError::new(45, format!("invalid type config: {}", err))
+            .into_response(StatusCode::OK);
+    }
+    let mut senders = ctx.senders.write().unwrap();
+    if senders.contains_key(&type_config.type_id) {
+        return ApiError::new(46, "type_id already registered").into_response(StatusCode::OK);
+    }
+    info!(type_id = type_config.type_id, root = %type_config.root, "type registered");
+    retention::spawn(&type_config, ctx.retention_metrics.clone());
+    compaction::spawn(&type_config, ctx.compaction_metrics.clone());
+    senders.insert(
+        type_config.type_id,
+        writer::spawn(type_config, ctx.health.clone(), ctx.writer_metrics.clone()),
+    );
+    Response::new(Body::from(r#"{ "state": 0 }"#.to_string()))
+}
+
+/// Removes a container file of a type, e.g. for erasure requests. Only names ending
+/// in `.blob` that resolve to a file inside the type's root are accepted.
+async fn delete_container(path: &str, ctx: Context) -> Response<Body> {
+    let count = |type_id, status, success, reason| {
+        ctx.http_requests_metrics
+            .current()
+            .get_or_create(&HttpLabels {
+                method: HttpMethod::DELETE,
+                status,
+                success,
+                type_id,
+                writer_id: 0,
+                reason,
+            })
+            .inc();
+    };
+    let (type_id, name) = match parse_container_path(path) {
+        Some((type_id, name)) if name.ends_with(".blob") => (type_id, name.to_string()),
+        _ => {
+            count(0, HttpStatus::Status4xx, No, 50);
+            return ApiError::new(50, "invalid path, need /type_id/N/container/NAME.blob")
+                .into_response(StatusCode::BAD_REQUEST);
+        }
+    };
+    let root = ctx
+        .senders
+        .read()
+        .unwrap()
+        .get(&type_id)
+        .map(|handle| (handle.root(), handle.template()));
+    let (root, template) = match root {
+        None => {
+            count(type_id, HttpStatus::Status2xx, No, 43);
+            return ApiError::new(43, "invalid type_id value").into_response(StatusCode::OK);
+        }
+        Some(files) => files,
+    };
+    let deleted = task::spawn_blocking(move || {
+        let file = paths::resolve_container(&root, &name, type_id, &template)?;
+        std::fs::remove_file(&file)?;
+        if let Err(err) = manifest::remove(&file) {
+            warn!(%err, path = %file.display(), "manifest update failed");
+        }
+        if let Err(err) = sidecar::remove(&file) {
+            warn!(%err, path = %file.display(), "removing the sidecar failed");
+        }
+        Ok::<_, io::Error>(file)
+    })
+    .await
+    .unwrap_or_else(|err| Err(io::Error::other(err)));
+    match deleted {
+        Ok(file) => {
+            info!(type_id, path = %file.display(), "container deleted");
+            count(type_id, HttpStatus::Status2xx, Yes, 0);
+            ctx.deleted_containers
+                .get_or_create(&TypeLabels { type_id })
+                .inc();
+            Response::new(Body::from(r#"{ "state": 0 }"#.to_string()))
+        }
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            warn!(type_id, %err, "container deletion rejected");
+            count(type_id, HttpStatus::Status4xx, No, 50);
+            ApiError::new(50, "invalid path, need /type_id/N/container/NAME.blob")
+                .into_response(StatusCode::BAD_REQUEST)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            count(type_id, HttpStatus::Status4xx, No, 51);
+            ApiError::new(51, "container not found").into_response(StatusCode::NOT_FOUND)
+        }
+        Err(err) => {
+            error!(type_id, %err, "container deletion failed");
+            count(type_id, HttpStatus::Status5xx, No, 52);
+            ApiError::new(52, format!("deletion failed: {}", err))
+                .into_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Reports where the blob a POST got `sequence` for went: its container and index
+/// once flushed, 202 while the writer still buffers it.
+fn blob_receipt(type_id: u32, writer_id: u32, sequence: u64, ctx: &Context) -> Response<Body> {
+    let count = |status, success, reason| {
+        ctx.http_requests_metrics
+            .current()
+            .get_or_create(&HttpLabels {
+                method: HttpMethod::GET,
+                status,
+                success,
+                type_id,
+                writer_id,
+                reason,
+            })
+            .inc();
+    };
+    let lookup = ctx
+        .senders
+        .read()
+        .unwrap()
+        .get(&type_id)
+        .map(|handle| handle.lookup(writer_id, sequence));
+    match lookup {
+        None => {
+            count(HttpStatus::Status2xx, No, 43);
+            ApiError::new(43, "invalid type_id value").into_response(StatusCode::OK)
+        }
+        Some(Lookup::Flushed(receipt)) => {
+            count(HttpStatus::Status2xx, Yes, 0);
+            let body = BlobReceipt {
+                state: 0,
+                container: Some(&receipt.name),
+                file: Some(&receipt.location),
+                index: Some(receipt.index),
+            };
+            Response::new(Body::from(serde_json::to_string(&body).unwrap()))
+        }
+        Some(Lookup::Pending) => {
+            count(HttpStatus::Status2xx, Yes, 0);
+            let body = BlobReceipt {
+                state: 0,
+                container: None,
+                file: None,
+                index: None,
+            };
+            let mut accepted = Response::new(Body::from(serde_json::to_string(&body).unwrap()));
+            *accepted.status_mut() = StatusCode::ACCEPTED;
+            accepted
+        }
+        Some(Lookup::Unknown) => {
+            count(HttpStatus::Status4xx, No, 57);
+            ApiError::new(57, "unknown sequence, not assigned or no longer tracked")
+                .into_response(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+/// Sends the data of the blob at `index` in a container, or the part of it asked for
+/// by a `Range` header, straight from the file. The container's checksum isn't
+/// verified, that would mean reading all of it. With `gzip`, whole blobs of a text
+/// based content type are compressed on the fly.
+async fn read_blob(
+    type_id: u32,
+    name: String,
+    index: usize,
+    range: Option<String>,
+    gzip: bool,
+    ctx: Context,
+) -> Response<Body> {
+    let count = |status, success, reason| {
+        ctx.http_requests_metrics
+            .current()
+            .get_or_create(&HttpLabels {
+                method: HttpMethod::GET,
+                status,
+                success,
+                type_id,
+                writer_id: 0,
+                reason,
+            })
+            .inc();
+    };
+    let root = ctx
+        .senders
+        .read()
+        .unwrap()
+        .get(&type_id)
+        .map(|handle| (handle.root(), handle.template()));
+    let (root, template) = match root {
+        None => {
+            count(HttpStatus::Status2xx, No, 43);
+            return ApiError::new(43, "invalid type_id value").into_response(StatusCode::OK);
+        }
+        Some(files) => files,
+    };
+    let open = match OpenFile::acquire(&ctx).await {
+        Some(open) => open,
+        None => {
+            warn!(type_id, "max_open_files reached, read not served");
+            count(HttpStatus::Status5xx, No, 63);
+            let mut unavailable = ApiError::new(63, "too many open files")
+                .into_response(StatusCode::SERVICE_UNAVAILABLE);
+            unavailable
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(1));
+            return unavailable;
+        }
+    };
+    let located = task::spawn_blocking(move || {
+        if !name.ends_with(".blob") {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let path = paths::resolve_container(&root, &name, type_id, &template)?;
+        Ok((path.clone(), Container::locate_blob(&path, index)?))
+    })
+    .await
+    .unwrap_or_else(|err| Err(io::Error::other(err)));
+    let (path, location) = match located {
+        Ok((path, Some(location))) => (path, location),
+        Ok((_, None)) => {
+            count(HttpStatus::Status4xx, No, 53);
+            return ApiError::new(53, "blob not found").into_response(StatusCode::NOT_FOUND);
+        }
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            count(HttpStatus::Status4xx, No, 50);
+            return ApiError::new(
+                50,
+                "invalid path, need /type_id/N/container/NAME.blob/blob/I",
+            )
+            .into_response(StatusCode::BAD_REQUEST);
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            count(HttpStatus::Status4xx, No, 51);
+            return ApiError::new(51, "container not found").into_response(StatusCode::NOT_FOUND);
+        }
+        Err(err) => {
+            error!(type_id, %err, "reading container failed");
+            count(HttpStatus::Status5xx, No, 54);
+            return ApiError::new(54, format!("reading container failed: {}", err))
+                .into_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let size = location.size as u64;
+    let (status, start, len) = match range::resolve(range.as_deref(), size) {
+        ByteRange::Full => (StatusCode::OK, 0, size),
+        ByteRange::Partial { start, end } => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        ByteRange::Unsatisfiable => {
+            count(HttpStatus::Status4xx, No, 64);
+            let mut unsatisfiable = ApiError::new(64, "range not satisfiable")
+                .into_response(StatusCode::RANGE_NOT_SATISFIABLE);
+            unsatisfiable.headers_mut().insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", size)).unwrap(),
+            );
+            return unsatisfiable;
+        }
+    };
+    if location.is_compressed() {
+        return read_compressed_blob(
+            type_id, &path, location, status, start, len, gzip, open, count,
+        )
+        .await;
     }
-    let type_id = match parts[2].parse::<u32>() {
-        Ok(type_id) => type_id,
-        Err(_) => return None,
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            error!(type_id, %err, "reading container failed");
+            count(HttpStatus::Status5xx, No, 54);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap();
+        }
     };
-    let writer_id = match parts[4].parse::<u32>() {
-        Ok(writer_id) => writer_id,
-        Err(_) => return None,
+    if let Err(err) = file.seek(SeekFrom::Start(location.offset + start)).await {
+        error!(type_id, %err, "reading container failed");
+        count(HttpStatus::Status5xx, No, 54);
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap();
+    }
+    count(HttpStatus::Status2xx, Yes, 0);
+    let mut response = Response::builder()
+        .status(status)
+        .header(
+            CONTENT_TYPE,
+            location
+                .content_type
+                .mime()
+                .unwrap_or("application/octet-stream"),
+        )
+        .header(ACCEPT_RANGES, "bytes");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, start + len - 1, size),
+        );
+    }
+    if location.content_type.is_compressible() {
+        response = response.header(VARY, "accept-encoding");
+    }
+    let chunks = file_chunks(file, len, open);
+    if gzip && status == StatusCode::OK && location.content_type.is_compressible() {
+        return response
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::wrap_stream(compression::gzip_stream(chunks)))
+            .unwrap();
+    }
+    response
+        .header(CONTENT_LENGTH, len)
+        .body(Body::wrap_stream(chunks))
+        .unwrap()
+}
+
+/// Serves the bytes `start..start + len` of a compressed blob: its stored data is read
+/// and inflated whole, so ranges and gzip work on the decompressed data.
+#[allow(clippy::too_many_arguments)]
+async fn read_compressed_blob(
+    type_id: u32,
+    path: &Path,
+    location: BlobLocation,
+    status: StatusCode,
+    start: u64,
+    len: u64,
+    gzip: bool,
+    open: OpenFile,
+    count: impl Fn(HttpStatus, Success, u32),
+) -> Response<Body> {
+    let path = path.to_path_buf();
+    let content_type = location.content_type;
+    let decoded = task::spawn_blocking(move || {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut stored = vec![0; location.stored_size as usize];
+        file.read_exact(&mut stored)?;
+        drop(open);
+        location.decode(&stored)
+    })
+    .await
+    .unwrap_or_else(|err| Err(io::Error::other(err)));
+    let data = match decoded {
+        Ok(data) => data,
+        Err(err) => {
+            error!(type_id, %err, "reading container failed");
+            count(HttpStatus::Status5xx, No, 54);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap();
+        }
     };
-    Some((type_id, writer_id))
+    count(HttpStatus::Status2xx, Yes, 0);
+    let mut response = Response::builder()
+        .status(status)
+        .header(
+            CONTENT_TYPE,
+            content_type.mime().unwrap_or("application/octet-stream"),
+        )
+        .header(ACCEPT_RANGES, "bytes");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, start + len - 1, data.len()),
+        );
+    }
+    if content_type.is_compressible() {
+        response = response.header(VARY, "accept-encoding");
+    }
+    let body = &data[start as usize..(start + len) as usize];
+    if gzip && status == StatusCode::OK && content_type.is_compressible() {
+        if let Ok(gzipped) = compression::gzip(body) {
+            return response
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from(gzipped))
+                .unwrap();
+        }
+    }
+    response
+        .header(CONTENT_LENGTH, len)
+        .body(Body::from(body.to_vec()))
+        .unwrap()
+}
+
+/// Streams the next `len` bytes of `file`, releasing `open` once the stream is done
+/// with the file.
+fn file_chunks(
+    file: tokio::fs::File,
+    len: u64,
+    open: OpenFile,
+) -> impl Stream<Item = io::Result<Vec<u8>>> + Send + 'static {
+    unfold(
+        (file, len, open),
+        |(mut file, remaining, open)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buf = vec![0; remaining.min(READ_CHUNK_SIZE) as usize];
+            match file.read(&mut buf).await {
+                Ok(0) => Some((
+                    Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    (file, 0, open),
+                )),
+                Ok(read) => {
+                    buf.truncate(read);
+                    Some((Ok(buf), (file, remaining - read as u64, open)))
+                }
+                Err(err) => Some((Err(err), (file, 0, open))),
+            }
+        },
+    )
+}
+
+/// The ingest throughput per type over the last stats interval, 404 unless the
+/// server runs in benchmark mode.
+fn throughput_stats(ctx: &Context) -> Response<Body> {
+    match &ctx.throughput {
+        None => {
+            let mut not_found = Response::new(Body::from("stats_interval_secs is not set"));
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            not_found
+        }
+        Some(throughput) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&serde_json::json!({ "types": throughput.rates() })).unwrap(),
+            ))
+            .unwrap(),
+    }
+}
+
+/// Readiness with the queue of every type, so operators can see which one is hot.
+fn readiness(ctx: &Context) -> Response<Body> {
+    let ready = ctx.health.is_ready();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::json!({ "ready": ready, "types": ctx.health.queue_depths() });
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&body).unwrap()))
+        .unwrap()
+}
+
+/// What this server is: the build, the container format version it writes and how
+/// long it has been running.
+fn version(ctx: &Context) -> Response<Body> {
+    let now = now_micros();
+    let body = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("GIT_HASH"),
+        "format_version": storage::VERSION,
+        "started": ctx.started,
+        "uptime_secs": now.saturating_sub(ctx.started) / 1_000_000,
+    });
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&body).unwrap()))
+        .unwrap()
+}
+
+fn unauthorized(method: HttpMethod, ctx: &Context) -> Response<Body> {
+    ctx.http_requests_metrics
+        .current()
+        .get_or_create(&HttpLabels {
+            method,
+            status: HttpStatus::Status4xx,
+            success: No,
+            type_id: 0,
+            writer_id: 0,
+            reason: 40,
+        })
+        .inc();
+    ApiError::new(40, "missing or invalid bearer token").into_response(StatusCode::UNAUTHORIZED)
+}
+
+/// A container file counted in `open_read_files` until dropped, holding one of the
+/// `max_open_files` permits if there is a limit.
+struct OpenFile {
+    _permit: Option<OwnedSemaphorePermit>,
+    gauge: Gauge,
+}
+
+impl OpenFile {
+    /// Waits up to `open_file_timeout` for a permit, `None` if none was released.
+    async fn acquire(ctx: &Context) -> Option<Self> {
+        let permit = match &ctx.open_files {
+            None => None,
+            Some(open_files) => Some(
+                timeout(ctx.open_file_timeout, open_files.clone().acquire_owned())
+                    .await
+                    .ok()?
+                    .ok()?,
+            ),
+        };
+        ctx.open_read_files.inc();
+        Some(Self {
+            _permit: permit,
+            gauge: ctx.open_read_files.clone(),
+        })
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// A request counted in `inflight_requests` until dropped, holding one of the
+/// `max_inflight_requests` permits if there is a limit.
+struct Inflight {
+    _permit: Option<OwnedSemaphorePermit>,
+    gauge: Gauge,
+}
+
+impl Inflight {
+    /// `None` when all permits are taken and the request is to be shed.
+    fn enter(ctx: &Context) -> Option<Self> {
+        let permit = match &ctx.inflight {
+            None => None,
+            Some(inflight) => Some(inflight.clone().try_acquire_owned().ok()?),
+        };
+        ctx.inflight_requests.inc();
+        Some(Self {
+            _permit: permit,
+            gauge: ctx.inflight_requests.clone(),
+        })
+    }
+}
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+fn shed(method: &Method, ctx: &Context) -> Response<Body> {
+    debug!("max_inflight_requests reached, shedding the request");
+    ctx.shed_requests.inc();
+    ctx.http_requests_metrics
+        .current()
+        .get_or_create(&HttpLabels {
+            method: match *method {
+                Method::POST => HttpMethod::POST,
+                Method::DELETE => HttpMethod::DELETE,
+                _ => HttpMethod::GET,
+            },
+            status: HttpStatus::Status5xx,
+            success: No,
+            type_id: 0,
+            writer_id: 0,
+            reason: 62,
+        })
+        .inc();
+    let mut shed = ApiError::new(62, "too many requests in flight")
+        .into_response(StatusCode::SERVICE_UNAVAILABLE);
+    shed.headers_mut().insert(RETRY_AFTER, HeaderValue::from(1));
+    shed
+}
+
+fn rate_limited(
+    key: LimitKey,
+    retry_after: Duration,
+    type_id: u32,
+    writer_id: u32,
+    ctx: &Context,
+) -> Response<Body> {
+    debug!(%key, ?retry_after, "upload rate limited");
+    ctx.http_requests_metrics
+        .current()
+        .get_or_create(&HttpLabels {
+            method: HttpMethod::POST,
+            status: HttpStatus::Status4xx,
+            success: No,
+            type_id,
+            writer_id,
+            reason: 55,
+        })
+        .inc();
+    ctx.rate_limited
+        .get_or_create(&RateLimitLabels {
+            key: key.to_string(),
+        })
+        .inc();
+    let mut limited =
+        ApiError::new(55, "rate limit exceeded").into_response(StatusCode::TOO_MANY_REQUESTS);
+    limited.headers_mut().insert(
+        RETRY_AFTER,
+        HeaderValue::from(retry_after.as_secs_f64().ceil().max(1.0) as u64),
+    );
+    limited
+}
+
+/// The body answering an accepted upload; `details` is left out with
+/// `success_body = "minimal"`.
+#[derive(Serialize)]
+struct Accepted<T> {
+    state: i32,
+    #[serde(flatten)]
+    details: Option<T>,
+}
+
+#[derive(Serialize)]
+struct Uploaded {
+    type_id: u32,
+    writer_id: u32,
+    /// assigned by the server, for looking the blob up once it is stored
+    sequence: u64,
+}
+
+#[derive(Serialize)]
+struct BatchUploaded {
+    count: usize,
+    type_id: u32,
+    writer_id: u32,
+    /// the blobs got consecutive sequences from this one on, `None` for an empty batch
+    first_sequence: Option<u64>,
+}
+
+/// Where a blob was stored, all `None` while it waits for its container's flush.
+#[derive(Serialize)]
+struct BlobReceipt<'a> {
+    state: i32,
+    container: Option<&'a str>,
+    file: Option<&'a str>,
+    index: Option<usize>,
+}
+
+fn accepted<T: Serialize>(details: T, ctx: &Context) -> Response<Body> {
+    let body = Accepted {
+        state: 0,
+        details: (ctx.success_body == SuccessBody::Verbose).then_some(details),
+    };
+    Response::new(Body::from(serde_json::to_string(&body).unwrap()))
+}
+
+/// A blob dropped because its writer reached the type's `writer_cap` with
+/// `over_cap = "reject"`.
+fn writer_capped(type_id: u32, writer_id: u32, ctx: &Context) -> Response<Body> {
+    ctx.http_requests_metrics
+        .current()
+        .get_or_create(&HttpLabels {
+            method: HttpMethod::POST,
+            status: HttpStatus::Status4xx,
+            success: No,
+            type_id,
+            writer_id,
+            reason: 61,
+        })
+        .inc();
+    ApiError::new(61, "writer_cap reached for this container")
+        .into_response(StatusCode::TOO_MANY_REQUESTS)
+}
+
+/// Answers an upload whose body couldn't be received or stored: 400 for a body that
+/// broke off, stalled or was too large, 500 when the server failed.
+fn upload_failed(err: io::Error, type_id: u32, writer_id: u32, ctx: &Context) -> Response<Body> {
+    let (status, http_status) = match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::TimedOut | io::ErrorKind::InvalidInput => {
+            (StatusCode::BAD_REQUEST, HttpStatus::Status4xx)
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, HttpStatus::Status5xx),
+    };
+    ctx.http_requests_metrics
+        .current()
+        .get_or_create(&HttpLabels {
+            method: HttpMethod::POST,
+            status: http_status,
+            success: No,
+            type_id,
+            writer_id,
+            reason: 49,
+        })
+        .inc();
+    ApiError::new(49, format!("upload failed: {}", err)).into_response(status)
+}
+
+fn writer_unavailable() -> Response<Body> {
+    ApiError::new(44, "writer is not running").into_response(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Answers an upload the writer of the type stopped taking or gave up on, e.g.
+/// during a shutdown.
+fn writer_gone(type_id: u32, writer_id: u32, ctx: &Context) -> Response<Body> {
+    ctx.http_requests_metrics
+        .current()
+        .get_or_create(&HttpLabels {
+            method: HttpMethod::POST,
+            status: HttpStatus::Status5xx,
+            success: No,
+            type_id,
+            writer_id,
+            reason: 44,
+        })
+        .inc();
+    writer_unavailable()
+}
+
+/// Checks the `Authorization: Bearer <token>` header; everything is allowed when no
+/// token is configured.
+fn is_authorized(req: &Request<Body>, auth_token: Option<&str>) -> bool {
+    let auth_token = match auth_token {
+        None => return true,
+        Some(auth_token) => auth_token,
+    };
+    let provided = match req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        None => return false,
+        Some(provided) => provided,
+    };
+    constant_time_eq(provided.as_bytes(), auth_token.as_bytes())
+}
+
+/// Compares without short-circuiting on the first differing byte, so response timing
+/// doesn't reveal how much of the token was guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn parse_admin_flush_path(path: &str) -> Option<u32> {
+    path.strip_prefix("/admin/flush/")?.parse::<u32>().ok()
+}
+
+/// Parses `/type_id/N/container/NAME`; the name isn't validated.
+fn parse_container_path(path: &str) -> Option<(u32, &str)> {
+    let (type_id, name) = path.strip_prefix("/type_id/")?.split_once("/container/")?;
+    Some((type_id.parse::<u32>().ok()?, name))
+}
+
+/// Parses `/type_id/N/container/NAME/blob/I`, `I` being the blob's index in the TOC.
+fn parse_blob_path(path: &str) -> Option<(u32, &str, usize)> {
+    let (type_id, name) = parse_container_path(path)?;
+    let (name, index) = name.split_once("/blob/")?;
+    Some((type_id, name, index.parse::<usize>().ok()?))
+}
+
+/// Parses `/type_id/N/writer_id/K/sequence/S`.
+fn parse_sequence_path(path: &str) -> Option<(u32, u32, u64)> {
+    let (writer_path, sequence) = path.split_once("/sequence/")?;
+    let (type_id, writer_id) = parse_path(writer_path).ok()?;
+    Some((type_id, writer_id, parse_decimal(sequence)?))
+}
+
+/// Why `parse_path` rejected a path.
+#[derive(Debug, PartialEq, Eq)]
+enum PathError {
+    /// a segment is missing or misnamed, or more segments follow the writer_id
+    Malformed,
+    /// an id isn't a plain decimal number or doesn't fit in a u32
+    InvalidId,
+}
+
+impl PathError {
+    /// The `reason` code of the rejected request.
+    fn reason(&self) -> u32 {
+        match self {
+            Self::Malformed => 41,
+            Self::InvalidId => 58,
+        }
+    }
+
+    fn desc(&self) -> &'static str {
+        match self {
+            Self::Malformed => "invalid path, need /type_id/N/writer_id/K",
+            Self::InvalidId => "type_id and writer_id must be decimal numbers below 2^32",
+        }
+    }
+}
+
+/// Parses `/type_id/N/writer_id/K`. Segment names are matched ignoring case and
+/// empty segments such as a trailing slash are skipped, a query string is ignored;
+/// ids must be plain decimal numbers, leading zeros allowed, so signs, spaces and
+/// hex are rejected. Any further segment makes the path malformed.
+fn parse_path(path: &str) -> Result<(u32, u32), PathError> {
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let mut parts = path.split('/').filter(|part| !part.is_empty());
+    let mut id = |name: &str| {
+        match parts.next() {
+            Some(part) if part.eq_ignore_ascii_case(name) => {}
+            _ => return Err(PathError::Malformed),
+        }
+        let value = parts.next().ok_or(PathError::Malformed)?;
+        parse_decimal(value).ok_or(PathError::InvalidId)
+    };
+    let type_id = id("type_id")?;
+    let writer_id = id("writer_id")?;
+    if parts.next().is_some() {
+        return Err(PathError::Malformed);
+    }
+    Ok((type_id, writer_id))
+}
+
+/// Parses a number of ASCII digits only, unlike `str::parse`, which takes a `+`.
+fn parse_decimal<T: FromStr>(value: &str) -> Option<T> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
 }
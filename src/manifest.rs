@@ -0,0 +1,136 @@
+use crate::blob::storage::Container;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Name of the manifest kept in every root directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Serializes manifest updates, several types may share a root.
+static UPDATES: Mutex<()> = Mutex::new(());
+
+/// Lists the container files of a directory so readers can find blobs without
+/// opening every file. It is updated whenever a container is written or deleted and
+/// can be rebuilt with `blob_queue reindex`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    /// sorted by file name
+    pub containers: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestEntry {
+    pub file: String,
+    pub type_id: u32,
+    pub objects: usize,
+    /// oldest and newest blob timestamp in microseconds since the Unix epoch, both 0
+    /// for an empty container
+    pub first_timestamp: u64,
+    pub last_timestamp: u64,
+    /// creation time of the container in microseconds since the Unix epoch, 0 if the
+    /// file predates it being recorded
+    #[serde(default)]
+    pub created: u64,
+    pub size: u64,
+}
+
+impl ManifestEntry {
+    fn read(path: &Path) -> io::Result<Self> {
+        let summary = Container::summarize(path)?;
+        let (first_timestamp, last_timestamp) = summary.time_range.unwrap_or((0, 0));
+        Ok(Self {
+            file: file_name(path)?,
+            type_id: summary.type_id,
+            objects: summary.objects,
+            first_timestamp,
+            last_timestamp,
+            created: summary.created.unwrap_or_default(),
+            size: fs::metadata(path)?.len(),
+        })
+    }
+}
+
+/// Adds or refreshes the entry of the container file at `path` in the manifest of
+/// its directory.
+pub fn record(path: &Path) -> io::Result<()> {
+    let entry = ManifestEntry::read(path)?;
+    update(parent(path)?, |manifest| {
+        manifest.containers.retain(|known| known.file != entry.file);
+        manifest.containers.push(entry);
+    })
+}
+
+/// Drops the entry of a deleted container file.
+pub fn remove(path: &Path) -> io::Result<()> {
+    let file = file_name(path)?;
+    update(parent(path)?, |manifest| {
+        manifest.containers.retain(|known| known.file != file)
+    })
+}
+
+/// Writes a new manifest for `root` from the `.blob` files in it. Files that can't
+/// be read as containers are left out and returned with the error.
+pub fn rebuild(root: &Path) -> io::Result<(Manifest, Vec<(String, io::Error)>)> {
+    let _guard = UPDATES.lock().unwrap();
+    let mut manifest = Manifest::default();
+    let mut skipped = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let path = entry.path();
+        // `type{type_id}_latest.blob` points at one of the others
+        if path.extension().is_none_or(|extension| extension != "blob")
+            || entry.file_type()?.is_symlink()
+        {
+            continue;
+        }
+        match ManifestEntry::read(&path) {
+            Ok(entry) => manifest.containers.push(entry),
+            Err(err) => skipped.push((path.display().to_string(), err)),
+        }
+    }
+    save(root, &mut manifest)?;
+    Ok((manifest, skipped))
+}
+
+fn load(root: &Path) -> io::Result<Manifest> {
+    match fs::read(root.join(MANIFEST_FILE)) {
+        Ok(json) => {
+            serde_json::from_slice(&json).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Manifest::default()),
+        Err(err) => Err(err),
+    }
+}
+
+fn update<F: FnOnce(&mut Manifest)>(root: &Path, f: F) -> io::Result<()> {
+    let _guard = UPDATES.lock().unwrap();
+    let mut manifest = load(root)?;
+    f(&mut manifest);
+    save(root, &mut manifest)
+}
+
+/// Replaces the manifest through a rename, so readers never see a partial one.
+fn save(root: &Path, manifest: &mut Manifest) -> io::Result<()> {
+    manifest
+        .containers
+        .sort_by(|left, right| left.file.cmp(&right.file));
+    let json = serde_json::to_vec_pretty(manifest).map_err(io::Error::other)?;
+    let tmp_path = root.join(format!("{}.tmp", MANIFEST_FILE));
+    fs::write(&tmp_path, json)?;
+    fs::rename(tmp_path, root.join(MANIFEST_FILE))
+}
+
+fn parent(path: &Path) -> io::Result<&Path> {
+    path.parent()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "path has no directory"))
+}
+
+fn file_name(path: &Path) -> io::Result<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid container file name"))
+}
@@ -1,4 +1,13 @@
-use prometheus_client::encoding::text::Encode;
+use prometheus_client::encoding::text::{Encode, EncodeMetric, Encoder};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::MetricType;
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 #[derive(Clone, Hash, PartialEq, Eq, Encode)]
 pub struct HttpLabels {
@@ -7,14 +16,20 @@ pub struct HttpLabels {
     pub success: Success,
     pub type_id: u32,
     pub writer_id: u32,
+    /// the `reason` code of a rejected request, 0 for accepted requests and failures
+    /// without a code
+    pub reason: u32,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Hash, PartialEq, Eq, Encode)]
 pub enum HttpMethod {
     GET,
     POST,
+    DELETE,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Hash, PartialEq, Eq, Encode)]
 pub enum HttpStatus {
     Status2xx,
@@ -27,3 +42,194 @@ pub enum Success {
     Yes,
     No,
 }
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+pub struct TypeLabels {
+    pub type_id: u32,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+pub struct WriterLabels {
+    pub type_id: u32,
+    pub writer_id: u32,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+pub struct BuildInfoLabels {
+    pub version: String,
+    /// abbreviated commit hash, `unknown` when built outside a git checkout
+    pub git_hash: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+pub struct FlushLabels {
+    pub type_id: u32,
+    pub trigger: FlushTrigger,
+}
+
+/// Why a writer stopped filling a container and flushed it.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum FlushTrigger {
+    /// `objects_in_container` reached
+    Count,
+    /// `max_objects_hard_limit` reached
+    HardLimit,
+    /// the rotation window closed
+    Time,
+    /// no blob for `idle_flush_ms`
+    Idle,
+    /// a writer reached its `writer_cap` and its next blob spilled over
+    WriterCap,
+    /// `soft_max_bytes` reached and no blob waiting
+    SoftMaxBytes,
+    /// the next blob would have exceeded `hard_max_bytes`
+    HardMaxBytes,
+    /// `POST /admin/flush`
+    Admin,
+    /// the server is stopping
+    Shutdown,
+    /// left behind in a spool file by a crash, with `resume_spool`
+    Resume,
+}
+
+impl Encode for FlushTrigger {
+    fn encode(&self, writer: &mut dyn Write) -> Result<(), io::Error> {
+        let trigger = match self {
+            Self::Count => "count",
+            Self::HardLimit => "hard_limit",
+            Self::Time => "time",
+            Self::Idle => "idle",
+            Self::WriterCap => "writer_cap",
+            Self::SoftMaxBytes => "soft_max_bytes",
+            Self::HardMaxBytes => "hard_max_bytes",
+            Self::Admin => "admin",
+            Self::Shutdown => "shutdown",
+            Self::Resume => "resume",
+        };
+        trigger.encode(writer)
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+pub struct PhaseLabels {
+    pub type_id: u32,
+    pub phase: FlushPhase,
+}
+
+/// A stage of turning a full container into a durable file.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum FlushPhase {
+    /// compressing the data, checking the file appended to
+    Serialize,
+    /// writing the container file
+    Write,
+    /// syncing it to disk, by `sync_policy`
+    Sync,
+    /// renaming an appended file over the old one
+    Rename,
+}
+
+impl Encode for FlushPhase {
+    fn encode(&self, writer: &mut dyn Write) -> Result<(), io::Error> {
+        let phase = match self {
+            Self::Serialize => "serialize",
+            Self::Write => "write",
+            Self::Sync => "sync",
+            Self::Rename => "rename",
+        };
+        phase.encode(writer)
+    }
+}
+
+/// Seconds spent in each `FlushPhase`. A family of histograms can't be created with
+/// `Default`, which doesn't know their buckets.
+#[derive(Clone)]
+pub struct PhaseDurations(pub Family<PhaseLabels, Histogram, fn() -> Histogram>);
+
+impl Default for PhaseDurations {
+    fn default() -> Self {
+        // 0.5ms to 16s
+        Self(Family::new_with_constructor(|| {
+            Histogram::new(exponential_buckets(0.0005, 2.0, 16))
+        }))
+    }
+}
+
+impl PhaseDurations {
+    pub fn observe(&self, type_id: u32, phase: FlushPhase, duration: Duration) {
+        self.0
+            .get_or_create(&PhaseLabels { type_id, phase })
+            .observe(duration.as_secs_f64());
+    }
+}
+
+/// The bucket a rate limited request was counted against, `type_id/writer_id` or the
+/// client IP.
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+pub struct RateLimitLabels {
+    pub key: String,
+}
+
+#[derive(Clone, Default)]
+pub struct RetentionMetrics {
+    pub deleted_files: Family<TypeLabels, Counter>,
+    pub freed_bytes: Family<TypeLabels, Counter>,
+}
+
+#[derive(Clone, Default)]
+pub struct CompactionMetrics {
+    /// small files merged into others and removed
+    pub merged_files: Family<TypeLabels, Counter>,
+    pub written_files: Family<TypeLabels, Counter>,
+    /// the writers' `checksum_failures`, for the files read to be merged
+    pub checksum_failures: Family<TypeLabels, Counter>,
+}
+
+#[derive(Clone, Default)]
+pub struct WriterMetrics {
+    /// writer tasks currently running
+    pub live: Gauge,
+    /// writer tasks of each type, changed by autoscaling
+    pub tasks: Family<TypeLabels, Gauge>,
+    pub restarts: Family<TypeLabels, Counter>,
+    /// failed attempts to store a finished container
+    pub write_failures: Family<TypeLabels, Counter>,
+    /// blob data added to containers, before deduplication
+    pub bytes_in: Family<TypeLabels, Counter>,
+    pub objects_in: Family<TypeLabels, Counter>,
+    /// container files written and not yet synced with `sync_policy = "interval"`
+    pub unsynced_files: Family<TypeLabels, Gauge>,
+    /// containers stored, by what made the writer flush them
+    pub flushes: Family<FlushLabels, Counter>,
+    /// blobs spilled or rejected by `writer_cap`
+    pub cap_hits: Family<WriterLabels, Counter>,
+    /// stored containers read back, to append to or merge with, that failed their
+    /// checksum
+    pub checksum_failures: Family<TypeLabels, Counter>,
+    pub flush_phase_seconds: PhaseDurations,
+}
+
+/// A registered metric that can be replaced by a fresh one while the server runs, for
+/// `POST /admin/metrics/reset`; the registry encodes whichever is current.
+#[derive(Clone, Default)]
+pub struct Resettable<M>(Arc<RwLock<M>>);
+
+impl<M: Clone + Default> Resettable<M> {
+    pub fn current(&self) -> M {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn reset(&self) {
+        *self.0.write().unwrap() = M::default();
+    }
+}
+
+impl<M: EncodeMetric> EncodeMetric for Resettable<M> {
+    fn encode(&self, encoder: Encoder) -> Result<(), io::Error> {
+        self.0.read().unwrap().encode(encoder)
+    }
+
+    fn metric_type(&self) -> MetricType {
+        self.0.read().unwrap().metric_type()
+    }
+}
@@ -0,0 +1,256 @@
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+use serde_json::{json, Value};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{debug, info_span, warn, Instrument};
+
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+/// OTLP `AGGREGATION_TEMPORALITY_CUMULATIVE`: the counters never reset while the
+/// process runs.
+const CUMULATIVE: u32 = 2;
+
+/// Pushes the metrics of the Prometheus registry to an OTLP/HTTP collector as JSON.
+/// Counters become monotonic cumulative sums and gauges gauges, the labels their
+/// attributes; other metric types are skipped.
+pub struct Exporter {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: String,
+    registry: Arc<Registry>,
+    /// when the counters started, in nanoseconds since the Unix epoch
+    started: u64,
+}
+
+/// Label names and values of a sample.
+type Labels = Vec<(String, String)>;
+
+/// A metric family parsed from the text exposition.
+struct Family {
+    name: String,
+    help: String,
+    kind: String,
+    points: Vec<(Labels, f64)>,
+}
+
+impl Exporter {
+    /// Creates the exporter for the collector at `endpoint` and spawns the task pushing
+    /// to it every `interval_secs`.
+    pub fn spawn(endpoint: &str, interval_secs: Option<u64>, registry: Arc<Registry>) -> Arc<Self> {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let exporter = Arc::new(Self {
+            client: Client::builder().build(connector),
+            url: format!("{}/v1/metrics", endpoint.trim_end_matches('/')),
+            registry,
+            started: now_nanos(),
+        });
+        let every = Duration::from_secs(interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS));
+        let pushing = exporter.clone();
+        task::spawn(
+            async move {
+                let mut ticks = interval(every);
+                ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                // the first tick completes right away and there is nothing to report yet
+                ticks.tick().await;
+                loop {
+                    ticks.tick().await;
+                    pushing.export().await;
+                }
+            }
+            .instrument(info_span!("otlp")),
+        );
+        exporter
+    }
+
+    /// Pushes the current values once; failures are logged, the next push sends the
+    /// cumulative values again.
+    pub async fn export(&self) {
+        if let Err(err) = self.push().await {
+            warn!(%err, url = %self.url, "pushing metrics failed");
+        }
+    }
+
+    async fn push(&self) -> io::Result<()> {
+        let mut text = Vec::new();
+        encode(&mut text, &self.registry)?;
+        let families = parse(&String::from_utf8_lossy(&text));
+        let body = to_otlp(&families, self.started, now_nanos());
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&body).map_err(io::Error::other)?,
+            ))
+            .map_err(io::Error::other)?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(io::Error::other)?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap_or_default();
+            return Err(io::Error::other(format!(
+                "collector answered {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+        debug!(families = families.len(), "metrics pushed");
+        Ok(())
+    }
+}
+
+/// Parses the OpenMetrics text `encode` writes, keeping the samples of counters and
+/// gauges.
+fn parse(text: &str) -> Vec<Family> {
+    let mut families: Vec<Family> = Vec::new();
+    for line in text.lines() {
+        if let Some(help) = line.strip_prefix("# HELP ") {
+            let (name, help) = help.split_once(' ').unwrap_or((help, ""));
+            families.push(Family {
+                name: name.to_string(),
+                help: help.to_string(),
+                kind: String::new(),
+                points: Vec::new(),
+            });
+        } else if let Some(kind) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = kind.split_once(' ').unwrap_or((kind, ""));
+            match families.last_mut() {
+                Some(family) if family.name == name => family.kind = kind.to_string(),
+                _ => families.push(Family {
+                    name: name.to_string(),
+                    help: String::new(),
+                    kind: kind.to_string(),
+                    points: Vec::new(),
+                }),
+            }
+        } else if !line.starts_with('#') {
+            let (family, (name, labels, value)) = match (families.last_mut(), parse_sample(line)) {
+                (Some(family), Some(sample)) => (family, sample),
+                _ => continue,
+            };
+            let expected = match family.kind.as_str() {
+                "counter" => format!("{}_total", family.name),
+                "gauge" => family.name.clone(),
+                _ => continue,
+            };
+            if name == expected {
+                family.points.push((labels, value));
+            }
+        }
+    }
+    families
+}
+
+/// Splits `name{key="value",...} value` into its parts.
+fn parse_sample(line: &str) -> Option<(&str, Labels, f64)> {
+    let name_end = line.find(['{', ' '])?;
+    let (name, mut rest) = line.split_at(name_end);
+    let mut labels = Vec::new();
+    if let Some(mut pairs) = rest.strip_prefix('{') {
+        loop {
+            pairs = pairs.trim_start_matches(',');
+            if let Some(after) = pairs.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (key, quoted) = pairs.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        escaped => value.push(escaped),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((key.to_string(), value));
+            pairs = &quoted[end + 1..];
+        }
+    }
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some((name, labels, value))
+}
+
+/// The body of an OTLP `ExportMetricsServiceRequest` in the JSON encoding.
+fn to_otlp(families: &[Family], started: u64, now: u64) -> Value {
+    let metrics: Vec<Value> = families
+        .iter()
+        .filter(|family| !family.points.is_empty())
+        .map(|family| {
+            let points: Vec<Value> = family
+                .points
+                .iter()
+                .map(|(labels, value)| {
+                    let attributes: Vec<Value> = labels
+                        .iter()
+                        .map(
+                            |(key, value)| json!({ "key": key, "value": { "stringValue": value } }),
+                        )
+                        .collect();
+                    json!({
+                        "attributes": attributes,
+                        "startTimeUnixNano": started.to_string(),
+                        "timeUnixNano": now.to_string(),
+                        "asDouble": value,
+                    })
+                })
+                .collect();
+            let description = family.help.trim_end_matches('.');
+            if family.kind == "counter" {
+                json!({
+                    "name": family.name,
+                    "description": description,
+                    "sum": {
+                        "aggregationTemporality": CUMULATIVE,
+                        "isMonotonic": true,
+                        "dataPoints": points,
+                    },
+                })
+            } else {
+                json!({
+                    "name": family.name,
+                    "description": description,
+                    "gauge": { "dataPoints": points },
+                })
+            }
+        })
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": env!("CARGO_PKG_NAME") } },
+                ],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
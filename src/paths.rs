@@ -0,0 +1,304 @@
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// A finished container file found on disk.
+pub struct BlobFile {
+    /// from the file name
+    pub timestamp: u64,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Resolves a client supplied file name below `root`. Anything but plain names, such
+/// as `..`, `.` or absolute paths, is rejected with `InvalidInput`, and so is a
+/// name that resolves outside of `root` through a symlink. A missing file is
+/// `NotFound`. Every handler touching the filesystem on behalf of a client has to go
+/// through here.
+pub fn safe_join(root: &Path, name: &str) -> io::Result<PathBuf> {
+    let escapes = || io::Error::new(ErrorKind::InvalidInput, "path escapes the root directory");
+    let name = Path::new(name);
+    if name.components().next().is_none()
+        || !name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(escapes());
+    }
+    let root = root.canonicalize()?;
+    let path = root.join(name).canonicalize()?;
+    if !path.starts_with(&root) {
+        return Err(escapes());
+    }
+    Ok(path)
+}
+
+/// Resolves a client supplied container file name like `safe_join`, also finding
+/// files of date partitioned types, which clients address by their flat name.
+pub fn resolve_container(
+    root: &Path,
+    name: &str,
+    type_id: u32,
+    template: &FilenameTemplate,
+) -> io::Result<PathBuf> {
+    match safe_join(root, name) {
+        Err(err) if err.kind() == ErrorKind::NotFound => match template.timestamp_of(type_id, name)
+        {
+            Some(file_time) => safe_join(root, &partitioned(name, file_time)),
+            None => Err(err),
+        },
+        resolved => resolved,
+    }
+}
+
+/// Where the container file `name` started at `file_time` goes, relative to the
+/// type's root. With `date_partitioned` it is in a `YYYY/MM/DD` directory for the
+/// UTC day of `file_time`.
+pub fn container_name(name: String, file_time: u64, date_partitioned: bool) -> String {
+    if date_partitioned {
+        partitioned(&name, file_time)
+    } else {
+        name
+    }
+}
+
+fn partitioned(name: &str, file_time: u64) -> String {
+    format!("{}/{}", date_dir(file_time), name)
+}
+
+/// Names of container files, e.g. `type{type_id}_{timestamp}.blob`, the default.
+///
+/// Placeholders: `{type_id}`, `{timestamp}` (microseconds since the Unix epoch the
+/// container was started), `{count}` (its number of blobs) and `{seq}` (the number of
+/// the container since the writer started, from 1). `{timestamp}` is required, it
+/// keeps names unique and lets retention find the oldest files, and names end in
+/// `.blob`. Placeholders render as digits and the rest may not hold path separators,
+/// so names never leave the directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilenameTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    TypeId,
+    Timestamp,
+    Count,
+    Seq,
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        Self::parse("type{type_id}_{timestamp}.blob").expect("valid default template")
+    }
+}
+
+impl FilenameTemplate {
+    pub fn parse(template: &str) -> io::Result<Self> {
+        let invalid = |reason: String| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid filename_template {}: {}", template, reason),
+            )
+        };
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            let literal_end = rest.find('{').unwrap_or(rest.len());
+            let literal = &rest[..literal_end];
+            if literal.contains(['/', '\\', '}']) {
+                return Err(invalid(format!("unexpected character in {}", literal)));
+            }
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(literal.to_string()));
+            }
+            rest = &rest[literal_end..];
+            if rest.is_empty() {
+                break;
+            }
+            let placeholder_end = rest
+                .find('}')
+                .ok_or_else(|| invalid("unclosed placeholder".to_string()))?;
+            parts.push(match &rest[1..placeholder_end] {
+                "type_id" => TemplatePart::TypeId,
+                "timestamp" => TemplatePart::Timestamp,
+                "count" => TemplatePart::Count,
+                "seq" => TemplatePart::Seq,
+                unknown => return Err(invalid(format!("unknown placeholder {{{}}}", unknown))),
+            });
+            rest = &rest[placeholder_end + 1..];
+        }
+        if !parts.contains(&TemplatePart::Timestamp) {
+            return Err(invalid("{timestamp} is required".to_string()));
+        }
+        if !template.ends_with(".blob") {
+            return Err(invalid("names must end in .blob".to_string()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Whether names change with the contents of the container, which rules out
+    /// appending to a file per window.
+    pub fn varies_per_container(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, TemplatePart::Count | TemplatePart::Seq))
+    }
+
+    pub fn render(&self, type_id: u32, timestamp: u64, count: usize, seq: u64) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => name.push_str(literal),
+                TemplatePart::TypeId => name.push_str(&type_id.to_string()),
+                TemplatePart::Timestamp => name.push_str(&timestamp.to_string()),
+                TemplatePart::Count => name.push_str(&count.to_string()),
+                TemplatePart::Seq => name.push_str(&seq.to_string()),
+            }
+        }
+        name
+    }
+
+    /// The timestamp of a name rendered from this template for `type_id`, `None` for
+    /// other names. Without `{type_id}` in the template, names of other types sharing
+    /// the root match as well.
+    pub fn timestamp_of(&self, type_id: u32, mut name: &str) -> Option<u64> {
+        let mut timestamp = None;
+        for part in &self.parts {
+            if let TemplatePart::Literal(literal) = part {
+                name = name.strip_prefix(literal.as_str())?;
+                continue;
+            }
+            let digits = name
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(name.len());
+            let value = name[..digits].parse::<u64>().ok()?;
+            match part {
+                TemplatePart::TypeId if value != type_id as u64 => return None,
+                TemplatePart::Timestamp => timestamp = Some(value),
+                _ => {}
+            }
+            name = &name[digits..];
+        }
+        if !name.is_empty() {
+            return None;
+        }
+        timestamp
+    }
+}
+
+/// `YYYY/MM/DD` of the UTC day of `micros` since the Unix epoch.
+fn date_dir(micros: u64) -> String {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = micros / 86_400_000_000 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{:04}/{:02}/{:02}", year, month, day)
+}
+
+/// Lists the finished containers of `type_id`, i.e. names matching its `template`,
+/// in `root` and, for date partitioned types, its subdirectories. Spool and temporary
+/// files and other names are skipped.
+pub fn list_blob_files(
+    type_id: u32,
+    root: &Path,
+    template: &FilenameTemplate,
+) -> io::Result<Vec<BlobFile>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            let name = entry.file_name();
+            let timestamp = name
+                .to_str()
+                .and_then(|name| template.timestamp_of(type_id, name));
+            if let Some(timestamp) = timestamp {
+                files.push(BlobFile {
+                    timestamp,
+                    path: entry.path(),
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A fresh directory holding `root/inside.blob` and `outside.blob` next to `root`.
+    fn sandbox() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "blob_queue_paths_{}_{}",
+            process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::write(dir.join("root/inside.blob"), b"").unwrap();
+        fs::write(dir.join("outside.blob"), b"").unwrap();
+        dir
+    }
+
+    #[test]
+    fn safe_join_rejects_names_leaving_root() {
+        let dir = sandbox();
+        let root = dir.join("root");
+        for name in [
+            "../../etc/passwd",
+            "../outside.blob",
+            ".",
+            "",
+            "/etc/passwd",
+        ] {
+            let err = safe_join(&root, name).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", name);
+        }
+        assert_eq!(
+            safe_join(&root, "inside.blob").unwrap(),
+            root.canonicalize().unwrap().join("inside.blob")
+        );
+        assert_eq!(
+            safe_join(&root, "missing.blob").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn safe_join_rejects_symlinks_leaving_root() {
+        let dir = sandbox();
+        let root = dir.join("root");
+        std::os::unix::fs::symlink(dir.join("outside.blob"), root.join("link.blob")).unwrap();
+        std::os::unix::fs::symlink(&dir, root.join("up")).unwrap();
+        for name in ["link.blob", "up/outside.blob"] {
+            let err = safe_join(&root, name).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", name);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
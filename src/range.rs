@@ -0,0 +1,65 @@
+/// What to send for a `Range` header on a resource of known size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// no usable header, send everything
+    Full,
+    /// inclusive bounds within the resource
+    Partial {
+        start: u64,
+        end: u64,
+    },
+    Unsatisfiable,
+}
+
+/// Resolves a single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range.
+/// Headers that don't parse or ask for several ranges are ignored, as HTTP allows,
+/// so the whole resource is sent.
+pub fn resolve(header: Option<&str>, size: u64) -> ByteRange {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        None => return ByteRange::Full,
+        Some(spec) => spec,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) if !spec.contains(',') => (start.trim(), end.trim()),
+        _ => return ByteRange::Full,
+    };
+    if start.is_empty() {
+        return match parse(end) {
+            None => ByteRange::Full,
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if size == 0 => ByteRange::Unsatisfiable,
+            Some(suffix) => ByteRange::Partial {
+                start: size - suffix.min(size),
+                end: size - 1,
+            },
+        };
+    }
+    let start = match parse(start) {
+        None => return ByteRange::Full,
+        Some(start) => start,
+    };
+    let end = match end {
+        "" => u64::MAX,
+        end => match parse(end) {
+            None => return ByteRange::Full,
+            Some(end) => end,
+        },
+    };
+    if end < start {
+        return ByteRange::Full;
+    }
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        start,
+        end: end.min(size - 1),
+    }
+}
+
+fn parse(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
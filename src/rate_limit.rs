@@ -0,0 +1,104 @@
+use crate::config::{RateLimitBy, RateLimitConfig};
+use hyper::server::conn::AddrStream;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_rustls::server::TlsStream;
+
+/// Above this many tracked keys, buckets that have refilled completely are dropped so
+/// a stream of client addresses can't grow the map without bound.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// What a producer is limited by, see `RateLimitBy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LimitKey {
+    Writer { type_id: u32, writer_id: u32 },
+    Client(IpAddr),
+}
+
+impl fmt::Display for LimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Writer { type_id, writer_id } => write!(f, "{}/{}", type_id, writer_id),
+            Self::Client(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// In-memory token buckets, one per key: each holds up to `burst` requests and
+/// refills at `requests_per_sec`.
+pub struct RateLimiter {
+    by: RateLimitBy,
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<LimitKey, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            by: config.by.unwrap_or_default(),
+            rate: config.requests_per_sec as f64,
+            burst: config.burst.unwrap_or(config.requests_per_sec) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The key a request from `client` posting to `type_id`/`writer_id` is counted
+    /// against.
+    pub fn key(&self, type_id: u32, writer_id: u32, client: IpAddr) -> LimitKey {
+        match self.by {
+            RateLimitBy::Writer => LimitKey::Writer { type_id, writer_id },
+            RateLimitBy::Client => LimitKey::Client(client),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, or returns how long until the next
+    /// one is available.
+    pub fn acquire(&self, key: LimitKey) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(&key) {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+}
+
+/// Connections that know the address of their client.
+pub trait RemoteIp {
+    fn remote_ip(&self) -> IpAddr;
+}
+
+impl RemoteIp for AddrStream {
+    fn remote_ip(&self) -> IpAddr {
+        self.remote_addr().ip()
+    }
+}
+
+impl RemoteIp for TlsStream<AddrStream> {
+    fn remote_ip(&self) -> IpAddr {
+        self.get_ref().0.remote_ip()
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Receipts kept per type; older ones are dropped first.
+const MAX_RECEIPTS: usize = 100_000;
+
+/// Where a flushed blob went.
+#[derive(Clone, Debug)]
+pub struct Receipt {
+    /// the container's name relative to the type's root
+    pub name: Arc<str>,
+    /// the container's location as reported by the storage backend
+    pub location: Arc<str>,
+    /// the blob's index in the container's TOC
+    pub index: usize,
+}
+
+/// What is known about a blob, by writer_id and sequence number.
+pub enum Lookup {
+    Flushed(Receipt),
+    /// still buffered by the writer
+    Pending,
+    /// never assigned, dropped, or flushed too long ago to be tracked
+    Unknown,
+}
+
+/// Where the recently flushed blobs of a type went, so producers can find their
+/// blobs by the sequence number the POST response gave them.
+#[derive(Default)]
+pub struct Receipts {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    receipts: HashMap<(u32, u64), Receipt>,
+    /// receipt keys in the order they were recorded
+    order: VecDeque<(u32, u64)>,
+    /// blobs queued or buffered by a writer task, by (writer_id, sequence)
+    pending: HashSet<(u32, u64)>,
+}
+
+impl Receipts {
+    /// Notes a blob handed to a writer task.
+    pub fn queued(&self, writer_id: u32, sequence: u64) {
+        self.inner
+            .lock()
+            .unwrap()
+            .pending
+            .insert((writer_id, sequence));
+    }
+
+    /// Forgets a blob that won't be stored, e.g. an upload that failed.
+    pub fn dropped(&self, writer_id: u32, sequence: u64) {
+        self.inner
+            .lock()
+            .unwrap()
+            .pending
+            .remove(&(writer_id, sequence));
+    }
+
+    /// Records the blobs of a stored container, given as (writer_id, sequence) in TOC
+    /// order starting at `first_index`.
+    pub fn record(
+        &self,
+        name: &str,
+        location: &str,
+        first_index: usize,
+        blobs: impl Iterator<Item = (u32, u64)>,
+    ) {
+        let (name, location) = (Arc::<str>::from(name), Arc::<str>::from(location));
+        let mut inner = self.inner.lock().unwrap();
+        for (index, (writer_id, sequence)) in (first_index..).zip(blobs) {
+            let receipt = Receipt {
+                name: name.clone(),
+                location: location.clone(),
+                index,
+            };
+            if inner
+                .receipts
+                .insert((writer_id, sequence), receipt)
+                .is_none()
+            {
+                inner.order.push_back((writer_id, sequence));
+            }
+            inner.pending.remove(&(writer_id, sequence));
+        }
+        while inner.order.len() > MAX_RECEIPTS {
+            let oldest = inner.order.pop_front().unwrap();
+            inner.receipts.remove(&oldest);
+        }
+    }
+
+    pub fn lookup(&self, writer_id: u32, sequence: u64) -> Lookup {
+        let inner = self.inner.lock().unwrap();
+        if let Some(receipt) = inner.receipts.get(&(writer_id, sequence)) {
+            return Lookup::Flushed(receipt.clone());
+        }
+        if inner.pending.contains(&(writer_id, sequence)) {
+            Lookup::Pending
+        } else {
+            Lookup::Unknown
+        }
+    }
+}
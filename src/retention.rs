@@ -0,0 +1,104 @@
+use crate::blob::storage::now_micros;
+use crate::config::{RetentionConfig, TypeConfig};
+use crate::manifest;
+use crate::metrics::{RetentionMetrics, TypeLabels};
+use crate::paths;
+use crate::paths::FilenameTemplate;
+use crate::sidecar;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::task;
+use tokio::time::interval;
+use tracing::{info, info_span, warn, Instrument};
+
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+/// Spawns the retention task for `type_id` if it has a retention policy.
+pub fn spawn(type_id: &TypeConfig, metrics: RetentionMetrics) {
+    let retention = match &type_id.retention {
+        None => return,
+        Some(retention) => retention.clone(),
+    };
+    let id = type_id.type_id;
+    let root = PathBuf::from(type_id.root.as_str());
+    let template = type_id.filename_template();
+    let span = info_span!("retention", type_id = id);
+    task::spawn(run(id, root, template, retention, metrics).instrument(span));
+}
+
+async fn run(
+    type_id: u32,
+    root: PathBuf,
+    template: FilenameTemplate,
+    retention: RetentionConfig,
+    metrics: RetentionMetrics,
+) {
+    let mut ticks = interval(Duration::from_secs(
+        retention.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS),
+    ));
+    loop {
+        ticks.tick().await;
+        let root = root.clone();
+        let (template, retention) = (template.clone(), retention.clone());
+        let deleted =
+            task::spawn_blocking(move || enforce(type_id, &root, &template, &retention)).await;
+        let (files, bytes) = match deleted {
+            Ok(Ok(deleted)) => deleted,
+            Ok(Err(err)) => {
+                warn!(%err, "retention scan failed");
+                continue;
+            }
+            Err(err) => {
+                warn!(%err, "retention scan panicked");
+                continue;
+            }
+        };
+        if files > 0 {
+            info!(files, bytes, "old containers deleted");
+            let labels = TypeLabels { type_id };
+            metrics.deleted_files.get_or_create(&labels).inc_by(files);
+            metrics.freed_bytes.get_or_create(&labels).inc_by(bytes);
+        }
+    }
+}
+
+/// Deletes the files violating the policy, oldest first, and returns how many files
+/// and bytes were removed. The newest file may still be appended to, so it is kept.
+fn enforce(
+    type_id: u32,
+    root: &Path,
+    template: &FilenameTemplate,
+    retention: &RetentionConfig,
+) -> io::Result<(u64, u64)> {
+    let mut files = paths::list_blob_files(type_id, root, template)?;
+    files.sort_by_key(|file| file.timestamp);
+    let newest = match files.pop() {
+        None => return Ok((0, 0)),
+        Some(newest) => newest,
+    };
+    let mut total: u64 = files.iter().map(|file| file.size).sum::<u64>() + newest.size;
+    let min_timestamp = retention
+        .max_age_secs
+        .map(|max_age_secs| now_micros().saturating_sub(max_age_secs * 1_000_000));
+    let (mut deleted_files, mut deleted_bytes) = (0, 0);
+    for file in files {
+        let too_old = min_timestamp.is_some_and(|min| file.timestamp < min);
+        let too_big = retention.max_total_bytes.is_some_and(|max| total > max);
+        if !too_old && !too_big {
+            break;
+        }
+        fs::remove_file(&file.path)?;
+        if let Err(err) = manifest::remove(&file.path) {
+            warn!(%err, path = %file.path.display(), "manifest update failed");
+        }
+        if let Err(err) = sidecar::remove(&file.path) {
+            warn!(%err, path = %file.path.display(), "removing the sidecar failed");
+        }
+        total -= file.size;
+        deleted_files += 1;
+        deleted_bytes += file.size;
+    }
+    Ok((deleted_files, deleted_bytes))
+}
@@ -0,0 +1,67 @@
+use crate::blob::checksum::{ChecksumAlgo, ChecksumScope};
+use crate::blob::storage::Container;
+use serde_derive::Serialize;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// The facts about a container file written next to it as `<name>.meta.json`, for
+/// consumers that can't parse the binary headers.
+#[derive(Serialize, Debug)]
+pub struct Sidecar {
+    pub type_id: u32,
+    pub object_count: usize,
+    /// size of the container file
+    pub total_bytes: u64,
+    pub checksum_algo: ChecksumAlgo,
+    /// `headers` when the checksum leaves the data out
+    pub checksum_scope: ChecksumScope,
+    /// the stored checksum in hex
+    pub checksum: String,
+    /// creation time in microseconds since the Unix epoch, `null` if the file predates
+    /// it being recorded
+    pub created: Option<u64>,
+}
+
+pub fn path(container: &Path) -> PathBuf {
+    let mut name = container
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_default();
+    name.push(".meta.json");
+    container.with_file_name(name)
+}
+
+/// Writes the sidecar of the container file at `path`. The container is synced to
+/// disk first and the sidecar is replaced through a rename, so a sidecar only ever
+/// describes a complete container.
+pub fn write(container: &Path) -> io::Result<()> {
+    File::open(container)?.sync_all()?;
+    let summary = Container::summarize(container)?;
+    let sidecar = Sidecar {
+        type_id: summary.type_id,
+        object_count: summary.objects,
+        total_bytes: fs::metadata(container)?.len(),
+        checksum_algo: summary.checksum_algo,
+        checksum_scope: summary.checksum_scope,
+        checksum: summary.checksum.to_hex(summary.checksum_algo),
+        created: summary.created,
+    };
+    let json = serde_json::to_vec_pretty(&sidecar).map_err(io::Error::other)?;
+    let sidecar_path = path(container);
+    let tmp_path = sidecar_path.with_extension("json.tmp");
+    fs::write(&tmp_path, json)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, sidecar_path)
+}
+
+/// Removes the sidecar of a deleted container file, if it has one.
+pub fn remove(container: &Path) -> io::Result<()> {
+    match fs::remove_file(path(container)) {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        removed => removed,
+    }
+}
@@ -0,0 +1,76 @@
+use crate::metrics::{TypeLabels, WriterMetrics};
+use crate::writer::WriterHandle;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::info;
+
+/// Ingest throughput per type over the last sampling interval, derived from the
+/// `ingested_bytes` and `ingested_objects` counters.
+#[derive(Default)]
+pub struct Throughput {
+    rates: Mutex<HashMap<u32, Rate>>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Rate {
+    pub type_id: u32,
+    pub mb_per_sec: f64,
+    pub objects_per_sec: f64,
+}
+
+impl Throughput {
+    /// The latest rates, ordered by type_id.
+    pub fn rates(&self) -> Vec<Rate> {
+        let mut rates: Vec<Rate> = self.rates.lock().unwrap().values().copied().collect();
+        rates.sort_by_key(|rate| rate.type_id);
+        rates
+    }
+}
+
+/// Samples the counters of every running type each `every` and logs the rates.
+pub fn spawn(
+    every: Duration,
+    senders: Arc<RwLock<HashMap<u32, WriterHandle>>>,
+    metrics: WriterMetrics,
+) -> Arc<Throughput> {
+    let throughput = Arc::new(Throughput::default());
+    let sampled = throughput.clone();
+    task::spawn(async move {
+        let mut ticks = interval(every);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut previous: HashMap<u32, (u64, u64)> = HashMap::new();
+        let mut sampled_at = Instant::now();
+        loop {
+            ticks.tick().await;
+            let elapsed = sampled_at.elapsed().as_secs_f64().max(f64::EPSILON);
+            sampled_at = Instant::now();
+            let type_ids: Vec<u32> = senders.read().unwrap().keys().copied().collect();
+            let mut rates = sampled.rates.lock().unwrap();
+            for type_id in type_ids {
+                let labels = TypeLabels { type_id };
+                let bytes = metrics.bytes_in.get_or_create(&labels).get();
+                let objects = metrics.objects_in.get_or_create(&labels).get();
+                let (previous_bytes, previous_objects) = previous
+                    .insert(type_id, (bytes, objects))
+                    .unwrap_or((bytes, objects));
+                let rate = Rate {
+                    type_id,
+                    mb_per_sec: (bytes - previous_bytes) as f64 / elapsed / 1_000_000.0,
+                    objects_per_sec: (objects - previous_objects) as f64 / elapsed,
+                };
+                info!(
+                    type_id,
+                    mb_per_sec = rate.mb_per_sec,
+                    objects_per_sec = rate.objects_per_sec,
+                    "throughput"
+                );
+                rates.insert(type_id, rate);
+            }
+        }
+    });
+    throughput
+}
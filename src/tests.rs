@@ -0,0 +1,467 @@
+//! Tests of the HTTP pipeline: end to end, a server on an ephemeral port whose types
+//! are rooted in a temporary directory, driven by a `hyper::Client`, and the parsing
+//! of request paths.
+
+use crate::backend::{MemoryBackend, StorageBackend};
+use crate::blob::storage::Container;
+use crate::config::Config;
+use crate::health::Health;
+use crate::metrics::WriterMetrics;
+use crate::{listen, parse_path, serve, writer, Context, PathError};
+use hyper::header::{ACCEPT, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio::task;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// Tells apart the roots of the servers of one test run.
+static SERVERS: AtomicUsize = AtomicUsize::new(0);
+
+/// A running server; its root is removed when it is dropped.
+struct TestServer {
+    addr: SocketAddr,
+    root: PathBuf,
+    stop: Option<oneshot::Sender<()>>,
+    server: JoinHandle<hyper::Result<()>>,
+}
+
+impl TestServer {
+    /// Starts a server with the types of `types`, TOML `[[types]]` tables in which
+    /// `{root}` stands for the server's temporary directory, and the admin endpoints
+    /// enabled.
+    async fn start(types: &str) -> Self {
+        Self::start_with_admin(types, true).await
+    }
+
+    async fn start_with_admin(types: &str, enable_admin: bool) -> Self {
+        Self::launch(types, enable_admin, None).await
+    }
+
+    /// Like `start`, the writers storing their containers in `backend`.
+    async fn start_with_backend(types: &str, backend: Arc<dyn StorageBackend>) -> Self {
+        Self::launch(types, true, Some(backend)).await
+    }
+
+    async fn launch(
+        types: &str,
+        enable_admin: bool,
+        backend: Option<Arc<dyn StorageBackend>>,
+    ) -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "blob_queue_test_{}_{}",
+            process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&root).unwrap();
+        let config_path = root.join("config.toml");
+        let types = types.replace("{root}", &root.display().to_string());
+        fs::write(&config_path, format!("[server]\nport = 0\n{}", types)).unwrap();
+        let config = Config::from_file(config_path.display().to_string()).unwrap();
+
+        let health = Arc::new(Health::new(config.server.max_queued));
+        let writer_metrics = WriterMetrics::default();
+        let senders: HashMap<_, _> = config
+            .types
+            .iter()
+            .map(|type_id| {
+                let (type_config, health) = (type_id.clone(), health.clone());
+                let handle = match &backend {
+                    None => writer::spawn(type_config, health, writer_metrics.clone()),
+                    Some(backend) => writer::spawn_with_backend(
+                        type_config,
+                        health,
+                        writer_metrics.clone(),
+                        backend.clone(),
+                    ),
+                };
+                (type_id.type_id, handle)
+            })
+            .collect();
+        let mut ctx = Context::new(senders, None, health, writer_metrics, None, None, false);
+        ctx.enable_admin = enable_admin;
+        let incoming = listen(&config.server).unwrap();
+        let addr = incoming.local_addr();
+        let (stop, stopped) = oneshot::channel();
+        let server = task::spawn(async move {
+            let (_close, closing) = watch::channel(());
+            let stopped = async {
+                let _ = stopped.await;
+            };
+            serve(incoming, ctx, &config.server, stopped, closing).await
+        });
+        Self {
+            addr,
+            root,
+            stop: Some(stop),
+            server,
+        }
+    }
+
+    async fn request(&self, method: Method, path: &str, body: &[u8]) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path))
+            .body(Body::from(body.to_vec()))
+            .unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn post(&self, path: &str, body: &[u8]) -> (StatusCode, String) {
+        self.request(Method::POST, path, body).await
+    }
+
+    /// Stops the server once the requests in flight are answered.
+    async fn stop(mut self) {
+        let _ = self.stop.take().unwrap().send(());
+        (&mut self.server).await.unwrap().unwrap();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// The file name in the `"file"` of a flush reply.
+fn flushed_file(reply: &str) -> PathBuf {
+    let reply: serde_json::Value = serde_json::from_str(reply).unwrap();
+    PathBuf::from(reply["file"].as_str().unwrap())
+}
+
+/// The data of the blobs of a container file, in order.
+fn blobs_of(path: &PathBuf) -> Vec<(u32, Vec<u8>)> {
+    let container = Container::from_file(File::open(path).unwrap()).unwrap();
+    container
+        .blobs()
+        .map(|blob| (blob.writer_id, blob.data.to_vec()))
+        .collect()
+}
+
+#[tokio::test]
+async fn posted_blobs_are_stored_in_a_container() {
+    let server = TestServer::start(
+        r#"
+        [[types]]
+        type_id = 1
+        root = "{root}"
+        objects_in_container = 100
+        "#,
+    )
+    .await;
+    for (writer_id, data) in [(0, "first"), (1, "second"), (0, "third")] {
+        let path = format!("/type_id/1/writer_id/{}", writer_id);
+        let (status, reply) = server.post(&path, data.as_bytes()).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+    }
+    let (status, reply) = server.post("/admin/flush/1", b"").await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(
+        blobs_of(&flushed_file(&reply)),
+        vec![
+            (0, b"first".to_vec()),
+            (1, b"second".to_vec()),
+            (0, b"third".to_vec())
+        ]
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn stored_blob_is_served() {
+    let server = TestServer::start(
+        r#"
+        [[types]]
+        type_id = 2
+        root = "{root}"
+        objects_in_container = 100
+        "#,
+    )
+    .await;
+    let (status, reply) = server.post("/type_id/2/writer_id/0", b"payload").await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    let (_, reply) = server.post("/admin/flush/2", b"").await;
+    let file = flushed_file(&reply);
+    let name = file.file_name().unwrap().to_str().unwrap();
+    let path = format!("/type_id/2/container/{}/blob/0", name);
+    assert_eq!(
+        server.request(Method::GET, &path, b"").await,
+        (StatusCode::OK, "payload".to_string())
+    );
+    let request = Request::get(format!("http://{}{}", server.addr, path))
+        .header(RANGE, "bytes=7-")
+        .body(Body::empty())
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes */7");
+    let reply = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let reply: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+    assert_eq!(reply["reason"], 64);
+    let path = format!("/type_id/2/container/{}/blob/1", name);
+    assert_eq!(
+        server.request(Method::GET, &path, b"").await.0,
+        StatusCode::NOT_FOUND
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn receipt_is_valid_json() {
+    let server = TestServer::start(
+        r#"
+        [[types]]
+        type_id = 5
+        root = "{root}"
+        objects_in_container = 100
+        filename_template = 'say "hi"_{timestamp}.blob'
+        "#,
+    )
+    .await;
+    let (status, reply) = server.post("/type_id/5/writer_id/0", b"quoted").await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    let path = format!("/type_id/5/writer_id/0/sequence/{}", reply["sequence"]);
+    let (status, reply) = server.request(Method::GET, &path, b"").await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", reply);
+    let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert!(reply["container"].is_null());
+    let (_, flushed) = server.post("/admin/flush/5", b"").await;
+    let (status, reply) = server.request(Method::GET, &path, b"").await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert!(reply["container"]
+        .as_str()
+        .unwrap()
+        .starts_with("say \"hi\"_"));
+    assert_eq!(
+        PathBuf::from(reply["file"].as_str().unwrap()),
+        flushed_file(&flushed)
+    );
+    assert_eq!(reply["index"], 0);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn flushed_container_goes_to_the_backend() {
+    let backend = Arc::new(MemoryBackend::default());
+    let server = TestServer::start_with_backend(
+        r#"
+        [[types]]
+        type_id = 4
+        root = "{root}"
+        objects_in_container = 100
+        "#,
+        backend.clone(),
+    )
+    .await;
+    let (status, reply) = server.post("/type_id/4/writer_id/7", b"in memory").await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    let (status, reply) = server.post("/admin/flush/4", b"").await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    let names = backend.names();
+    assert_eq!(names.len(), 1);
+    let container = Container::from_bytes(&backend.get(&names[0]).unwrap()).unwrap();
+    let blobs: Vec<_> = container
+        .blobs()
+        .map(|blob| (blob.writer_id, blob.data.to_vec()))
+        .collect();
+    assert_eq!(blobs, vec![(7, b"in memory".to_vec())]);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn stalled_upload_doesnt_hold_up_the_type() {
+    let server = TestServer::start(
+        r#"
+        [[types]]
+        type_id = 5
+        root = "{root}"
+        objects_in_container = 100
+        "#,
+    )
+    .await;
+    let (mut sender, body) = Body::channel();
+    let request = Request::post(format!("http://{}/type_id/5/writer_id/1", server.addr))
+        .body(body)
+        .unwrap();
+    let stalled = task::spawn(Client::new().request(request));
+    sender.send_data("slow ".into()).await.unwrap();
+
+    let quick = server.post("/type_id/5/writer_id/2", b"quick");
+    let (status, reply) = timeout(Duration::from_secs(5), quick).await.unwrap();
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    let (_, reply) = server.post("/admin/flush/5", b"").await;
+    assert_eq!(
+        blobs_of(&flushed_file(&reply)),
+        vec![(2, b"quick".to_vec())]
+    );
+
+    sender.send_data("upload".into()).await.unwrap();
+    drop(sender);
+    assert_eq!(stalled.await.unwrap().unwrap().status(), StatusCode::OK);
+    let (_, reply) = server.post("/admin/flush/5", b"").await;
+    assert_eq!(
+        blobs_of(&flushed_file(&reply)),
+        vec![(1, b"slow upload".to_vec())]
+    );
+    let uploads = fs::read_dir(&server.root)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("upload".as_ref()))
+        .count();
+    assert_eq!(uploads, 0);
+    server.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn latest_pointer_follows_flushes() {
+    let server = TestServer::start(
+        r#"
+        [[types]]
+        type_id = 3
+        root = "{root}"
+        objects_in_container = 100
+        maintain_latest_pointer = true
+        "#,
+    )
+    .await;
+    for data in ["older", "newer"] {
+        server.post("/type_id/3/writer_id/0", data.as_bytes()).await;
+        let (_, reply) = server.post("/admin/flush/3", b"").await;
+        let pointer = server.root.join("type3_latest.blob");
+        assert_eq!(
+            fs::read_link(&pointer).unwrap(),
+            flushed_file(&reply).strip_prefix(&server.root).unwrap()
+        );
+        assert_eq!(blobs_of(&pointer), vec![(0, data.as_bytes().to_vec())]);
+    }
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn unknown_type_is_rejected() {
+    let server = TestServer::start(
+        r#"
+        [[types]]
+        type_id = 1
+        root = "{root}"
+        objects_in_container = 100
+        "#,
+    )
+    .await;
+    let (status, reply) = server.post("/type_id/9/writer_id/0", b"data").await;
+    assert_eq!(status, StatusCode::OK);
+    let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert_eq!(reply["reason"], 43);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn error_body_follows_accept() {
+    let server = TestServer::start(
+        r#"
+        [[types]]
+        type_id = 1
+        root = "{root}"
+        objects_in_container = 100
+        "#,
+    )
+    .await;
+    for (accept, content_type) in [
+        ("text/plain", "text/plain; charset=utf-8"),
+        ("application/json, text/plain;q=0.5", "application/json"),
+    ] {
+        let request = Request::post(format!("http://{}/type_id/9/writer_id/0", server.addr))
+            .header(ACCEPT, accept)
+            .body(Body::from("data"))
+            .unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], content_type);
+        let reply = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        if content_type == "application/json" {
+            let reply: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+            assert_eq!(
+                reply,
+                serde_json::json!({"state": -1, "reason": 43, "desc": "invalid type_id value"})
+            );
+        } else {
+            assert_eq!(reply, "invalid type_id value\n");
+        }
+    }
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn admin_endpoints_need_enable_admin() {
+    let server = TestServer::start_with_admin(
+        r#"
+        [[types]]
+        type_id = 1
+        root = "{root}"
+        objects_in_container = 100
+        "#,
+        false,
+    )
+    .await;
+    let register = format!(
+        r#"{{"type_id": 2, "root": "{}", "objects_in_container": 10}}"#,
+        server.root.display()
+    );
+    for (path, body) in [
+        ("/admin/flush/1", ""),
+        ("/admin/types", register.as_str()),
+        ("/admin/metrics/reset", ""),
+    ] {
+        let (status, reply) = server.post(path, body.as_bytes()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}: {}", path, reply);
+    }
+    let (_, reply) = server.post("/type_id/2/writer_id/0", b"data").await;
+    let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert_eq!(reply["reason"], 43, "type 2 was registered");
+    server.stop().await;
+}
+
+#[test]
+fn upload_paths_are_parsed() {
+    use PathError::{InvalidId, Malformed};
+    for (path, parsed) in [
+        ("/type_id/1/writer_id/2", Ok((1, 2))),
+        ("/TYPE_ID/1/Writer_Id/2", Ok((1, 2))),
+        ("/type_id/1/writer_id/2/", Ok((1, 2))),
+        ("/type_id/1/writer_id/2?sync=1", Ok((1, 2))),
+        ("/type_id/007/writer_id/02", Ok((7, 2))),
+        ("/type_id/4294967295/writer_id/0", Ok((u32::MAX, 0))),
+        ("/type_id/4294967296/writer_id/0", Err(InvalidId)),
+        ("/type_id/1/writer_id/99999999999999999999", Err(InvalidId)),
+        ("/type_id/+1/writer_id/2", Err(InvalidId)),
+        ("/type_id/ 1/writer_id/2", Err(InvalidId)),
+        ("/type_id/0x1/writer_id/2", Err(InvalidId)),
+        ("/type_id/-1/writer_id/2", Err(InvalidId)),
+        ("/type_id/1 /writer_id/2", Err(InvalidId)),
+        ("/type_id/1e3/writer_id/2", Err(InvalidId)),
+        ("/type_id/1_000/writer_id/2", Err(InvalidId)),
+        ("/type_id/\u{ff11}/writer_id/2", Err(InvalidId)),
+        ("/type_id/1/writer_id/+0", Err(InvalidId)),
+        ("/type_id/1/writer_id/2/extra", Err(Malformed)),
+        ("/type_id/1/writer_id", Err(Malformed)),
+        ("/type/1/writer_id/2", Err(Malformed)),
+        ("/writer_id/2/type_id/1", Err(Malformed)),
+        ("/", Err(Malformed)),
+    ] {
+        assert_eq!(parse_path(path), parsed, "{}", path);
+    }
+}
@@ -0,0 +1,88 @@
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+
+/// How long an upload may go without sending data before it is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tells apart the upload files of the process.
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// A request body received into a file of its own in the type's root, so the writer
+/// copies a finished blob instead of waiting on the client. The file is removed
+/// when this is dropped, whether the blob was stored or not.
+#[derive(Debug)]
+pub struct UploadFile {
+    path: PathBuf,
+    size: u64,
+}
+
+impl UploadFile {
+    /// Writes `body` to a new `.upload` file in `root` as its chunks arrive. Fails
+    /// with `ErrorKind::InvalidData` if the body breaks off, `ErrorKind::TimedOut` if
+    /// it sends nothing for `IDLE_TIMEOUT` and `ErrorKind::InvalidInput` once it grows
+    /// past `u32::MAX` bytes, the most a blob holds; other errors are the file's.
+    pub async fn receive(root: &Path, type_id: u32, mut body: Body) -> io::Result<Self> {
+        let path = root.join(format!(
+            "type{}_{}.upload",
+            type_id,
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = tokio::fs::File::create(&path).await?;
+        // removes the file if the body fails or the request is dropped meanwhile
+        let mut upload = Self { path, size: 0 };
+        loop {
+            let chunk = match timeout(IDLE_TIMEOUT, body.data()).await {
+                Ok(None) => break,
+                Ok(Some(Ok(chunk))) => chunk,
+                Ok(Some(Err(err))) => return Err(io::Error::new(ErrorKind::InvalidData, err)),
+                Err(_) => return Err(io::Error::new(ErrorKind::TimedOut, "upload stalled")),
+            };
+            upload.size += chunk.len() as u64;
+            if upload.size > u32::MAX as u64 {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "blob too large"));
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(upload)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for UploadFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Removes the upload files of `type_id` left in `root` by a crash; their requests
+/// were never answered.
+pub fn remove_leftovers(root: &Path, type_id: u32) -> io::Result<()> {
+    let prefix = format!("type{}_", type_id);
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if name.starts_with(&prefix) && name.ends_with(".upload") {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
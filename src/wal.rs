@@ -0,0 +1,275 @@
+use crate::blob::checksum::{Crc32, DefaultCrc32};
+use crate::blob::content_type::ContentType;
+use crate::blob::meta;
+use crate::blob::meta::BlobMeta;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Starts a blob: writer_id, sequence, timestamp, content type code and metadata.
+const BEGIN: u8 = b'B';
+/// The next piece of the data of the blob begun last.
+const DATA: u8 = b'D';
+/// The blob begun last is complete.
+const END: u8 = b'E';
+/// The blob begun last was dropped, e.g. its upload failed.
+const ABORT: u8 = b'A';
+
+/// The write-ahead log of a writer task: every blob the task accepts is logged here
+/// before the producer gets its reply, and the log is emptied once the container
+/// holding the blobs is stored. A blob is a sequence of records, each framed as
+/// kind, payload length, payload and a CRC32 of kind and payload, so a record torn
+/// by a crash is detected and ends the log.
+pub struct Wal {
+    file: File,
+}
+
+/// A blob found in a log that wasn't emptied.
+pub struct Recovered {
+    pub writer_id: u32,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub content_type: ContentType,
+    pub meta: BlobMeta,
+    pub data: Vec<u8>,
+}
+
+/// The log of writer task `task` of `type_id` under `root`.
+fn path(root: &Path, type_id: u32, task: u32) -> PathBuf {
+    root.join(format!("type{}_{}.wal", type_id, task))
+}
+
+impl Wal {
+    /// Opens the log of writer task `task` and returns the complete blobs left in it.
+    /// With `orphans_from`, it also takes over the logs of the tasks from that one on,
+    /// left behind when `writers_per_type` was lowered or autoscaled tasks ran. The
+    /// log is rewritten to hold exactly the returned blobs, so they stay logged until
+    /// the container they go into is stored.
+    pub fn open(
+        root: &Path,
+        type_id: u32,
+        task: u32,
+        orphans_from: Option<u32>,
+    ) -> io::Result<(Self, Vec<Recovered>)> {
+        let own = path(root, type_id, task);
+        let mut logs = vec![own.clone()];
+        if let Some(orphans_from) = orphans_from {
+            logs.extend(
+                (orphans_from..)
+                    .map(|task| path(root, type_id, task))
+                    .take_while(|orphan| orphan.exists()),
+            );
+        }
+        let mut recovered = Vec::new();
+        for log in logs.iter() {
+            match File::open(log) {
+                Ok(file) => recovered.extend(read(log, BufReader::new(file))?),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let tmp_path = own.with_extension("wal.tmp");
+        let mut rewritten = File::create(&tmp_path)?;
+        for blob in recovered.iter() {
+            rewritten.write_all(&blob_records(
+                blob.writer_id,
+                blob.sequence,
+                blob.timestamp,
+                blob.content_type,
+                &meta::encode(&blob.meta)?,
+                &blob.data,
+            ))?;
+        }
+        rewritten.sync_all()?;
+        fs::rename(&tmp_path, &own)?;
+        for orphan in logs.iter().skip(1) {
+            fs::remove_file(orphan)?;
+        }
+        let file = OpenOptions::new().append(true).open(&own)?;
+        Ok((Self { file }, recovered))
+    }
+
+    /// Logs a whole blob received at or stamped with `timestamp`; with `sync` it is on
+    /// disk when this returns, otherwise it only survives a crash of the process.
+    #[allow(clippy::too_many_arguments)]
+    pub fn log(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        timestamp: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+        data: &[u8],
+        sync: bool,
+    ) -> io::Result<()> {
+        let meta = meta::encode(meta)?;
+        self.file.write_all(&blob_records(
+            writer_id,
+            sequence,
+            timestamp,
+            content_type,
+            &meta,
+            data,
+        ))?;
+        self.synced(sync)
+    }
+
+    /// Starts logging a blob whose data follows with `data`.
+    pub fn begin(
+        &mut self,
+        writer_id: u32,
+        sequence: u64,
+        timestamp: u64,
+        content_type: ContentType,
+        meta: &BlobMeta,
+    ) -> io::Result<()> {
+        let meta = meta::encode(meta)?;
+        let payload = begin_payload(writer_id, sequence, timestamp, content_type, &meta);
+        self.file.write_all(&record(BEGIN, &payload))
+    }
+
+    pub fn data(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(&record(DATA, chunk))
+    }
+
+    /// Completes the blob begun last, see `log` for `sync`.
+    pub fn end(&mut self, sync: bool) -> io::Result<()> {
+        self.file.write_all(&record(END, &[]))?;
+        self.synced(sync)
+    }
+
+    pub fn abort(&mut self) -> io::Result<()> {
+        self.file.write_all(&record(ABORT, &[]))
+    }
+
+    /// Empties the log once its blobs are stored.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
+
+    fn synced(&mut self, sync: bool) -> io::Result<()> {
+        if sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+fn record(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut hasher = <DefaultCrc32 as Crc32>::new();
+    hasher.update(&[kind]);
+    hasher.update(payload);
+    let mut buf = Vec::with_capacity(payload.len() + 9);
+    buf.push(kind);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+    buf
+}
+
+fn begin_payload(
+    writer_id: u32,
+    sequence: u64,
+    timestamp: u64,
+    content_type: ContentType,
+    meta: &[u8],
+) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(24 + meta.len());
+    payload.extend_from_slice(&writer_id.to_le_bytes());
+    payload.extend_from_slice(&sequence.to_le_bytes());
+    payload.extend_from_slice(&timestamp.to_le_bytes());
+    payload.extend_from_slice(&content_type.code().to_le_bytes());
+    payload.extend_from_slice(meta);
+    payload
+}
+
+/// The records of a whole blob, written at once.
+fn blob_records(
+    writer_id: u32,
+    sequence: u64,
+    timestamp: u64,
+    content_type: ContentType,
+    meta: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let payload = begin_payload(writer_id, sequence, timestamp, content_type, meta);
+    let mut records = record(BEGIN, &payload);
+    records.extend(record(DATA, data));
+    records.extend(record(END, &[]));
+    records
+}
+
+/// Reads the complete blobs of a log, stopping at the first torn or damaged record.
+fn read<R: Read>(path: &Path, mut reader: R) -> io::Result<Vec<Recovered>> {
+    let mut recovered = Vec::new();
+    let mut current: Option<Recovered> = None;
+    loop {
+        let kind = match reader.read_u8() {
+            Ok(kind) => kind,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+        let frame = reader.read_u32::<LittleEndian>().and_then(|len| {
+            // a damaged length mustn't allocate more than the log holds
+            let mut payload = Vec::new();
+            (&mut reader).take(len as u64).read_to_end(&mut payload)?;
+            if payload.len() < len as usize {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof));
+            }
+            let crc = reader.read_u32::<LittleEndian>()?;
+            Ok((payload, crc))
+        });
+        let payload = match frame {
+            Ok((payload, crc)) => {
+                let mut hasher = <DefaultCrc32 as Crc32>::new();
+                hasher.update(&[kind]);
+                hasher.update(&payload);
+                if hasher.finalize() != crc {
+                    warn!(path = %path.display(), "damaged write-ahead log record, ignoring the rest");
+                    break;
+                }
+                payload
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                warn!(path = %path.display(), "torn write-ahead log record, ignoring the rest");
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        match kind {
+            BEGIN => current = parse_begin(&payload),
+            DATA => {
+                if let Some(blob) = current.as_mut() {
+                    blob.data.extend_from_slice(&payload);
+                }
+            }
+            END => recovered.extend(current.take()),
+            ABORT => current = None,
+            _ => {
+                warn!(path = %path.display(), kind, "unknown write-ahead log record, ignoring the rest");
+                break;
+            }
+        }
+    }
+    Ok(recovered)
+}
+
+fn parse_begin(mut payload: &[u8]) -> Option<Recovered> {
+    let writer_id = payload.read_u32::<LittleEndian>().ok()?;
+    let sequence = payload.read_u64::<LittleEndian>().ok()?;
+    let timestamp = payload.read_u64::<LittleEndian>().ok()?;
+    let content_type = ContentType::from_code(payload.read_u32::<LittleEndian>().ok()?);
+    Some(Recovered {
+        writer_id,
+        sequence,
+        timestamp,
+        content_type,
+        meta: meta::decode(payload).ok()?,
+        data: Vec::new(),
+    })
+}
@@ -0,0 +1,1456 @@
+use crate::autoscale;
+use crate::backend;
+use crate::backend::StorageBackend;
+use crate::blob::content_type::ContentType;
+use crate::blob::meta::BlobMeta;
+use crate::blob::storage;
+use crate::blob::storage::{now_micros, BlobError, ContainerWriter};
+use crate::config::{OverCap, Rotation, SyncPolicy, TypeConfig, WriterCapConfig};
+use crate::fsync::Syncer;
+use crate::health::Health;
+use crate::latest;
+use crate::manifest;
+use crate::metrics::{
+    FlushLabels, FlushPhase, FlushTrigger, TypeLabels, WriterLabels, WriterMetrics,
+};
+use crate::paths;
+use crate::paths::FilenameTemplate;
+use crate::receipts::{Lookup, Receipts};
+use crate::sidecar;
+use crate::upload;
+use crate::upload::UploadFile;
+use crate::wal::Wal;
+use futures_util::FutureExt;
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::pending;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::mem;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task;
+use tokio::time::sleep;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use xxhash_rust::xxh64::xxh64;
+
+/// Pause before a failed writer is restarted, so a persistent fault doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Bytes of an upload file copied into the container at a time.
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+/// Attempts to store a finished container before the writer gives up on it.
+const FLUSH_ATTEMPTS: u32 = 5;
+/// Pause after the first failed attempt, doubled after each further one.
+const FLUSH_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Blobs in a container that force a flush unless `max_objects_hard_limit` is set.
+const DEFAULT_MAX_OBJECTS_HARD_LIMIT: u32 = 1_000_000;
+/// TOC entries reserved for a new container at most; beyond them the TOC grows as
+/// blobs arrive, so a mistyped `objects_in_container` can't allocate gigabytes up
+/// front.
+const MAX_RESERVED_OBJECTS: usize = 16_384;
+
+#[derive(Debug)]
+pub struct PostData {
+    data: BlobData,
+    writer_id: u32,
+    sequence: u64,
+    content_type: ContentType,
+    meta: BlobMeta,
+    /// the time of the event the blob holds, in microseconds since the epoch; the
+    /// blob is stamped with the time it is written when not given
+    event_time: Option<u64>,
+    /// told once a buffered blob is in the write-ahead log
+    logged: Option<oneshot::Sender<io::Result<()>>>,
+}
+
+impl PostData {
+    pub fn new(writer_id: u32, content_type: ContentType, meta: BlobMeta, data: Vec<u8>) -> Self {
+        Self {
+            data: BlobData::Buffered(data),
+            writer_id,
+            sequence: 0,
+            content_type,
+            meta,
+            event_time: None,
+            logged: None,
+        }
+    }
+
+    /// Stamps the blob with `event_time` instead of the time it is written.
+    pub fn with_event_time(mut self, event_time: Option<u64>) -> Self {
+        self.event_time = event_time;
+        self
+    }
+
+    /// Has the writer report on `logged` whether the blob made it into the type's
+    /// write-ahead log; an uploaded blob reports on its `stored` channel instead.
+    pub fn acknowledged(mut self, logged: oneshot::Sender<io::Result<()>>) -> Self {
+        self.logged = Some(logged);
+        self
+    }
+
+    /// A blob received into an upload file by the request handler, which the writer
+    /// copies into its spool file chunk by chunk, so it never holds the whole blob in
+    /// memory. Whether it was stored is sent on `stored`.
+    pub fn uploaded(
+        writer_id: u32,
+        content_type: ContentType,
+        meta: BlobMeta,
+        upload: UploadFile,
+        stored: oneshot::Sender<io::Result<()>>,
+    ) -> Self {
+        Self {
+            data: BlobData::Uploaded { upload, stored },
+            writer_id,
+            sequence: 0,
+            content_type,
+            meta,
+            event_time: None,
+            logged: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BlobData {
+    Buffered(Vec<u8>),
+    Uploaded {
+        upload: UploadFile,
+        stored: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+impl BlobData {
+    fn size(&self) -> u64 {
+        match self {
+            Self::Buffered(data) => data.len() as u64,
+            Self::Uploaded { upload, .. } => upload.size(),
+        }
+    }
+}
+
+/// Messages for a writer task that aren't blobs.
+pub enum Control {
+    /// Writes out the current container right away and replies with the file it went
+    /// to, or `None` when nothing was buffered.
+    Flush(oneshot::Sender<Option<String>>),
+    /// Stops taking blobs, writes the ones queued until then into the current
+    /// container, stores it and ends the task, replying like `Flush`.
+    Shutdown(oneshot::Sender<Option<String>>),
+    /// Replaces the type's settings. The rotation limit applies to the current
+    /// container; the root and interval windows take effect from the next one.
+    Reconfigure(Box<TypeConfig>),
+}
+
+/// The sending ends of the data and control channels of a type's writer tasks.
+#[derive(Clone)]
+pub struct WriterHandle {
+    /// the running tasks, in the order of their numbers, which name their
+    /// write-ahead logs
+    tasks: Arc<RwLock<Vec<TaskHandle>>>,
+    /// where the next tie between equally loaded tasks is broken
+    next_task: Arc<AtomicUsize>,
+    /// last sequence number handed out per writer_id
+    sequences: Arc<Mutex<HashMap<u32, u64>>>,
+    /// the type's current settings, given to tasks added later
+    config: Arc<RwLock<TypeConfig>>,
+    shared: Arc<Shared>,
+}
+
+/// One writer task of a type.
+struct TaskHandle {
+    data: UnboundedSender<PostData>,
+    control: UnboundedSender<Control>,
+    /// blobs sent to the task and not yet written into its container
+    backlog: Arc<AtomicUsize>,
+}
+
+/// What the writer tasks of a type share.
+struct Shared {
+    type_id: u32,
+    health: Arc<Health>,
+    metrics: WriterMetrics,
+    receipts: Receipts,
+    /// timestamps and numbers of the type's container files
+    files: FileIds,
+    syncer: Arc<Syncer>,
+    /// writer tasks of the type at start; the logs of tasks from this one on are
+    /// taken over by task 0 when it first opens its log
+    tasks: u32,
+    orphans_adopted: AtomicBool,
+    wal: bool,
+    /// spool files left behind by an earlier run, with `resume_spool`; task 0 stores
+    /// their containers before it builds its first one
+    leftover_spools: Mutex<Vec<PathBuf>>,
+    /// file time of the container `maintain_latest_pointer` points at, so a task
+    /// finishing an older container after a newer one doesn't move it back
+    latest: Arc<Mutex<u64>>,
+    /// used instead of the configured backend, see `spawn_with_backend`
+    backend: Option<Arc<dyn StorageBackend>>,
+    /// set by `WriterHandle::shutdown`, no tasks are added anymore
+    stopping: AtomicBool,
+}
+
+/// Hands out the timestamps and `{seq}` numbers of a type's containers, unique
+/// across its writer tasks so they never pick the same file name.
+#[derive(Default)]
+struct FileIds {
+    /// microseconds since the epoch
+    last_time: AtomicU64,
+    last_seq: AtomicU64,
+}
+
+impl FileIds {
+    /// The current time, or one microsecond after the last one handed out.
+    fn next_time(&self) -> u64 {
+        let now = now_micros();
+        let last = self
+            .last_time
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        now.max(last + 1)
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.last_seq.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl WriterHandle {
+    /// Assigns the next sequence number of the blob's writer_id and queues it with the
+    /// least loaded writer task of the type.
+    ///
+    /// Numbers start at 1, increase by one per blob of a (type_id, writer_id) pair and
+    /// restart when the process does. Assigning and queueing happen under one lock, so
+    /// with a single writer task the blobs of a writer_id reach the container in
+    /// sequence order; blobs of different writer_ids interleave in arrival order. With
+    /// several tasks the blobs of a writer_id spread over their files in no particular
+    /// order. Merged or appended files keep the numbers, so consumers can restore the
+    /// order of a writer from them. Returns the assigned number, which `lookup` finds
+    /// the blob by.
+    pub fn send(&self, post: PostData) -> Result<u64, Box<SendError<PostData>>> {
+        Ok(self.send_all(vec![post])?[0])
+    }
+
+    /// Like `send` for several blobs at once; the blobs of a writer_id get consecutive
+    /// numbers. Fails once a task has stopped taking blobs, e.g. after a shutdown; the
+    /// blobs not sent by then are taken off the queue `health` counts.
+    pub fn send_all(&self, posts: Vec<PostData>) -> Result<Vec<u64>, Box<SendError<PostData>>> {
+        let mut sequences = self.sequences.lock().unwrap();
+        let tasks = self.tasks.read().unwrap();
+        let mut assigned = Vec::with_capacity(posts.len());
+        let unsent = posts.len();
+        for (sent, mut post) in posts.into_iter().enumerate() {
+            let sequence = sequences.entry(post.writer_id).or_insert(0);
+            *sequence += 1;
+            post.sequence = *sequence;
+            assigned.push(*sequence);
+            self.shared.receipts.queued(post.writer_id, post.sequence);
+            let task = self.least_loaded(&tasks);
+            task.backlog.fetch_add(1, Ordering::Relaxed);
+            if let Err(err) = task.data.send(post) {
+                task.backlog.fetch_sub(1, Ordering::Relaxed);
+                self.shared
+                    .receipts
+                    .dropped(err.0.writer_id, err.0.sequence);
+                for _ in sent..unsent {
+                    self.shared.health.dequeued(self.shared.type_id);
+                }
+                return Err(Box::new(err));
+            }
+        }
+        Ok(assigned)
+    }
+
+    /// The task with the smallest backlog, the search starting one task further on
+    /// each call so ties are spread round-robin.
+    fn least_loaded<'a>(&self, tasks: &'a [TaskHandle]) -> &'a TaskHandle {
+        let start = self.next_task.fetch_add(1, Ordering::Relaxed);
+        (0..tasks.len())
+            .map(|offset| &tasks[(start + offset) % tasks.len()])
+            .min_by_key(|task| task.backlog.load(Ordering::Relaxed))
+            .unwrap()
+    }
+
+    /// Blobs sent to the type's writer tasks and not yet written into a container.
+    pub fn backlog(&self) -> usize {
+        let tasks = self.tasks.read().unwrap();
+        tasks
+            .iter()
+            .map(|task| task.backlog.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn task_count(&self) -> usize {
+        self.tasks.read().unwrap().len()
+    }
+
+    /// Whether `shutdown` was called.
+    pub fn stopping(&self) -> bool {
+        self.shared.stopping.load(Ordering::Relaxed)
+    }
+
+    /// Starts one more writer task with the current settings. Returns `false` once
+    /// the writers are shutting down.
+    pub fn add_task(&self) -> bool {
+        if self.stopping() {
+            return false;
+        }
+        let mut tasks = self.tasks.write().unwrap();
+        let config = self.config.read().unwrap().clone();
+        let task = spawn_task(config, tasks.len() as u32, &self.shared);
+        tasks.push(task);
+        self.shared.count_tasks(tasks.len());
+        true
+    }
+
+    /// Stops the last writer task: it gets no more blobs, writes the ones queued
+    /// for it into its container and stores it. Returns the file written, `None`
+    /// when there was nothing to store or only one task is left.
+    pub async fn retire_task(&self) -> Option<String> {
+        let task = {
+            let mut tasks = self.tasks.write().unwrap();
+            if tasks.len() <= 1 {
+                return None;
+            }
+            let task = tasks.pop()?;
+            self.shared.count_tasks(tasks.len());
+            task
+        };
+        let (reply, written) = oneshot::channel();
+        task.control.send(Control::Shutdown(reply)).ok()?;
+        written.await.ok()?
+    }
+
+    /// Where the blob with `sequence` of `writer_id` went, if it was flushed recently.
+    pub fn lookup(&self, writer_id: u32, sequence: u64) -> Lookup {
+        self.shared.receipts.lookup(writer_id, sequence)
+    }
+
+    /// Sends `Control::Flush` to every writer task and returns the files written, or
+    /// `None` if a task is gone.
+    pub async fn flush(&self) -> Option<Vec<String>> {
+        self.control_all(Control::Flush).await
+    }
+
+    /// Sends `Control::Shutdown` to every writer task and returns the files written
+    /// once they have stopped, or `None` if a task was gone already.
+    pub async fn shutdown(&self) -> Option<Vec<String>> {
+        self.shared.stopping.store(true, Ordering::Relaxed);
+        self.control_all(Control::Shutdown).await
+    }
+
+    async fn control_all<F>(&self, control: F) -> Option<Vec<String>>
+    where
+        F: Fn(oneshot::Sender<Option<String>>) -> Control,
+    {
+        let mut flushed = Vec::new();
+        for task in self.tasks.read().unwrap().iter() {
+            let (reply, written) = oneshot::channel();
+            task.control.send(control(reply)).ok()?;
+            flushed.push(written);
+        }
+        let mut files = Vec::new();
+        for written in flushed {
+            files.extend(written.await.ok()?);
+        }
+        Some(files)
+    }
+
+    /// Sends `Control::Reconfigure` to every writer task; `root`, `template` and
+    /// `writer_count` report the new settings right away. The number of tasks stays as
+    /// it is, so settings that append are rejected while there are several.
+    pub fn reconfigure(&self, type_id: TypeConfig) -> io::Result<()> {
+        let tasks = self.tasks.read().unwrap();
+        if tasks.len() > 1 && type_id.appends() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "type {}: its {} writer tasks can't append to the same file",
+                    type_id.type_id,
+                    tasks.len()
+                ),
+            ));
+        }
+        *self.config.write().unwrap() = type_id.clone();
+        for task in tasks.iter() {
+            task.control
+                .send(Control::Reconfigure(Box::new(type_id.clone())))
+                .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        }
+        Ok(())
+    }
+
+    pub fn root(&self) -> PathBuf {
+        PathBuf::from(self.config.read().unwrap().root.as_str())
+    }
+
+    pub fn template(&self) -> FilenameTemplate {
+        self.config.read().unwrap().filename_template()
+    }
+
+    /// Whether the type has a write-ahead log, so buffered blobs should be
+    /// `acknowledged` before the producer is answered.
+    pub fn logs_ahead(&self) -> bool {
+        self.shared.wal
+    }
+
+    /// Producers of the type post with writer_ids below this.
+    pub fn writer_count(&self) -> u32 {
+        self.config.read().unwrap().writer_count()
+    }
+}
+
+impl Shared {
+    /// The injected backend if there is one, otherwise the one `type_id` configures.
+    fn backend(&self, type_id: &TypeConfig) -> io::Result<Arc<dyn StorageBackend>> {
+        match &self.backend {
+            Some(backend) => Ok(backend.clone()),
+            None => backend::for_type(type_id),
+        }
+    }
+
+    fn count_tasks(&self, tasks: usize) {
+        self.metrics
+            .tasks
+            .get_or_create(&TypeLabels {
+                type_id: self.type_id,
+            })
+            .set(tasks as u64);
+    }
+}
+
+/// Spawns the `writers_per_type` writer tasks of `type_id`, each building its own
+/// containers. A task is restarted when it fails or panics and only marked dead in
+/// `health` once its channels are closed.
+pub fn spawn(type_id: TypeConfig, health: Arc<Health>, metrics: WriterMetrics) -> WriterHandle {
+    spawn_writers(type_id, health, metrics, None)
+}
+
+/// Like `spawn`, but the writer tasks store their containers in `backend` whatever
+/// the configuration says, e.g. a `MemoryBackend` to check what a test produced.
+#[cfg(test)]
+pub fn spawn_with_backend(
+    type_id: TypeConfig,
+    health: Arc<Health>,
+    metrics: WriterMetrics,
+    backend: Arc<dyn StorageBackend>,
+) -> WriterHandle {
+    spawn_writers(type_id, health, metrics, Some(backend))
+}
+
+fn spawn_writers(
+    type_id: TypeConfig,
+    health: Arc<Health>,
+    metrics: WriterMetrics,
+    backend: Option<Arc<dyn StorageBackend>>,
+) -> WriterHandle {
+    health.track_queue(type_id.type_id, type_id.max_queued);
+    if let Err(err) = upload::remove_leftovers(Path::new(type_id.root.as_str()), type_id.type_id) {
+        warn!(%err, type_id = type_id.type_id, "removing leftover upload files failed");
+    }
+    // before any task creates a spool file of its own
+    let leftover_spools = if type_id.resume_spool {
+        leftover_spools(&type_id).unwrap_or_else(|err| {
+            warn!(%err, type_id = type_id.type_id, "looking for spool files to resume failed");
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let shared = Arc::new(Shared {
+        type_id: type_id.type_id,
+        syncer: Syncer::spawn(&type_id, &metrics),
+        health,
+        metrics,
+        receipts: Receipts::default(),
+        files: FileIds::default(),
+        tasks: type_id.writers_per_type.unwrap_or(1),
+        orphans_adopted: AtomicBool::new(false),
+        wal: type_id.wal,
+        leftover_spools: Mutex::new(leftover_spools),
+        latest: Arc::new(Mutex::new(0)),
+        backend,
+        stopping: AtomicBool::new(false),
+    });
+    let tasks: Vec<TaskHandle> = (0..shared.tasks)
+        .map(|task| spawn_task(type_id.clone(), task, &shared))
+        .collect();
+    shared.count_tasks(tasks.len());
+    let handle = WriterHandle {
+        tasks: Arc::new(RwLock::new(tasks)),
+        next_task: Arc::new(AtomicUsize::new(0)),
+        sequences: Arc::new(Mutex::new(HashMap::new())),
+        config: Arc::new(RwLock::new(type_id.clone())),
+        shared,
+    };
+    autoscale::spawn(&type_id, handle.clone());
+    handle
+}
+
+fn spawn_task(type_id: TypeConfig, task: u32, shared: &Arc<Shared>) -> TaskHandle {
+    let (data, receiver) = unbounded_channel();
+    let (control, controls) = unbounded_channel();
+    let backlog = Arc::new(AtomicUsize::new(0));
+    let shared = shared.clone();
+    let task_backlog = backlog.clone();
+    task::spawn(async move {
+        supervise(type_id, task, receiver, controls, &task_backlog, &shared).await;
+    });
+    TaskHandle {
+        data,
+        control,
+        backlog,
+    }
+}
+
+/// Runs the writer task until it is shut down or its channels close, restarting it
+/// after errors and panics. The channels and the latest config outlive each run, so
+/// queued blobs are kept; the blobs of the container being built or stored when the
+/// writer failed stay in its spool file. A task whose channels closed is marked dead
+/// in `health`.
+async fn supervise(
+    mut type_id: TypeConfig,
+    task: u32,
+    mut receiver: UnboundedReceiver<PostData>,
+    mut controls: UnboundedReceiver<Control>,
+    backlog: &AtomicUsize,
+    shared: &Shared,
+) {
+    let labels = TypeLabels {
+        type_id: type_id.type_id,
+    };
+    let span = info_span!("writer", type_id = type_id.type_id, task);
+    loop {
+        shared.metrics.live.inc();
+        let result = AssertUnwindSafe(write_containers(
+            &mut type_id,
+            task,
+            &mut receiver,
+            &mut controls,
+            backlog,
+            shared,
+        ))
+        .catch_unwind()
+        .instrument(span.clone())
+        .await;
+        shared.metrics.live.dec();
+        match result {
+            Ok(Err(err)) if err.kind() == ErrorKind::BrokenPipe => {
+                span.in_scope(|| info!("writer stopped, its queue was closed"));
+                shared.health.writer_exited();
+                return;
+            }
+            Ok(Err(err)) => span.in_scope(|| error!(%err, "writer failed, restarting")),
+            Err(_) => span.in_scope(|| error!("writer panicked, restarting")),
+            Ok(Ok(())) => {
+                span.in_scope(|| info!("writer stopped"));
+                return;
+            }
+        }
+        shared.metrics.restarts.get_or_create(&labels).inc();
+        sleep(RESTART_DELAY).await;
+    }
+}
+
+async fn write_containers(
+    type_id: &mut TypeConfig,
+    task: u32,
+    receiver: &mut UnboundedReceiver<PostData>,
+    controls: &mut UnboundedReceiver<Control>,
+    backlog: &AtomicUsize,
+    shared: &Shared,
+) -> io::Result<()> {
+    let (health, metrics) = (&shared.health, &shared.metrics);
+    let mut rotation = type_id.rotation();
+    let mut backend = shared.backend(type_id)?;
+    let (mut wal, mut recovered) = if shared.wal {
+        // later the logs beyond the initial tasks may belong to added ones
+        let orphans_from = (task == 0 && !shared.orphans_adopted.swap(true, Ordering::Relaxed))
+            .then_some(shared.tasks);
+        let (root, id) = (PathBuf::from(type_id.root.as_str()), type_id.type_id);
+        let (wal, recovered) = blocking(move || Wal::open(&root, id, task, orphans_from)).await?;
+        if !recovered.is_empty() {
+            info!(
+                blobs = recovered.len(),
+                "blobs recovered from the write-ahead log"
+            );
+        }
+        (Some(wal), recovered)
+    } else {
+        (None, Vec::new())
+    };
+    if task == 0 {
+        resume_spools(type_id, &backend, shared).await?;
+    }
+    // set once `Control::Shutdown` closed the queue; answered when it is drained
+    let mut shutdown_reply = None;
+    // taken off the queue but not handled yet: a blob over its writer's cap or
+    // `hard_max_bytes`, carried into the next container, or one found while looking
+    // for a pause to flush at `soft_max_bytes`
+    let mut spilled = None;
+    loop {
+        // file names carry microseconds since the epoch, like the TOC entry timestamps
+        let creation_time = shared.files.next_time();
+        let (file_time, window_end) = match rotation {
+            Rotation::Count { .. } => (creation_time, None),
+            Rotation::Interval { interval_secs } => {
+                let interval = interval_secs * 1_000_000;
+                let window_start = creation_time - creation_time % interval;
+                let jitter = flush_jitter(type_id, window_start);
+                (window_start, Some(window_start + interval + jitter))
+            }
+        };
+        let root = Path::new(type_id.root.as_str()).to_path_buf();
+        let spool_path = root.join(format!(
+            "type{}_{}.blob.spool",
+            type_id.type_id, creation_time
+        ));
+        let id = type_id.type_id;
+        let checksum_algo = type_id.checksum.unwrap_or_default();
+        let checksum_scope = type_id.checksum_scope.unwrap_or_default();
+        let dedup = type_id.dedup;
+        let permissions = type_id.file_permissions();
+        let resume_spool = type_id.resume_spool;
+        let block_threshold = type_id.block_threshold();
+        let hard_limit = type_id
+            .max_objects_hard_limit
+            .unwrap_or(DEFAULT_MAX_OBJECTS_HARD_LIMIT) as usize;
+        let expected = match rotation {
+            Rotation::Count { count } => (count as usize).min(hard_limit).min(MAX_RESERVED_OBJECTS),
+            Rotation::Interval { .. } => 0,
+        };
+        let recovered = mem::take(&mut recovered);
+        let mut tallies: HashMap<u32, Tally> = HashMap::new();
+        for blob in recovered.iter() {
+            tallies
+                .entry(blob.writer_id)
+                .or_default()
+                .add(blob.data.len() as u64);
+        }
+        let container = blocking(move || {
+            let mut container = ContainerWriter::create(id, checksum_algo, spool_path)?;
+            container.set_file_permissions(permissions)?;
+            container.reserve(expected.max(recovered.len()));
+            container.set_checksum_scope(checksum_scope);
+            if dedup {
+                container.enable_dedup();
+            }
+            if resume_spool {
+                container.enable_journal()?;
+            }
+            if let Some(block_threshold) = block_threshold {
+                container.enable_compression(block_threshold);
+            }
+            for blob in recovered {
+                container.push_with_timestamp(
+                    blob.writer_id,
+                    blob.sequence,
+                    blob.timestamp,
+                    blob.content_type,
+                    &blob.meta,
+                    &blob.data,
+                )?;
+            }
+            Ok(container)
+        })
+        .await?;
+        let mut building = Building {
+            container,
+            wal: wal.take(),
+            sync: type_id.sync_policy == Some(SyncPolicy::Always),
+        };
+        let window_closed = sleep_until_micros(window_end);
+        tokio::pin!(window_closed);
+        let mut flush_reply = None;
+        // reset on every blob with `idle_flush_ms`
+        let mut idle_deadline = None;
+        let trigger = loop {
+            let container = &building.container;
+            if let Rotation::Count { count } = rotation {
+                if container.len() >= count as usize {
+                    break FlushTrigger::Count;
+                }
+            }
+            let hard_limit = type_id
+                .max_objects_hard_limit
+                .unwrap_or(DEFAULT_MAX_OBJECTS_HARD_LIMIT);
+            if container.len() >= hard_limit as usize {
+                warn!(
+                    objects = container.len(),
+                    hard_limit, "max_objects_hard_limit reached, flushing early"
+                );
+                break FlushTrigger::HardLimit;
+            }
+            let data_size = container.data_size();
+            if type_id.hard_max_bytes.is_some_and(|hard| data_size >= hard) {
+                break FlushTrigger::HardMaxBytes;
+            }
+            if spilled.is_none() && type_id.soft_max_bytes.is_some_and(|soft| data_size >= soft) {
+                match receiver.try_recv() {
+                    Ok(obj) => {
+                        health.dequeued(type_id.type_id);
+                        spilled = Some(obj);
+                    }
+                    Err(_) => break FlushTrigger::SoftMaxBytes,
+                }
+            }
+            let obj: PostData = match spilled.take() {
+                Some(obj) => obj,
+                None => {
+                    let obj = tokio::select! {
+                        obj = receiver.recv() => match obj {
+                            None if shutdown_reply.is_some() => break FlushTrigger::Shutdown,
+                            None => return Err(io::Error::from(ErrorKind::BrokenPipe)),
+                            Some(obj) => obj,
+                        },
+                        Some(control) = controls.recv() => match control {
+                            Control::Flush(reply) => {
+                                flush_reply = Some(reply);
+                                break FlushTrigger::Admin;
+                            }
+                            Control::Shutdown(reply) => {
+                                info!(queued = backlog.load(Ordering::Relaxed), "shutting down");
+                                receiver.close();
+                                shutdown_reply = Some(reply);
+                                continue;
+                            }
+                            Control::Reconfigure(new_type_id) => {
+                                info!(?rotation, new_rotation = ?new_type_id.rotation(), "reconfigured");
+                                match shared.backend(&new_type_id) {
+                                    Ok(new_backend) => backend = new_backend,
+                                    Err(err) => error!(%err, "keeping the previous storage backend"),
+                                }
+                                *type_id = *new_type_id;
+                                rotation = type_id.rotation();
+                                health.track_queue(type_id.type_id, type_id.max_queued);
+                                continue;
+                            }
+                        },
+                        _ = &mut window_closed => break FlushTrigger::Time,
+                        _ = sleep_until_micros(idle_deadline), if !container.is_empty() => break FlushTrigger::Idle,
+                    };
+                    health.dequeued(type_id.type_id);
+                    obj
+                }
+            };
+            if let Some(cap) = &type_id.writer_cap {
+                let tally = tallies.get(&obj.writer_id).copied().unwrap_or_default();
+                if tally.over(cap, obj.data.size()) {
+                    metrics
+                        .cap_hits
+                        .get_or_create(&WriterLabels {
+                            type_id: type_id.type_id,
+                            writer_id: obj.writer_id,
+                        })
+                        .inc();
+                    if cap.over_cap.unwrap_or_default() == OverCap::Spill {
+                        debug!(
+                            writer_id = obj.writer_id,
+                            "writer_cap reached, blob spilled"
+                        );
+                        spilled = Some(obj);
+                        break FlushTrigger::WriterCap;
+                    }
+                    debug!(
+                        writer_id = obj.writer_id,
+                        "writer_cap reached, blob rejected"
+                    );
+                    reject_over_cap(obj, shared);
+                    backlog.fetch_sub(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+            if let Some(hard) = type_id.hard_max_bytes {
+                let container = &building.container;
+                if !container.is_empty() && container.data_size() + obj.data.size() > hard {
+                    debug!(
+                        writer_id = obj.writer_id,
+                        "hard_max_bytes reached, blob carried into the next container"
+                    );
+                    spilled = Some(obj);
+                    break FlushTrigger::HardMaxBytes;
+                }
+            }
+            idle_deadline = type_id
+                .idle_flush_ms
+                .map(|idle_flush_ms| now_micros() + idle_flush_ms * 1000);
+            let timestamp = obj.event_time.unwrap_or_else(now_micros);
+            building = match obj.data {
+                BlobData::Buffered(data) => {
+                    let size = data.len() as u64;
+                    debug!(writer_id = obj.writer_id, size, "blob received");
+                    let (building, logged) = blocking(move || {
+                        let mut building = building;
+                        let logged = match building.wal.as_mut() {
+                            None => Ok(()),
+                            Some(wal) => wal.log(
+                                obj.writer_id,
+                                obj.sequence,
+                                timestamp,
+                                obj.content_type,
+                                &obj.meta,
+                                data.as_slice(),
+                                building.sync,
+                            ),
+                        };
+                        if logged.is_ok() {
+                            building.container.push_with_timestamp(
+                                obj.writer_id,
+                                obj.sequence,
+                                timestamp,
+                                obj.content_type,
+                                &obj.meta,
+                                data.as_slice(),
+                            )?;
+                        }
+                        Ok((building, logged))
+                    })
+                    .await?;
+                    match &logged {
+                        Ok(()) => {
+                            count_ingested(metrics, type_id.type_id, size);
+                            tallies.entry(obj.writer_id).or_default().add(size);
+                        }
+                        Err(err) => {
+                            warn!(writer_id = obj.writer_id, %err, "logging the blob failed, dropped");
+                            shared.receipts.dropped(obj.writer_id, obj.sequence);
+                        }
+                    }
+                    if let Some(ack) = obj.logged {
+                        let _ = ack.send(logged);
+                    }
+                    building
+                }
+                BlobData::Uploaded { upload, stored } => {
+                    let size = upload.size();
+                    debug!(writer_id = obj.writer_id, size, "uploaded blob received");
+                    let (building, copied) = blocking(move || {
+                        let mut building = building;
+                        let copied = push_upload(
+                            &mut building,
+                            obj.writer_id,
+                            obj.sequence,
+                            timestamp,
+                            obj.content_type,
+                            &obj.meta,
+                            &upload,
+                        )?;
+                        Ok((building, copied))
+                    })
+                    .await?;
+                    match &copied {
+                        Ok(()) => {
+                            count_ingested(metrics, type_id.type_id, size);
+                            tallies.entry(obj.writer_id).or_default().add(size);
+                        }
+                        Err(err) => {
+                            warn!(writer_id = obj.writer_id, %err, "uploaded blob dropped");
+                            shared.receipts.dropped(obj.writer_id, obj.sequence);
+                        }
+                    }
+                    let _ = stored.send(copied);
+                    building
+                }
+            };
+            backlog.fetch_sub(1, Ordering::Relaxed);
+        };
+        let Building {
+            container,
+            wal: mut used_wal,
+            ..
+        } = building;
+        let path = if container.is_empty() {
+            blocking(move || container.discard()).await?;
+            None
+        } else {
+            let seq = shared.files.next_seq();
+            let flushed = flush(type_id, &backend, file_time, seq, container, shared).await?;
+            metrics
+                .flushes
+                .get_or_create(&FlushLabels {
+                    type_id: type_id.type_id,
+                    trigger,
+                })
+                .inc();
+            Some(flushed)
+        };
+        // the blobs of the log are stored now, or were dropped
+        if let Some(mut stored_wal) = used_wal.take() {
+            wal = Some(
+                blocking(move || {
+                    stored_wal.truncate()?;
+                    Ok(stored_wal)
+                })
+                .await?,
+            );
+        }
+        if trigger == FlushTrigger::Shutdown {
+            if let Some(reply) = shutdown_reply.take() {
+                let _ = reply.send(path);
+            }
+            return Ok(());
+        }
+        if let Some(reply) = flush_reply {
+            let _ = reply.send(path);
+        }
+    }
+}
+
+/// The spool files of `type_id` in its root that have a TOC journal.
+fn leftover_spools(type_id: &TypeConfig) -> io::Result<Vec<PathBuf>> {
+    let prefix = format!("type{}_", type_id.type_id);
+    let mut spools = Vec::new();
+    for entry in std::fs::read_dir(type_id.root.as_str())? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if name.starts_with(&prefix)
+            && name.ends_with(".blob.spool")
+            && storage::journal_path(&path).exists()
+        {
+            spools.push(path);
+        }
+    }
+    spools.sort();
+    Ok(spools)
+}
+
+/// Stores the containers of the spool files left behind, named as if they were
+/// flushed now, or into the interval window they were created in. A spool file
+/// that can't be read is left alone; one that fails to be stored is kept for the
+/// restarted task.
+async fn resume_spools(
+    type_id: &TypeConfig,
+    backend: &Arc<dyn StorageBackend>,
+    shared: &Shared,
+) -> io::Result<()> {
+    loop {
+        let spool_path = match shared.leftover_spools.lock().unwrap().last() {
+            None => return Ok(()),
+            Some(spool_path) => spool_path.clone(),
+        };
+        let (id, checksum_algo) = (type_id.type_id, type_id.checksum.unwrap_or_default());
+        let checksum_scope = type_id.checksum_scope.unwrap_or_default();
+        let permissions = type_id.file_permissions();
+        let block_threshold = type_id.block_threshold();
+        let resume_path = spool_path.clone();
+        let resumed = blocking(move || {
+            let mut container = ContainerWriter::resume(id, checksum_algo, resume_path)?;
+            container.set_file_permissions(permissions)?;
+            container.set_checksum_scope(checksum_scope);
+            if let Some(block_threshold) = block_threshold {
+                container.enable_compression(block_threshold);
+            }
+            Ok(container)
+        })
+        .await;
+        match resumed {
+            Err(err) => warn!(%err, path = %spool_path.display(), "resuming the spool file failed"),
+            Ok(container) if container.is_empty() => blocking(move || container.discard()).await?,
+            Ok(container) => {
+                let objects = container.len();
+                let file_time = match type_id.rotation() {
+                    Rotation::Count { .. } => shared.files.next_time(),
+                    Rotation::Interval { interval_secs } => {
+                        let created = container.created();
+                        created - created % (interval_secs * 1_000_000)
+                    }
+                };
+                let seq = shared.files.next_seq();
+                flush(type_id, backend, file_time, seq, container, shared).await?;
+                info!(path = %spool_path.display(), objects, "spool file resumed");
+                shared
+                    .metrics
+                    .flushes
+                    .get_or_create(&FlushLabels {
+                        type_id: id,
+                        trigger: FlushTrigger::Resume,
+                    })
+                    .inc();
+            }
+        }
+        shared.leftover_spools.lock().unwrap().pop();
+    }
+}
+
+/// The container a writer task is filling and, with `wal`, the log of its blobs.
+struct Building {
+    container: ContainerWriter,
+    wal: Option<Wal>,
+    /// sync the log before a blob is acknowledged, with `sync_policy = "always"`
+    sync: bool,
+}
+
+/// What a writer_id put into the container being built, for `writer_cap`.
+#[derive(Clone, Copy, Default)]
+struct Tally {
+    objects: u32,
+    bytes: u64,
+}
+
+impl Tally {
+    fn add(&mut self, size: u64) {
+        self.objects += 1;
+        self.bytes += size;
+    }
+
+    /// Whether a blob of `size` bytes would take the writer over `cap`.
+    fn over(&self, cap: &WriterCapConfig, size: u64) -> bool {
+        if self.objects == 0 {
+            return false;
+        }
+        let objects = cap.max_objects.is_some_and(|max| self.objects >= max);
+        let bytes = cap.max_bytes.is_some_and(|max| self.bytes + size > max);
+        objects || bytes
+    }
+}
+
+/// Drops a blob rejected by `writer_cap`, telling the producer where it waits for an
+/// answer.
+fn reject_over_cap(obj: PostData, shared: &Shared) {
+    shared.receipts.dropped(obj.writer_id, obj.sequence);
+    let err = io::Error::new(ErrorKind::QuotaExceeded, "writer_cap reached");
+    match obj.data {
+        BlobData::Buffered(_) => {
+            if let Some(ack) = obj.logged {
+                let _ = ack.send(Err(err));
+            }
+        }
+        BlobData::Uploaded { stored, .. } => {
+            let _ = stored.send(Err(err));
+        }
+    }
+}
+
+/// Copies an upload file into the container chunk by chunk, logging the chunks too
+/// when there is a write-ahead log. Whether the blob was added is returned next to
+/// the container; an upload that can't be read leaves the container as it was. Only
+/// spool and log errors are returned as errors.
+fn push_upload(
+    building: &mut Building,
+    writer_id: u32,
+    sequence: u64,
+    timestamp: u64,
+    content_type: ContentType,
+    meta: &BlobMeta,
+    upload: &UploadFile,
+) -> io::Result<io::Result<()>> {
+    if let Some(wal) = building.wal.as_mut() {
+        wal.begin(writer_id, sequence, timestamp, content_type, meta)?;
+    }
+    let mut file = match File::open(upload.path()) {
+        Ok(file) => file,
+        Err(err) => return abort_upload(building, err),
+    };
+    let mut chunk = vec![0; UPLOAD_CHUNK_SIZE];
+    loop {
+        let read = match file.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return abort_upload(building, err),
+        };
+        if let Some(wal) = building.wal.as_mut() {
+            wal.data(&chunk[..read])?;
+        }
+        building.container.write_chunk(&chunk[..read])?;
+    }
+    let added = building.container.end_blob_with_timestamp(
+        writer_id,
+        sequence,
+        timestamp,
+        content_type,
+        meta,
+    );
+    if let Some(wal) = building.wal.as_mut() {
+        match &added {
+            Ok(()) => wal.end(building.sync)?,
+            Err(_) => wal.abort()?,
+        }
+    }
+    Ok(added)
+}
+
+fn abort_upload(building: &mut Building, err: io::Error) -> io::Result<io::Result<()>> {
+    if let Some(wal) = building.wal.as_mut() {
+        wal.abort()?;
+    }
+    building.container.abort_blob()?;
+    Ok(Err(err))
+}
+
+/// Hands the container to the storage backend and returns where it went. Interval
+/// rotation always appends, so a forced flush in the middle of a window doesn't
+/// clobber the window's file.
+///
+/// A failed store, e.g. on a full disk or a lost mount, is retried with backoff
+/// while new blobs wait in the queue. After `FLUSH_ATTEMPTS` the type is reported
+/// as failing in `health` until one of its containers is stored again, and the
+/// error is returned with the blobs left in the spool file.
+async fn flush(
+    type_id: &TypeConfig,
+    backend: &Arc<dyn StorageBackend>,
+    file_time: u64,
+    seq: u64,
+    mut container: ContainerWriter,
+    shared: &Shared,
+) -> io::Result<String> {
+    let (health, metrics) = (&shared.health, &shared.metrics);
+    let objects = container.len();
+    let dedup_ratio = container.dedup_saved_bytes().map(|saved_bytes| {
+        let stored_bytes = container.data_size();
+        (stored_bytes + saved_bytes) as f64 / stored_bytes.max(1) as f64
+    });
+    let started = Instant::now();
+    let file_time = match type_id.append_window_secs {
+        None => file_time,
+        Some(window_secs) => {
+            let window = window_secs * 1_000_000;
+            file_time - file_time % window
+        }
+    };
+    let append = type_id.appends();
+    let name = type_id
+        .filename_template()
+        .render(type_id.type_id, file_time, objects, seq);
+    let name = paths::container_name(name, file_time, type_id.date_partitioned);
+    let mut attempt = 1;
+    let stored = loop {
+        let (store_backend, store_name) = (backend.clone(), name.clone());
+        let (unstored, stored) = blocking(move || {
+            let stored = store_backend.store(&store_name, &mut container, append);
+            Ok((container, stored))
+        })
+        .await?;
+        container = unstored;
+        let err = match stored {
+            Ok(stored) => break stored,
+            Err(err) => err,
+        };
+        let labels = TypeLabels {
+            type_id: type_id.type_id,
+        };
+        metrics.write_failures.get_or_create(&labels).inc();
+        if BlobError::is_checksum_failure_in(&err) {
+            metrics.checksum_failures.get_or_create(&labels).inc();
+        }
+        if attempt == FLUSH_ATTEMPTS {
+            health.flush_failed(type_id.type_id);
+            return Err(err);
+        }
+        let delay = FLUSH_RETRY_DELAY * 2u32.pow(attempt - 1);
+        warn!(%err, attempt, ?delay, "storing the container failed, retrying");
+        sleep(delay).await;
+        attempt += 1;
+    };
+    health.flush_succeeded(type_id.type_id);
+    let (timings, phases) = (container.timings(), &metrics.flush_phase_seconds);
+    phases.observe(type_id.type_id, FlushPhase::Serialize, timings.serialize);
+    phases.observe(type_id.type_id, FlushPhase::Write, timings.write);
+    phases.observe(type_id.type_id, FlushPhase::Rename, timings.rename);
+    if let Some(path) = backend.local_path(&name) {
+        if let Err(err) = shared.syncer.written(path).await {
+            warn!(%err, "syncing the container failed");
+        }
+    }
+    let location = backend.location(&name);
+    shared
+        .receipts
+        .record(&name, &location, stored.first_index, container.sequences());
+    if let Err(err) = blocking(move || container.discard()).await {
+        warn!(%err, "removing the spool file failed");
+    }
+    info!(
+        %location,
+        objects,
+        bytes = stored.bytes,
+        dedup_ratio,
+        duration_ms = started.elapsed().as_millis() as u64,
+        "container flushed"
+    );
+    if let Some(path) = backend.local_path(&name) {
+        if type_id.write_sidecar {
+            let container_path = path.clone();
+            if let Err(err) = blocking(move || sidecar::write(&container_path)).await {
+                warn!(%err, "writing the sidecar failed");
+            }
+        }
+        if let Err(err) = blocking(move || manifest::record(&path)).await {
+            warn!(%err, "manifest update failed");
+        }
+        if type_id.maintain_latest_pointer {
+            let (id, root) = (type_id.type_id, PathBuf::from(type_id.root.as_str()));
+            let newest = shared.latest.clone();
+            let pointed = blocking(move || {
+                let mut newest = newest.lock().unwrap();
+                if file_time >= *newest {
+                    latest::point_at(&root, id, &name)?;
+                    *newest = file_time;
+                }
+                Ok(())
+            });
+            if let Err(err) = pointed.await {
+                warn!(%err, "updating the latest pointer failed");
+            }
+        }
+    }
+    Ok(location)
+}
+
+fn count_ingested(metrics: &WriterMetrics, type_id: u32, size: u64) {
+    let labels = TypeLabels { type_id };
+    metrics.bytes_in.get_or_create(&labels).inc_by(size);
+    metrics.objects_in.get_or_create(&labels).inc();
+}
+
+/// How long after the end of the window starting at `window_start` its container is
+/// flushed, in microseconds: up to `flush_jitter_ms`, the same for a type and window
+/// on every run.
+fn flush_jitter(type_id: &TypeConfig, window_start: u64) -> u64 {
+    match type_id.flush_jitter_ms {
+        None | Some(0) => 0,
+        Some(flush_jitter_ms) => {
+            xxh64(&window_start.to_le_bytes(), type_id.type_id as u64)
+                % (flush_jitter_ms * 1000 + 1)
+        }
+    }
+}
+
+/// Resolves at `deadline` (microseconds since the epoch), or never when there is none.
+async fn sleep_until_micros(deadline: Option<u64>) {
+    match deadline {
+        None => pending().await,
+        Some(deadline) => sleep(Duration::from_micros(deadline.saturating_sub(now_micros()))).await,
+    }
+}
+
+/// Runs file I/O on the blocking thread pool so slow disks don't stall the executor.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) => Err(io::Error::other(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::blob::storage::Container;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+    use std::fs;
+    use std::process;
+    use tokio::time::timeout;
+
+    /// Tells apart the roots of the writers of one test run.
+    static WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A new temporary directory for the root of a type.
+    fn test_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "blob_queue_writer_{}_{}",
+            process::id(),
+            WRITERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// The containers stored by a writer of a type with `soft_max_bytes = soft` and
+    /// `hard_max_bytes = hard`, the data of their blobs in order, and how many of them
+    /// each trigger flushed. The blobs are all queued before the writer task first
+    /// runs; the container left open once they are written is flushed like
+    /// `POST /admin/flush` does.
+    async fn containers_of(
+        soft: u64,
+        hard: u64,
+        blobs: &[&[u8]],
+    ) -> (Vec<Vec<Vec<u8>>>, Vec<(FlushTrigger, u64)>) {
+        let root = test_root();
+        let type_id: TypeConfig = toml::from_str(&format!(
+            "type_id = 1\nroot = {:?}\nobjects_in_container = 100\n\
+             soft_max_bytes = {}\nhard_max_bytes = {}",
+            root.display().to_string(),
+            soft,
+            hard
+        ))
+        .unwrap();
+        let backend = Arc::new(MemoryBackend::default());
+        let (health, metrics) = (Arc::new(Health::new(None)), WriterMetrics::default());
+        let handle = spawn_with_backend(type_id, health.clone(), metrics.clone(), backend.clone());
+        let posts = blobs
+            .iter()
+            .map(|data| PostData::new(0, ContentType::None, BlobMeta::new(), data.to_vec()))
+            .collect();
+        handle.send_all(posts).unwrap();
+        for _ in blobs {
+            health.enqueued(1);
+        }
+        timeout(Duration::from_secs(10), async {
+            while handle.backlog() > 0 {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("blobs written");
+        handle.flush().await.unwrap();
+        // so the writer doesn't create its next spool file while the root is removed
+        handle.shutdown().await.unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let containers = backend
+            .names()
+            .iter()
+            .map(|name| {
+                let container = Container::from_bytes(&backend.get(name).unwrap()).unwrap();
+                container.blobs().map(|blob| blob.data.to_vec()).collect()
+            })
+            .collect();
+        let triggers = [
+            FlushTrigger::SoftMaxBytes,
+            FlushTrigger::HardMaxBytes,
+            FlushTrigger::Admin,
+        ]
+        .into_iter()
+        .map(|trigger| {
+            let flushes = metrics
+                .flushes
+                .get_or_create(&FlushLabels {
+                    type_id: 1,
+                    trigger,
+                })
+                .get();
+            (trigger, flushes)
+        })
+        .filter(|(_, flushes)| *flushes > 0)
+        .collect();
+        (containers, triggers)
+    }
+
+    #[tokio::test]
+    async fn soft_max_bytes_reached_with_no_blob_waiting() {
+        let (containers, triggers) = containers_of(10, 100, &[b"aaaaa", b"bbbbb"]).await;
+        assert_eq!(containers, [[b"aaaaa", b"bbbbb"]]);
+        assert_eq!(triggers, [(FlushTrigger::SoftMaxBytes, 1)]);
+    }
+
+    #[tokio::test]
+    async fn soft_max_bytes_reached_with_blobs_waiting() {
+        let (containers, triggers) =
+            containers_of(10, 100, &[b"aaaaa", b"bbbbb", b"ccc", b"dd"]).await;
+        assert_eq!(containers, [vec![&b"aaaaa"[..], b"bbbbb", b"ccc", b"dd"]]);
+        assert_eq!(triggers, [(FlushTrigger::SoftMaxBytes, 1)]);
+    }
+
+    #[tokio::test]
+    async fn hard_max_bytes_reached_exactly() {
+        let (containers, triggers) =
+            containers_of(10, 20, &[b"aaaaaaaa", b"bbbbbbbb", b"cccc", b"d"]).await;
+        assert_eq!(
+            containers,
+            [vec![&b"aaaaaaaa"[..], b"bbbbbbbb", b"cccc"], vec![b"d"]]
+        );
+        assert_eq!(
+            triggers,
+            [(FlushTrigger::HardMaxBytes, 1), (FlushTrigger::Admin, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn blob_crossing_hard_max_bytes_starts_the_next_container() {
+        let (containers, triggers) =
+            containers_of(10, 20, &[b"aaaaaaaa", b"bbbbbbbb", b"ccccc"]).await;
+        assert_eq!(
+            containers,
+            [vec![&b"aaaaaaaa"[..], b"bbbbbbbb"], vec![b"ccccc"]]
+        );
+        assert_eq!(
+            triggers,
+            [(FlushTrigger::HardMaxBytes, 1), (FlushTrigger::Admin, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn blob_bigger_than_hard_max_bytes_gets_a_container_of_its_own() {
+        let big = [b'x'; 30];
+        let (containers, triggers) = containers_of(10, 20, &[b"aaaa", &big, b"b"]).await;
+        assert_eq!(containers, [vec![&b"aaaa"[..]], vec![&big], vec![b"b"]]);
+        assert_eq!(
+            triggers,
+            [(FlushTrigger::HardMaxBytes, 2), (FlushTrigger::Admin, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_fails_once_the_writer_stopped() {
+        let root = test_root();
+        let type_id: TypeConfig = toml::from_str(&format!(
+            "type_id = 1\nroot = {:?}\nobjects_in_container = 100",
+            root.display().to_string()
+        ))
+        .unwrap();
+        let health = Arc::new(Health::new(None));
+        let handle = spawn(type_id, health.clone(), WriterMetrics::default());
+        handle.shutdown().await.unwrap();
+        let posts = (0..3)
+            .map(|_| {
+                health.enqueued(1);
+                PostData::new(0, ContentType::None, BlobMeta::new(), b"late".to_vec())
+            })
+            .collect();
+        assert!(handle.send_all(posts).is_err());
+        assert_eq!(health.queue_depths()[0].depth, 0);
+        assert!(matches!(handle.lookup(0, 1), Lookup::Unknown));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_phases_are_timed() {
+        let root = test_root();
+        let type_id: TypeConfig = toml::from_str(&format!(
+            "type_id = 1\nroot = {:?}\nobjects_in_container = 100\nsync_policy = \"always\"",
+            root.display().to_string()
+        ))
+        .unwrap();
+        let (health, metrics) = (Arc::new(Health::new(None)), WriterMetrics::default());
+        let handle = spawn(type_id, health.clone(), metrics.clone());
+        health.enqueued(1);
+        let post = PostData::new(0, ContentType::None, BlobMeta::new(), b"timed".to_vec());
+        handle.send(post).unwrap();
+        handle.shutdown().await.unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let mut registry = <Registry>::default();
+        registry.register(
+            "container_flush_phase_seconds",
+            "",
+            Box::new(metrics.flush_phase_seconds.0.clone()),
+        );
+        let mut encoded = Vec::new();
+        encode(&mut encoded, &registry).unwrap();
+        let encoded = String::from_utf8(encoded).unwrap();
+        for phase in ["serialize", "write", "sync", "rename"] {
+            let count = format!(
+                "container_flush_phase_seconds_count{{type_id=\"1\",phase=\"{}\"}} 1",
+                phase
+            );
+            assert!(encoded.contains(&count), "{}: {}", phase, encoded);
+        }
+    }
+}